@@ -7,8 +7,13 @@ use std::time::{Duration, Instant};
 
 use crate::config::{AutosaveMode, Config};
 use crate::db;
-use crate::provider::{LlmEvent, LlmProvider, OllamaProvider, ProviderMessage, ProviderRegistry};
-use crate::session::Session;
+use crate::pricing::{format_cost, Budget};
+use crate::provider::{
+    ChatOptions, ChatStream, ContentBlock, LlmEvent, LlmProvider, OllamaProvider, ProviderMessage,
+    ProviderRegistry, ToolResult, Usage,
+};
+use crate::semantic::Indexer;
+use crate::session::{image_media_type, Attachment, Session, ToolCallRecord, ToolStatus};
 use crate::tools::Tools;
 use crate::tree::SessionTree;
 use vim_navigator::{InputMode, ListNavigator, VimNavigator};
@@ -20,6 +25,7 @@ pub enum AppScreen {
     Providers,
     Models,
     Search,
+    Cost,
     Help,
     Setup,
 }
@@ -37,6 +43,32 @@ pub enum ModelScreenMode {
     CustomInput,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchMode {
+    Keyword,
+    Semantic,
+    /// Semantic ranking blended with keyword matches
+    Hybrid,
+}
+
+impl SearchMode {
+    pub fn next(self) -> Self {
+        match self {
+            Self::Keyword => Self::Semantic,
+            Self::Semantic => Self::Hybrid,
+            Self::Hybrid => Self::Keyword,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Keyword => "keyword",
+            Self::Semantic => "semantic",
+            Self::Hybrid => "hybrid",
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ProviderListItem {
     pub name: String,
@@ -46,6 +78,14 @@ pub struct ProviderListItem {
     pub is_default: bool,
 }
 
+/// A tool call requested by the model during the current turn
+#[derive(Debug, Clone)]
+pub struct PendingToolCall {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+}
+
 #[derive(Debug, Clone)]
 pub struct ProviderModel {
     pub provider: String, // "ollama", "claude", "bedrock"
@@ -54,6 +94,19 @@ pub struct ProviderModel {
     pub is_current: bool, // Currently selected for this session
 }
 
+/// A request in flight, kept so it can be re-sent to the next provider in
+/// the fallback chain if the current one fails before answering
+struct ActiveRequest {
+    messages: Vec<ProviderMessage>,
+    /// Set when continuing after tool calls
+    tool_results: Option<Vec<ToolResult>>,
+    /// Provider and model currently answering
+    provider: String,
+    model: String,
+    /// Providers still to try, in order
+    fallbacks: VecDeque<String>,
+}
+
 pub struct App {
     pub screen: AppScreen,
     pub vim_nav: VimNavigator,
@@ -62,6 +115,7 @@ pub struct App {
     pub session_nav: ListNavigator,
     pub current_session: Option<Session>,
     pub message_buffer: String,
+    pub pending_attachments: Vec<Attachment>, // images to send with the next message
     pub current_project: Option<String>,
     pub input_scroll: u16,
     pub message_scroll: u16,
@@ -74,10 +128,18 @@ pub struct App {
     pub ollama: OllamaProvider,
     // Provider registry for dynamic provider management
     pub provider_registry: ProviderRegistry,
-    // Unified response receiver for all providers
-    pub response_receiver: Option<Receiver<LlmEvent>>,
+    // Embeds saved messages for semantic search, if the embedding provider is configured
+    pub indexer: Option<Indexer>,
+    // Unified response stream for all providers; dropping it cancels the request
+    pub response_stream: Option<ChatStream>,
+    active_request: Option<ActiveRequest>,
+    pending_usage: Option<Usage>, // reported for the response not yet recorded
     pub waiting_for_response: bool,
     pub assistant_buffer: String,
+    // Reasoning streamed for the current turn, and its signature if any
+    pub thinking_buffer: String,
+    pub thinking_signature: Option<String>,
+    pub show_thinking: bool,
     pub models: Vec<crate::provider::ollama::OllamaModel>,
     pub model_nav: ListNavigator,
     pub pull_status: Option<String>,
@@ -94,27 +156,40 @@ pub struct App {
     pub custom_model_input: String,
     pub tools: Tools,
     pub tool_status: Option<String>,
-    pub pending_tool_results: Vec<(String, String)>, // (tool_name, result)
-    pub pending_tool_calls: VecDeque<(String, serde_json::Value)>, // queued tool calls awaiting confirmation
+    pub retry_status: Option<String>, // shown while waiting to resend a failed request
+    pub pending_tool_results: Vec<(ToolCallRecord, String)>, // (call, output) resolved this turn
+    pub pending_tool_calls: VecDeque<PendingToolCall>, // queued tool calls awaiting confirmation
     pub awaiting_tool_confirmation: bool,
     pub done_received: bool, // track whether Done event arrived while processing tool queue
     pub auto_approve_tools: bool, // approve all tool calls for rest of session
-    pub setup_step: usize, // Current step in setup wizard (0=welcome, 1=ollama, 2=claude, 3=bedrock, 4=complete)
+    pub budget_warning: Option<String>, // soft spend limit reached, shown in the status line
+    pub budget_override: Option<String>, // session allowed to send past hard spend limits
+    pub schema_retry: Option<String>, // follow-up for a reply that failed its schema, sent by :schema retry
+    pub schema_retries_used: u32,     // automatic schema retries sent for the current reply
+    pub setup_step: usize, // Current step in setup wizard (0=welcome, 1=ollama, 2=claude, 3=bedrock, 4=azure, 5=complete)
     pub setup_message: String, // Status message for setup wizard
     pub ollama_status: Option<bool>,
     pub claude_status: Option<bool>,
     pub bedrock_status: Option<bool>,
+    pub azure_status: Option<bool>,
     // Search state
     pub search_query: String,
     pub search_results: Vec<db::SearchResult>,
     pub search_nav: ListNavigator,
+    pub search_mode: SearchMode,
+    pub search_error: Option<String>, // why a semantic search failed
+    pub search_unindexed: usize,      // messages not yet embedded when the search ran
     pub previous_screen: AppScreen,
+    /// Spend summary shown by `:cost`
+    pub cost_report: db::CostReport,
 }
 
 impl App {
     pub fn new() -> Result<Self> {
-        let config = Config::load()?;
-        let conn = db::init_db()?;
+        Self::with_config(Config::load()?, db::init_db()?)
+    }
+
+    fn with_config(config: Config, conn: Connection) -> Result<Self> {
         let sessions = db::list_sessions(&conn)?;
 
         let mut session_tree = SessionTree::new();
@@ -141,6 +216,7 @@ impl App {
 
         // Build provider registry from config
         let provider_registry = ProviderRegistry::from_config(&config);
+        let indexer = start_indexer(&config, &provider_registry);
 
         Ok(Self {
             screen: AppScreen::SessionList,
@@ -150,6 +226,7 @@ impl App {
             session_nav: ListNavigator::new(),
             current_session: None,
             message_buffer: String::new(),
+            pending_attachments: Vec::new(),
             current_project: None,
             input_scroll: 0,
             message_scroll: 0,
@@ -160,9 +237,15 @@ impl App {
             needs_save: false,
             ollama,
             provider_registry,
-            response_receiver: None,
+            indexer,
+            response_stream: None,
+            active_request: None,
+            pending_usage: None,
             waiting_for_response: false,
             assistant_buffer: String::new(),
+            thinking_buffer: String::new(),
+            thinking_signature: None,
+            show_thinking: false,
             models: Vec::new(),
             model_nav: ListNavigator::new(),
             pull_status: None,
@@ -177,20 +260,30 @@ impl App {
             custom_model_input: String::new(),
             tools: Tools::new(),
             tool_status: None,
+            retry_status: None,
             pending_tool_results: Vec::new(),
             pending_tool_calls: VecDeque::new(),
             awaiting_tool_confirmation: false,
             done_received: false,
             auto_approve_tools: false,
+            budget_warning: None,
+            budget_override: None,
+            schema_retry: None,
+            schema_retries_used: 0,
             setup_step: 0,
             setup_message: String::new(),
             ollama_status: None,
             claude_status: None,
             bedrock_status: None,
+            azure_status: None,
             search_query: String::new(),
             search_results: Vec::new(),
             search_nav: ListNavigator::new(),
+            search_mode: SearchMode::Keyword,
+            search_error: None,
+            search_unindexed: 0,
             previous_screen: AppScreen::SessionList,
+            cost_report: db::CostReport::default(),
         })
     }
 
@@ -301,6 +394,9 @@ impl App {
         for (name, config) in &self.config.providers {
             let is_available = match config {
                 crate::config::ProviderConfig::Ollama { .. } => self.ollama.is_running(),
+                crate::config::ProviderConfig::Replay {
+                    cassette, record, ..
+                } => record.is_some() || cassette.exists(),
                 crate::config::ProviderConfig::Bedrock { .. } => {
                     std::env::var("AWS_ACCESS_KEY_ID").is_ok()
                         || std::env::var("AWS_PROFILE").is_ok()
@@ -354,6 +450,7 @@ impl App {
         self.config.providers.remove(name);
         let _ = self.config.save();
         self.provider_registry = ProviderRegistry::from_config(&self.config);
+        self.indexer = start_indexer(&self.config, &self.provider_registry);
         self.refresh_provider_list();
         self.provider_screen_mode = ProviderScreenMode::List;
         // Clamp nav index
@@ -513,50 +610,64 @@ impl App {
 
     /// Unified response handler for all LLM providers
     pub fn check_response(&mut self) {
-        if let Some(ref receiver) = self.response_receiver {
-            match receiver.try_recv() {
+        if let Some(ref mut stream) = self.response_stream {
+            match stream.events.try_recv() {
                 Ok(LlmEvent::Text(text)) => {
                     crate::debug_log!("DEBUG: Received text: {:?}", text);
+                    self.retry_status = None;
                     self.assistant_buffer.push_str(&text);
                 }
-                Ok(LlmEvent::ToolUse { id: _, name, input }) => {
+                Ok(LlmEvent::Thinking(text)) => {
+                    self.retry_status = None;
+                    self.thinking_buffer.push_str(&text);
+                }
+                Ok(LlmEvent::Retrying { attempt, delay }) => {
+                    crate::debug_log!("DEBUG: Retrying, attempt {} in {:?}", attempt, delay);
+                    self.retry_status = Some(format!(
+                        "Request failed, retrying in {:.1}s (attempt {})...",
+                        delay.as_secs_f64(),
+                        attempt
+                    ));
+                }
+                Ok(LlmEvent::ThinkingSignature(signature)) => {
+                    self.thinking_signature = Some(signature);
+                }
+                Ok(LlmEvent::ToolUse { id, name, input }) => {
                     crate::debug_log!(
-                        "DEBUG: Received ToolUse - name: {}, input: {:?}",
+                        "DEBUG: Received ToolUse - id: {}, name: {}, input: {:?}",
+                        id,
                         name,
                         input
                     );
+                    self.retry_status = None;
+                    let call = PendingToolCall { id, name, input };
 
                     if self.auto_approve_tools {
                         // Auto-approve: execute immediately
-                        let result = self.execute_tool(&name, input);
-                        let result_str = match result {
-                            Ok(output) => output,
-                            Err(e) => format!("Error: {}", e),
-                        };
-                        self.pending_tool_results.push((name.clone(), result_str));
-                        self.assistant_buffer
-                            .push_str(&format!("\n\n[Tool: {} executed]", name));
+                        self.resolve_tool_call(call, ToolStatus::AutoApproved);
                     } else {
                         // Queue tool call for confirmation
-                        self.pending_tool_calls.push_back((name.clone(), input));
+                        self.pending_tool_calls.push_back(call);
                         // Show confirmation for the first queued tool if not already showing one
                         if !self.awaiting_tool_confirmation {
                             self.awaiting_tool_confirmation = true;
-                            if let Some((ref tool_name, _)) = self.pending_tool_calls.front() {
+                            if let Some(next) = self.pending_tool_calls.front() {
                                 self.tool_status = Some(format!(
                                     "Waiting for confirmation: {} [Y]es [N]o [A]ll [Q]uit",
-                                    tool_name
+                                    next.name
                                 ));
                             }
                         }
                     }
                 }
-                Ok(LlmEvent::Done {
-                    input_tokens: _,
-                    output_tokens,
-                }) => {
+                Ok(LlmEvent::Done(usage)) => {
                     crate::debug_log!("DEBUG: Received Done event, pending_tool_results: {}, awaiting_confirmation: {}",
                         self.pending_tool_results.len(), self.awaiting_tool_confirmation);
+                    self.retry_status = None;
+                    if let Some(ref mut session) = self.current_session {
+                        session.last_usage = Some(usage);
+                    }
+                    self.pending_usage = Some(usage);
 
                     // If we're awaiting tool confirmation, mark Done as received and wait
                     if self.awaiting_tool_confirmation || !self.pending_tool_calls.is_empty() {
@@ -568,53 +679,15 @@ impl App {
                     // If we have pending tool results, send them back to continue the conversation
                     else if !self.pending_tool_results.is_empty() {
                         crate::debug_log!("DEBUG: Continuing conversation with tool results");
-
-                        // Save the assistant's tool call message and the tool results to history
-                        if let Some(ref mut session) = self.current_session {
-                            // Get the current provider's model name
-                            let model_name =
-                                Some(self.config.model_for_provider(&session.llm_provider));
-
-                            // Save assistant message with tool calls (marked as executed)
-                            session.add_message_with_flag(
-                                "assistant".to_string(),
-                                self.assistant_buffer.clone(),
-                                model_name,
-                                true, // tools_executed flag
-                            );
-
-                            // Save tool results as system message (also marked as executed)
-                            let mut tool_results_text = String::new();
-                            for (i, (name, result)) in self.pending_tool_results.iter().enumerate()
-                            {
-                                if i > 0 {
-                                    tool_results_text.push_str("\n\n");
-                                }
-                                tool_results_text
-                                    .push_str(&format!("[Tool {} result]:\n{}", name, result));
-                            }
-
-                            session.add_message_with_flag(
-                                "system".to_string(),
-                                tool_results_text,
-                                None,
-                                true, // tools_executed flag
-                            );
-                        }
-
-                        // Clear the buffer before continuing so we don't duplicate output
-                        self.assistant_buffer.clear();
-
-                        self.continue_with_tool_results();
+                        self.process_tool_completion();
                     } else {
                         // No more tool calls, save the final response
                         crate::debug_log!("DEBUG: No tool results, saving final response");
+                        let (model_name, provider) = self.answered_by();
+                        let cost = self.pending_cost(model_name.as_deref(), provider.as_deref());
+                        let thinking = self.take_thinking();
                         if let Some(ref mut session) = self.current_session {
-                            // Get the current provider's model name
-                            let model_name =
-                                Some(self.config.model_for_provider(&session.llm_provider));
-
-                            let token_count = output_tokens.map(|t| t as i64);
+                            let token_count = usage.output_tokens.map(|t| t as i64);
                             session.add_message_full(
                                 "assistant".to_string(),
                                 self.assistant_buffer.clone(),
@@ -623,53 +696,158 @@ impl App {
                                 false, // is_summary
                                 token_count,
                             );
+                            session.set_last_provider(provider);
+                            let corrected = self
+                                .pending_usage
+                                .take()
+                                .and_then(|usage| session.set_last_usage(usage));
+                            session.set_last_cost(cost);
+                            if let Some((text, signature)) = thinking {
+                                session.set_last_thinking(text, signature);
+                            }
                             match self.config.autosave_mode {
-                                AutosaveMode::OnSend => self.save_current_message(),
+                                AutosaveMode::OnSend => {
+                                    // The user message was saved with an estimate
+                                    if let Some(idx) = corrected {
+                                        let _ = db::update_message(
+                                            &self.conn,
+                                            &session.id,
+                                            &session.messages[idx],
+                                        );
+                                    }
+                                    self.save_current_message()
+                                }
                                 AutosaveMode::Timer => self.needs_save = true,
                                 AutosaveMode::Disabled => {}
                             }
                         }
-                        self.assistant_buffer.clear();
+                        let reply = std::mem::take(&mut self.assistant_buffer);
                         self.waiting_for_response = false;
-                        self.response_receiver = None;
+                        self.response_stream = None;
+                        // The reply may have crossed a soft limit
+                        if let Some(request) = self.active_request.take() {
+                            self.refresh_budget_warning(&request.provider);
+                        }
                         self.message_scroll_manual = false; // Reset scroll to auto-scroll to new message
+                        self.check_structured_reply(&reply);
                     }
                 }
                 Ok(LlmEvent::Error(err)) => {
                     crate::debug_log!("DEBUG: Received Error event: {}", err);
+                    self.retry_status = None;
                     if let Some(ref mut session) = self.current_session {
                         session.add_message("system".to_string(), format!("Error: {}", err), None);
                     }
+
+                    // Nothing has been shown for this reply yet, so the next
+                    // provider in the chain can answer it instead
+                    let nothing_streamed = self.assistant_buffer.is_empty()
+                        && self.thinking_buffer.is_empty()
+                        && self.pending_tool_calls.is_empty()
+                        && self.pending_tool_results.is_empty();
+                    if err.kind.should_fall_back() && nothing_streamed {
+                        if let Some(request) = self.active_request.take() {
+                            self.response_stream = None;
+                            if self.start_request(
+                                request.messages,
+                                request.tool_results,
+                                request.fallbacks.into(),
+                                true,
+                            ) {
+                                return;
+                            }
+                        }
+                    }
+
                     self.assistant_buffer.clear();
+                    self.thinking_buffer.clear();
+                    self.thinking_signature = None;
                     self.waiting_for_response = false;
-                    self.response_receiver = None;
+                    self.response_stream = None;
+                    self.active_request = None;
                     self.pending_tool_results.clear();
                     self.pending_tool_calls.clear();
                     self.done_received = false;
                     self.awaiting_tool_confirmation = false;
+                    self.schema_retries_used = 0;
                 }
                 Err(_) => {} // No message available yet
             }
         }
     }
 
-    pub fn confirm_tool_execution(&mut self) {
-        if let Some((name, arguments)) = self.pending_tool_calls.pop_front() {
-            crate::debug_log!("DEBUG: Executing confirmed tool: {}", name);
+    /// Validate a final reply against the session's schema. A reply that
+    /// doesn't match is sent back with the errors up to `schema_retries`
+    /// times, then the follow-up is kept for `:schema retry`.
+    fn check_structured_reply(&mut self, reply: &str) {
+        self.schema_retry = None;
+        let Some(ref mut session) = self.current_session else {
+            return;
+        };
+        let Some(schema) = session.response_schema.as_ref() else {
+            return;
+        };
+        let errors = crate::schema::check_reply(schema, reply);
+        if errors.is_empty() {
+            self.schema_retries_used = 0;
+            return;
+        }
 
-            // Execute tool and collect result
-            let result = self.execute_tool(&name, arguments);
-            let result_str = match result {
-                Ok(output) => output,
-                Err(e) => format!("Error: {}", e),
-            };
+        let prompt = crate::schema::retry_prompt(&errors);
+        let mut message = format!("Reply doesn't match the schema:\n{}", errors.join("\n"));
+        if self.schema_retries_used < self.config.schema_retries {
+            self.schema_retries_used += 1;
+            message.push_str(&format!(
+                "\nRetrying ({}/{})",
+                self.schema_retries_used, self.config.schema_retries
+            ));
+            session.add_message("system".to_string(), message, None);
+            self.send_schema_retry(prompt);
+        } else {
+            self.schema_retries_used = 0;
+            message.push_str("\nUse :schema retry to ask for a corrected reply");
+            session.add_message("system".to_string(), message, None);
+            self.schema_retry = Some(prompt);
+        }
+    }
+
+    /// Send a schema follow-up as a user message, keeping the user's draft
+    fn send_schema_retry(&mut self, prompt: String) {
+        let draft = std::mem::replace(&mut self.message_buffer, prompt);
+        let attachments = std::mem::take(&mut self.pending_attachments);
+        self.submit_message_buffer();
+        self.message_buffer = draft;
+        self.pending_attachments = attachments;
+    }
+
+    /// Execute (or skip, if not approved) a tool call and store its output
+    /// for the continuation request
+    fn resolve_tool_call(&mut self, call: PendingToolCall, status: ToolStatus) {
+        let (output, is_error) = match status {
+            ToolStatus::Approved | ToolStatus::AutoApproved => {
+                match self.execute_tool(&call.name, call.input.clone()) {
+                    Ok(output) => (output, false),
+                    Err(e) => (format!("Error: {}", e), true),
+                }
+            }
+            ToolStatus::Rejected => ("Tool execution rejected by user".to_string(), true),
+            ToolStatus::Cancelled => ("Tool execution cancelled by user".to_string(), true),
+        };
 
-            // Store tool result for later
-            self.pending_tool_results.push((name.clone(), result_str));
+        let record = ToolCallRecord {
+            id: call.id,
+            name: call.name,
+            input: call.input,
+            status,
+            is_error,
+        };
+        self.pending_tool_results.push((record, output));
+    }
 
-            // Show tool name in assistant buffer (not the full output)
-            self.assistant_buffer
-                .push_str(&format!("\n\n[Tool: {} executed]", name));
+    pub fn confirm_tool_execution(&mut self) {
+        if let Some(call) = self.pending_tool_calls.pop_front() {
+            crate::debug_log!("DEBUG: Executing confirmed tool: {}", call.name);
+            self.resolve_tool_call(call, ToolStatus::Approved);
         }
 
         self.advance_tool_queue();
@@ -680,16 +858,9 @@ impl App {
         self.auto_approve_tools = true;
 
         // Execute all remaining queued tools
-        while let Some((name, arguments)) = self.pending_tool_calls.pop_front() {
-            crate::debug_log!("DEBUG: Auto-executing tool: {}", name);
-            let result = self.execute_tool(&name, arguments);
-            let result_str = match result {
-                Ok(output) => output,
-                Err(e) => format!("Error: {}", e),
-            };
-            self.pending_tool_results.push((name.clone(), result_str));
-            self.assistant_buffer
-                .push_str(&format!("\n\n[Tool: {} executed]", name));
+        while let Some(call) = self.pending_tool_calls.pop_front() {
+            crate::debug_log!("DEBUG: Auto-executing tool: {}", call.name);
+            self.resolve_tool_call(call, ToolStatus::AutoApproved);
         }
 
         self.awaiting_tool_confirmation = false;
@@ -701,20 +872,19 @@ impl App {
     }
 
     pub fn reject_tool_execution(&mut self) {
-        if let Some((name, _)) = self.pending_tool_calls.pop_front() {
-            crate::debug_log!("DEBUG: Rejected tool execution: {}", name);
-            self.pending_tool_results
-                .push((name.clone(), "Tool execution rejected by user".to_string()));
+        if let Some(call) = self.pending_tool_calls.pop_front() {
+            crate::debug_log!("DEBUG: Rejected tool execution: {}", call.name);
+            self.resolve_tool_call(call, ToolStatus::Rejected);
         }
 
         self.advance_tool_queue();
     }
 
     fn advance_tool_queue(&mut self) {
-        if let Some((ref next_name, _)) = self.pending_tool_calls.front() {
+        if let Some(next) = self.pending_tool_calls.front() {
             self.tool_status = Some(format!(
                 "Waiting for confirmation: {} [Y]es [N]o [A]ll [Q]uit",
-                next_name
+                next.name
             ));
         } else {
             self.awaiting_tool_confirmation = false;
@@ -734,55 +904,93 @@ impl App {
                 self.pending_tool_results.len()
             );
 
-            // Save the assistant's tool call message and the tool results to history
-            if let Some(ref mut session) = self.current_session {
-                // Get the current provider's model name
-                let model_name = Some(self.config.model_for_provider(&session.llm_provider));
-
-                // Save assistant message with tool calls (marked as executed)
-                session.add_message_with_flag(
-                    "assistant".to_string(),
-                    self.assistant_buffer.clone(),
-                    model_name.clone(),
-                    true, // tools_executed flag
-                );
-
-                // Save tool results as system message (also marked as executed)
-                let mut tool_results_text = String::new();
-                for (i, (name, result)) in self.pending_tool_results.iter().enumerate() {
-                    if i > 0 {
-                        tool_results_text.push_str("\n\n");
-                    }
-                    tool_results_text.push_str(&format!("[Tool {} result]:\n{}", name, result));
-                }
-
-                session.add_message_with_flag(
-                    "system".to_string(),
-                    tool_results_text,
-                    None,
-                    true, // tools_executed flag
-                );
-            }
-
             // Clear the buffer before continuing so we don't duplicate output
-            self.assistant_buffer.clear();
-
-            self.continue_with_tool_results();
+            let assistant_text = std::mem::take(&mut self.assistant_buffer);
+            let thinking = self.take_thinking();
+            let tool_results = std::mem::take(&mut self.pending_tool_results);
+            let answered_by = self.answered_by();
+
+            // Send before recording this turn, so history isn't duplicated
+            self.continue_with_tool_results(&assistant_text, thinking.clone(), &tool_results);
+            self.record_tool_turn(assistant_text, thinking, tool_results, answered_by);
         } else {
             // No tool results, just finish
             crate::debug_log!("DEBUG: No tool results after confirmation");
+            let thinking = self.take_thinking();
+            let (model_name, provider) = self.answered_by();
+            let cost = self.pending_cost(model_name.as_deref(), provider.as_deref());
             if let Some(ref mut session) = self.current_session {
-                // Get the current provider's model name
-                let model_name = Some(self.config.model_for_provider(&session.llm_provider));
                 session.add_message(
                     "assistant".to_string(),
                     self.assistant_buffer.clone(),
                     model_name,
                 );
+                session.set_last_provider(provider);
+                if let Some(usage) = self.pending_usage.take() {
+                    session.set_last_usage(usage);
+                }
+                session.set_last_cost(cost);
+                if let Some((text, signature)) = thinking {
+                    session.set_last_thinking(text, signature);
+                }
             }
             self.assistant_buffer.clear();
             self.waiting_for_response = false;
-            self.response_receiver = None;
+            self.response_stream = None;
+            self.active_request = None;
+        }
+    }
+
+    /// Take the reasoning streamed for the current turn, with its signature.
+    /// Gemini may send a signature with no reasoning text; it is kept so the
+    /// turn can be replayed.
+    fn take_thinking(&mut self) -> Option<(String, Option<String>)> {
+        let thinking = std::mem::take(&mut self.thinking_buffer);
+        let signature = self.thinking_signature.take();
+        (!thinking.is_empty() || signature.is_some()).then_some((thinking, signature))
+    }
+
+    /// Save the assistant message that requested tools, followed by one
+    /// "tool" record per call
+    fn record_tool_turn(
+        &mut self,
+        assistant_text: String,
+        thinking: Option<(String, Option<String>)>,
+        tool_results: Vec<(ToolCallRecord, String)>,
+        (model_name, provider): (Option<String>, Option<String>),
+    ) {
+        let cost = self.pending_cost(model_name.as_deref(), provider.as_deref());
+        let Some(ref mut session) = self.current_session else {
+            return;
+        };
+
+        let count = tool_results.len() + 1;
+
+        // Assistant message is hidden in the chat view; the tool records are shown instead
+        session.add_message("assistant".to_string(), assistant_text, model_name);
+        session.set_last_provider(provider);
+        let corrected = self
+            .pending_usage
+            .take()
+            .and_then(|usage| session.set_last_usage(usage));
+        session.set_last_cost(cost);
+        if let Some((text, signature)) = thinking {
+            session.set_last_thinking(text, signature);
+        }
+        for (call, output) in tool_results {
+            session.add_tool_message(call, output);
+        }
+
+        match self.config.autosave_mode {
+            AutosaveMode::OnSend => {
+                // The user message was saved with an estimate
+                if let Some(idx) = corrected {
+                    let _ = db::update_message(&self.conn, &session.id, &session.messages[idx]);
+                }
+                self.save_recent_messages(count)
+            }
+            AutosaveMode::Timer => self.needs_save = true,
+            AutosaveMode::Disabled => {}
         }
     }
 
@@ -864,10 +1072,7 @@ impl App {
         let provider_name = &session.llm_provider.clone();
 
         // Create a temporary message list for the summary request
-        let summary_messages = vec![ProviderMessage {
-            role: "user".to_string(),
-            content: compact_prompt.clone(),
-        }];
+        let summary_messages = vec![ProviderMessage::text("user", compact_prompt.clone())];
 
         // Get summary synchronously using the provider registry
         let provider = self
@@ -878,14 +1083,14 @@ impl App {
             .model
             .clone()
             .unwrap_or_else(|| self.config.model_for_provider(provider_name));
-        let receiver = provider.chat(&model, summary_messages, None, 2048)?;
+        let mut stream = provider.chat(&model, summary_messages, None, &ChatOptions::new(2048))?;
 
         let mut summary_text = String::new();
-        loop {
-            match receiver.recv() {
-                Ok(LlmEvent::Text(text)) => summary_text.push_str(&text),
-                Ok(LlmEvent::Done { .. }) => break,
-                Ok(LlmEvent::Error(e)) => {
+        while let Some(event) = stream.events.blocking_recv() {
+            match event {
+                LlmEvent::Text(text) => summary_text.push_str(&text),
+                LlmEvent::Done(_) => break,
+                LlmEvent::Error(e) => {
                     return Err(anyhow::anyhow!("{} error: {}", provider_name, e))
                 }
                 _ => {}
@@ -960,221 +1165,359 @@ impl App {
         }
 
         let session = match self.current_session {
-            Some(ref mut s) => s,
+            Some(ref s) => s,
             None => return Ok(()),
         };
 
-        let provider_name = session.llm_provider.clone();
-
-        // Build messages for the provider
-        let cwd = std::env::current_dir()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| "/".to_string());
+        // Convert session messages to ProviderMessage format
+        let mut messages: Vec<ProviderMessage> =
+            vec![ProviderMessage::text("system", system_prompt())];
 
-        // Build a summary of previously executed tools
-        let tool_messages: Vec<_> = session
-            .messages
-            .iter()
-            .filter(|m| m.tools_executed && m.role == "system")
-            .collect();
+        // Add conversation messages, including earlier tool calls and their results
+        let history = history_messages(session);
 
-        let mut tool_summary = String::new();
-        let mut first_summary = true;
-        for m in &tool_messages {
-            let mut tools_in_msg = String::new();
-            let mut first_tool = true;
-            for line in m.content.lines().filter(|line| line.starts_with("[Tool ")) {
-                if !first_tool {
-                    tools_in_msg.push_str(", ");
-                }
-                tools_in_msg.push_str(line);
-                first_tool = false;
-            }
-            if !tools_in_msg.is_empty() {
-                if !first_summary {
-                    tool_summary.push_str("; ");
-                }
-                tool_summary.push_str(&tools_in_msg);
-                first_summary = false;
-            }
-        }
+        crate::debug_log!(
+            "DEBUG send_llm_message: Total messages: {}, Sent: {}",
+            session.messages.len(),
+            history.len()
+        );
 
-        let context_note = if !tool_summary.is_empty() {
-            format!("\n\nNote: You have already executed these tools in this conversation: {}. You have access to their results in your context, so you don't need to re-run them.", tool_summary)
-        } else {
-            String::new()
-        };
+        messages.extend(history);
 
-        // Build system prompt
-        let system_prompt = format!(
-            "You are a helpful assistant with access to tools for reading files, editing code, and searching the codebase.\n\nONLY use tools when the user explicitly asks you to work with files or code. Do NOT use tools for casual conversation.\n\nCurrent working directory: {}{}",
-            cwd, context_note
-        );
+        let chain = self
+            .config
+            .provider_chain(&session.llm_provider, session.project.as_deref());
+        self.pending_usage = None;
+        self.start_request(messages, None, chain, false);
 
-        // Convert session messages to ProviderMessage format
-        let mut messages: Vec<ProviderMessage> = vec![ProviderMessage {
-            role: "system".to_string(),
-            content: system_prompt,
-        }];
-
-        // Add conversation messages, filtering based on provider behavior
-        let total_messages = session.messages.len();
-        let filtered_messages: Vec<_> = session
-            .messages
-            .iter()
-            .filter(|m| m.role != "system" && !m.content.trim().is_empty())
-            .collect();
+        Ok(())
+    }
 
-        crate::debug_log!(
-            "DEBUG send_llm_message: Total messages: {}, After filtering: {}",
-            total_messages,
-            filtered_messages.len()
-        );
+    /// Ask the session's provider how many prompt tokens the next request
+    /// would use, including the unsent input. None if it can't count.
+    fn count_prompt_tokens(&self) -> Result<Option<u32>> {
+        let session = self
+            .current_session
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No session loaded"))?;
+        let provider = self
+            .provider(&session.llm_provider)
+            .ok_or_else(|| anyhow::anyhow!("Provider '{}' not available", session.llm_provider))?;
+        let model_id = session
+            .model
+            .clone()
+            .unwrap_or_else(|| self.config.model_for_provider(&session.llm_provider));
 
-        messages.extend(filtered_messages.iter().map(|m| ProviderMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
-        }));
+        let mut messages = vec![ProviderMessage::text("system", system_prompt())];
+        messages.extend(history_messages(session));
+        if !self.message_buffer.trim().is_empty() {
+            messages.push(ProviderMessage::text("user", self.message_buffer.clone()));
+        }
 
-        // Get tool definitions
-        let tools = Some(crate::provider::get_tool_definitions());
+        provider.count_tokens(
+            &model_id,
+            messages,
+            Some(crate::provider::get_tool_definitions()),
+        )
+    }
 
-        // Use registry to get provider
-        let provider = if provider_name == "ollama" {
+    /// Look up a provider by name
+    fn provider(&self, name: &str) -> Option<&dyn LlmProvider> {
+        if name == "ollama" {
             // Use the stored ollama instance for Ollama-specific operations
             Some(&self.ollama as &dyn LlmProvider)
         } else {
             // Get from registry for other providers
-            self.provider_registry.get(&provider_name)
-        };
+            self.provider_registry.get(name)
+        }
+    }
 
-        match provider {
-            Some(provider) => {
-                // Get model from session or config default
-                let model_id = session
-                    .model
-                    .clone()
-                    .unwrap_or_else(|| self.config.model_for_provider(&provider_name));
-
-                let max_tokens = self.config.max_output_tokens_for_provider(&provider_name);
-                if let Ok(receiver) = provider.chat(&model_id, messages, tools, max_tokens) {
-                    self.response_receiver = Some(receiver);
-                    self.waiting_for_response = true;
-                } else {
+    /// Send a request to one provider. Returns the stream and the model used.
+    fn open_stream(
+        &self,
+        provider_name: &str,
+        messages: &[ProviderMessage],
+        tool_results: Option<&[ToolResult]>,
+    ) -> Result<(ChatStream, String)> {
+        let session = self
+            .current_session
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No session loaded"))?;
+        let provider = self
+            .provider(provider_name)
+            .ok_or_else(|| anyhow::anyhow!("Provider '{}' not available", provider_name))?;
+
+        // The session's model only applies to its own provider
+        let model_id = session
+            .model
+            .clone()
+            .filter(|_| session.llm_provider == provider_name)
+            .unwrap_or_else(|| self.config.model_for_provider(provider_name));
+
+        let mut options = self.config.chat_options_for_provider(provider_name);
+        options.sampling = options.sampling.merged(&session.sampling);
+        options.response_schema = session.response_schema.clone();
+        let tools = Some(crate::provider::get_tool_definitions());
+
+        let stream = match tool_results {
+            Some(results) => provider.continue_with_tools(
+                &model_id,
+                messages.to_vec(),
+                tools,
+                results.to_vec(),
+                &options,
+            ),
+            None => provider.chat(&model_id, messages.to_vec(), tools, &options),
+        }
+        .map_err(|e| anyhow::anyhow!("Failed to start {} chat: {}", provider_name, e))?;
+
+        Ok((stream, model_id))
+    }
+
+    /// Send a request to the first provider in `chain` that accepts it.
+    /// The rest of the chain is kept so a failed reply can fall back.
+    fn start_request(
+        &mut self,
+        messages: Vec<ProviderMessage>,
+        tool_results: Option<Vec<ToolResult>>,
+        chain: Vec<String>,
+        mut after_failure: bool,
+    ) -> bool {
+        let mut fallbacks = VecDeque::from(chain);
+
+        while let Some(provider_name) = fallbacks.pop_front() {
+            if after_failure {
+                if let Some(ref mut session) = self.current_session {
                     session.add_message(
                         "system".to_string(),
-                        format!("Error: Failed to start {} chat", provider_name),
+                        format!("Falling back to {}", provider_name),
                         None,
                     );
                 }
             }
-            None => {
-                session.add_message(
-                    "system".to_string(),
-                    format!("Error: Provider '{}' not available", provider_name),
-                    None,
-                );
+
+            if let Some(reason) = self.hard_limit_reached(&provider_name) {
+                if let Some(ref mut session) = self.current_session {
+                    session.add_message(
+                        "system".to_string(),
+                        format!(
+                            "Not sending to {}: {}. Use :budget override to send anyway",
+                            provider_name, reason
+                        ),
+                        None,
+                    );
+                }
+                after_failure = true;
+                continue;
+            }
+
+            match self.open_stream(&provider_name, &messages, tool_results.as_deref()) {
+                Ok((stream, model)) => {
+                    self.refresh_budget_warning(&provider_name);
+                    self.response_stream = Some(stream);
+                    self.waiting_for_response = true;
+                    self.active_request = Some(ActiveRequest {
+                        messages,
+                        tool_results,
+                        provider: provider_name,
+                        model,
+                        fallbacks,
+                    });
+                    return true;
+                }
+                Err(e) => {
+                    if let Some(ref mut session) = self.current_session {
+                        session.add_message("system".to_string(), format!("Error: {}", e), None);
+                    }
+                    after_failure = true;
+                }
             }
         }
 
-        Ok(())
+        self.waiting_for_response = false;
+        self.response_stream = None;
+        self.active_request = None;
+        false
     }
 
-    fn continue_with_tool_results(&mut self) {
-        let session = match self.current_session {
-            Some(ref mut s) => s,
-            None => return,
+    /// This month's spend against each limit that applies to sending to
+    /// `provider` from the current session: (what it limits, spent, budget)
+    fn budget_spend(&self, provider: &str) -> Vec<(String, f64, Budget)> {
+        let mut limits = Vec::new();
+        if let Some(budget) = self.config.provider_budget(provider) {
+            if let Ok(spent) = db::month_spend(&self.conn, Some(provider), None, None) {
+                let spent = spent + self.unsaved_spend(Some(provider));
+                limits.push((format!("provider {}", provider), spent, budget));
+            }
+        }
+        let project = self
+            .current_session
+            .as_ref()
+            .and_then(|s| s.project.as_deref());
+        if let Some(project) = project {
+            if let Some(budget) = self.config.project_budget(project) {
+                if let Ok(spent) = db::month_spend(&self.conn, None, Some(project), None) {
+                    let spent = spent + self.unsaved_spend(None);
+                    limits.push((format!("project {}", project), spent, budget));
+                }
+            }
+        }
+        limits
+    }
+
+    /// This month's spend on current session replies that aren't in the
+    /// database yet, e.g. with timer or disabled autosave
+    fn unsaved_spend(&self, provider: Option<&str>) -> f64 {
+        let Some(ref session) = self.current_session else {
+            return 0.0;
+        };
+        let Ok(month_start) = db::month_start(&self.conn) else {
+            return 0.0;
         };
+        let in_memory: f64 = session
+            .messages
+            .iter()
+            .filter(|m| m.timestamp.timestamp() >= month_start)
+            .filter(|m| {
+                provider.is_none_or(|p| m.provider.as_deref().unwrap_or(&session.llm_provider) == p)
+            })
+            .filter_map(|m| m.cost)
+            .sum();
+        let saved = db::month_spend(&self.conn, provider, None, Some(&session.id)).unwrap_or(0.0);
+        (in_memory - saved).max(0.0)
+    }
 
-        let provider_name = session.llm_provider.clone();
+    /// Why sending to `provider` is refused: a hard limit reached this
+    /// month, unless overridden for the current session
+    fn hard_limit_reached(&self, provider: &str) -> Option<String> {
+        let session_id = self.current_session.as_ref().map(|s| &s.id);
+        if session_id.is_some() && self.budget_override.as_ref() == session_id {
+            return None;
+        }
+        self.budget_spend(provider)
+            .into_iter()
+            .find_map(|(scope, spent, budget)| {
+                let hard = budget.hard?;
+                (spent >= hard).then(|| {
+                    format!(
+                        "{} has spent {} of its {} monthly limit",
+                        scope,
+                        format_cost(spent),
+                        format_cost(hard)
+                    )
+                })
+            })
+    }
 
-        // Build tool result messages
-        let tool_results: Vec<String> = self
-            .pending_tool_results
-            .iter()
-            .map(|(name, result)| format!("[Tool {} result]:\n{}", name, result))
-            .collect();
+    /// Show a warning in the status line while a soft limit is exceeded
+    fn refresh_budget_warning(&mut self, provider: &str) {
+        self.budget_warning =
+            self.budget_spend(provider)
+                .into_iter()
+                .find_map(|(scope, spent, budget)| {
+                    let soft = budget.soft?;
+                    (spent >= soft).then(|| {
+                        format!(
+                            "Budget: {} at {} of {} this month",
+                            scope,
+                            format_cost(spent),
+                            format_cost(soft)
+                        )
+                    })
+                });
+    }
+
+    /// Model and provider of the reply being streamed, to record on its message
+    fn answered_by(&self) -> (Option<String>, Option<String>) {
+        match (&self.active_request, &self.current_session) {
+            (Some(request), _) => (Some(request.model.clone()), Some(request.provider.clone())),
+            (None, Some(session)) => (
+                Some(self.config.model_for_provider(&session.llm_provider)),
+                Some(session.llm_provider.clone()),
+            ),
+            (None, None) => (None, None),
+        }
+    }
+
+    /// Price the usage reported for the reply being recorded
+    fn pending_cost(&self, model: Option<&str>, provider: Option<&str>) -> Option<f64> {
+        let usage = self.pending_usage.as_ref()?;
+        self.config.cost(provider?, model?, usage)
+    }
+
+    fn continue_with_tool_results(
+        &mut self,
+        assistant_text: &str,
+        thinking: Option<(String, Option<String>)>,
+        tool_results: &[(ToolCallRecord, String)],
+    ) {
+        let session = match self.current_session {
+            Some(ref s) => s,
+            None => return,
+        };
 
         crate::debug_log!(
             "DEBUG: Sending {} tool results back to model",
             tool_results.len()
         );
 
-        // Convert tool results to ToolResult format for the provider
-        let tool_result_structs: Vec<crate::provider::ToolResult> = self
-            .pending_tool_results
-            .iter()
-            .map(|(name, result)| crate::provider::ToolResult {
-                tool_use_id: name.clone(),
-                content: result.clone(),
-            })
-            .collect();
-
         // Build messages for continuation
-        let cwd = std::env::current_dir()
-            .map(|p| p.display().to_string())
-            .unwrap_or_else(|_| "/".to_string());
-
-        let system_prompt = format!(
-            "You are a helpful assistant with access to tools for reading files, editing code, and searching the codebase.\n\nONLY use tools when the user explicitly asks you to work with files or code. Do NOT use tools for casual conversation.\n\nCurrent working directory: {}",
-            cwd
-        );
-
-        let mut messages: Vec<ProviderMessage> = vec![ProviderMessage {
-            role: "system".to_string(),
-            content: system_prompt,
-        }];
+        let mut messages: Vec<ProviderMessage> =
+            vec![ProviderMessage::text("system", system_prompt())];
 
         // Add conversation messages
-        let total_messages = session.messages.len();
-        let filtered_messages: Vec<_> = session
-            .messages
-            .iter()
-            .filter(|m| m.role != "system" && !m.content.trim().is_empty())
-            .collect();
+        let history = history_messages(session);
 
         crate::debug_log!(
-            "DEBUG continue_with_tool_results: Total messages: {}, After filtering: {}",
-            total_messages,
-            filtered_messages.len()
+            "DEBUG continue_with_tool_results: Total messages: {}, Sent: {}",
+            session.messages.len(),
+            history.len()
         );
 
-        messages.extend(filtered_messages.iter().map(|m| ProviderMessage {
-            role: m.role.clone(),
-            content: m.content.clone(),
-        }));
-
-        // Clear pending results since we're sending them now
-        self.pending_tool_results.clear();
-        self.done_received = false;
+        messages.extend(history);
 
-        // Get tool definitions
-        let tools = Some(crate::provider::get_tool_definitions());
+        // The assistant turn that requested the tools, with its tool_use blocks.
+        // Claude requires the turn's thinking block to come first.
+        let mut assistant_content: Vec<ContentBlock> = thinking
+            .map(|(thinking, signature)| ContentBlock::Thinking {
+                thinking,
+                signature,
+            })
+            .into_iter()
+            .collect();
+        assistant_content.push(ContentBlock::Text {
+            text: assistant_text.to_string(),
+        });
+        assistant_content.extend(tool_results.iter().map(|(call, _)| ContentBlock::ToolUse {
+            id: call.id.clone(),
+            name: call.name.clone(),
+            input: call.input.clone(),
+        }));
+        messages.push(ProviderMessage {
+            role: "assistant".to_string(),
+            content: assistant_content,
+        });
 
-        // Continue conversation with tool results
-        // Use registry to get provider
-        let provider = if provider_name == "ollama" {
-            Some(&self.ollama as &dyn LlmProvider)
-        } else {
-            self.provider_registry.get(&provider_name)
-        };
+        let tool_result_structs: Vec<ToolResult> = tool_results
+            .iter()
+            .map(|(call, output)| ToolResult {
+                tool_use_id: call.id.clone(),
+                content: output.clone(),
+                is_error: call.is_error,
+            })
+            .collect();
 
-        if let Some(provider) = provider {
-            // Get model from session or config default
-            let model_id = session
-                .model
-                .clone()
-                .unwrap_or_else(|| self.config.model_for_provider(&provider_name));
+        self.done_received = false;
 
-            if let Ok(receiver) =
-                provider.continue_with_tools(&model_id, messages, tools, tool_result_structs, 4096)
-            {
-                self.response_receiver = Some(receiver);
-            }
-        } else {
-            self.waiting_for_response = false;
-            self.response_receiver = None;
-        }
+        // Stay with the provider that asked for the tools, falling back as usual
+        let primary = self
+            .active_request
+            .as_ref()
+            .map_or(session.llm_provider.as_str(), |r| r.provider.as_str());
+        let chain = self
+            .config
+            .provider_chain(primary, session.project.as_deref());
+        self.start_request(messages, Some(tool_result_structs), chain, false);
     }
 
     pub fn check_pull_progress(&mut self) {
@@ -1195,9 +1538,18 @@ impl App {
     }
 
     fn save_current_message(&mut self) {
+        self.save_recent_messages(1);
+    }
+
+    /// Save the last `count` messages and the session metadata
+    fn save_recent_messages(&mut self, count: usize) {
         if let Some(ref mut session) = self.current_session {
-            if let Some(last_msg) = session.messages.last() {
-                let _ = db::save_message(&self.conn, &session.id, last_msg);
+            let start = session.messages.len().saturating_sub(count);
+            for msg in &session.messages[start..] {
+                let _ = db::save_message(&self.conn, &session.id, msg);
+            }
+            if let Some(ref indexer) = self.indexer {
+                indexer.notify();
             }
             let _ = db::save_session(&self.conn, session);
             self.last_autosave = Instant::now();
@@ -1213,22 +1565,39 @@ impl App {
 
         // Esc cancels streaming response (in any mode)
         if key.code == KeyCode::Esc && self.waiting_for_response {
-            self.response_receiver = None;
+            // Abort the request itself, not just our end of the channel
+            if let Some(stream) = self.response_stream.take() {
+                stream.cancel();
+            }
             self.waiting_for_response = false;
+            self.retry_status = None;
             self.pending_tool_calls.clear();
-            self.pending_tool_results.clear();
             self.done_received = false;
-            // Save whatever was streamed so far
-            if !self.assistant_buffer.is_empty() {
+            let tool_results = std::mem::take(&mut self.pending_tool_results);
+            let thinking = self.take_thinking();
+            let answered_by = self.answered_by();
+            self.active_request = None;
+            if !tool_results.is_empty() {
+                // Tools that already ran stay in history
+                let assistant_text = std::mem::take(&mut self.assistant_buffer);
+                self.record_tool_turn(assistant_text, thinking, tool_results, answered_by);
+            }
+            // Save whatever was streamed so far, marked as cut off
+            else if !self.assistant_buffer.is_empty() {
+                let content = std::mem::take(&mut self.assistant_buffer);
+                let (model_name, provider) = answered_by;
                 if let Some(ref mut session) = self.current_session {
-                    let model_name = Some(self.config.model_for_provider(&session.llm_provider));
-                    session.add_message(
-                        "assistant".to_string(),
-                        self.assistant_buffer.clone(),
-                        model_name,
-                    );
+                    session.add_interrupted_message(content, model_name);
+                    session.set_last_provider(provider);
+                    if let Some((text, signature)) = thinking {
+                        session.set_last_thinking(text, signature);
+                    }
+                }
+                match self.config.autosave_mode {
+                    AutosaveMode::OnSend => self.save_current_message(),
+                    AutosaveMode::Timer => self.needs_save = true,
+                    AutosaveMode::Disabled => {}
                 }
-                self.assistant_buffer.clear();
             }
             return Ok(false);
         }
@@ -1256,20 +1625,28 @@ impl App {
             }
             KeyCode::Char('q') | KeyCode::Char('Q') | KeyCode::Esc => {
                 // Quit/cancel - reject current and discard remaining queue
-                if let Some((name, _)) = self.pending_tool_calls.pop_front() {
-                    self.pending_tool_results
-                        .push((name, "Tool execution rejected by user".to_string()));
+                if let Some(call) = self.pending_tool_calls.pop_front() {
+                    self.resolve_tool_call(call, ToolStatus::Rejected);
                 }
                 // Reject all remaining queued tools
-                while let Some((name, _)) = self.pending_tool_calls.pop_front() {
-                    self.pending_tool_results
-                        .push((name, "Tool execution cancelled by user".to_string()));
+                while let Some(call) = self.pending_tool_calls.pop_front() {
+                    self.resolve_tool_call(call, ToolStatus::Cancelled);
                 }
                 self.awaiting_tool_confirmation = false;
                 self.tool_status = None;
                 self.done_received = false;
                 self.waiting_for_response = false;
-                self.response_receiver = None;
+                if let Some(stream) = self.response_stream.take() {
+                    stream.cancel();
+                }
+                // Record the turn so the rejections are saved and the next
+                // request doesn't resend results for calls it never made
+                let tool_results = std::mem::take(&mut self.pending_tool_results);
+                let thinking = self.take_thinking();
+                let answered_by = self.answered_by();
+                self.active_request = None;
+                let assistant_text = std::mem::take(&mut self.assistant_buffer);
+                self.record_tool_turn(assistant_text, thinking, tool_results, answered_by);
                 Ok(false)
             }
             _ => Ok(false), // Ignore other keys while waiting for confirmation
@@ -1318,10 +1695,8 @@ impl App {
             KeyCode::Char('1') => {
                 self.screen = AppScreen::SessionList;
             }
-            KeyCode::Char('2') => {
-                if self.current_session.is_some() {
-                    self.screen = AppScreen::Chat;
-                }
+            KeyCode::Char('2') if self.current_session.is_some() => {
+                self.screen = AppScreen::Chat;
             }
             KeyCode::Char('3') => {
                 self.screen = AppScreen::Providers;
@@ -1341,20 +1716,10 @@ impl App {
             }
             KeyCode::Enter if self.screen == AppScreen::Chat => {
                 // Send message in normal mode
-                if !self.message_buffer.is_empty() && !self.waiting_for_response {
-                    if let Some(ref mut session) = self.current_session {
-                        session.add_message("user".to_string(), self.message_buffer.clone(), None);
-
-                        let _ = self.send_llm_message();
-
-                        match self.config.autosave_mode {
-                            AutosaveMode::OnSend => self.save_current_message(),
-                            AutosaveMode::Timer => self.needs_save = true,
-                            AutosaveMode::Disabled => {}
-                        }
-                    }
-                    self.message_buffer.clear();
-                }
+                self.submit_message_buffer();
+            }
+            KeyCode::Char('t') if self.screen == AppScreen::Chat => {
+                self.show_thinking = !self.show_thinking;
             }
             KeyCode::Char('j') | KeyCode::Down => {
                 if self.screen == AppScreen::Chat {
@@ -1394,10 +1759,8 @@ impl App {
                         self.search_nav.selected_index.saturating_sub(1);
                 }
             }
-            KeyCode::Char('g') => {
-                if self.screen == AppScreen::SessionList {
-                    self.session_nav.selected_index = 0;
-                }
+            KeyCode::Char('g') if self.screen == AppScreen::SessionList => {
+                self.session_nav.selected_index = 0;
             }
             KeyCode::Char('G') => {
                 if self.screen == AppScreen::Chat {
@@ -1416,64 +1779,63 @@ impl App {
             {
                 self.provider_screen_mode = ProviderScreenMode::List;
             }
-            KeyCode::Char('n') => {
-                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() {
-                    // Get parent project of currently selected item
-                    let project = self
-                        .session_tree
-                        .get_parent_project(self.session_nav.selected_index);
-                    let model = Some(
-                        self.config
-                            .model_for_provider(&self.config.default_provider),
-                    );
-                    let session =
-                        Session::new(None, project, self.config.default_provider.clone(), model);
-                    if db::save_session(&self.conn, &session).is_ok() {
-                        self.sessions = db::list_sessions(&self.conn).unwrap_or_default();
-                        self.rebuild_tree();
-                        self.current_session = Some(session);
-                        self.screen = AppScreen::Chat;
-                    }
+            KeyCode::Char('n')
+                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() =>
+            {
+                // Get parent project of currently selected item
+                let project = self
+                    .session_tree
+                    .get_parent_project(self.session_nav.selected_index);
+                let model = Some(
+                    self.config
+                        .model_for_provider(&self.config.default_provider),
+                );
+                let session =
+                    Session::new(None, project, self.config.default_provider.clone(), model);
+                if db::save_session(&self.conn, &session).is_ok() {
+                    self.sessions = db::list_sessions(&self.conn).unwrap_or_default();
+                    self.rebuild_tree();
+                    self.current_session = Some(session);
+                    self.screen = AppScreen::Chat;
                 }
             }
             KeyCode::Char('d')
                 if self.screen == AppScreen::Providers
-                    && self.provider_screen_mode == ProviderScreenMode::List =>
+                    && self.provider_screen_mode == ProviderScreenMode::List
+                    && !self.provider_list.is_empty() =>
             {
-                if !self.provider_list.is_empty() {
-                    self.provider_screen_mode = ProviderScreenMode::ConfirmDelete;
-                }
+                self.provider_screen_mode = ProviderScreenMode::ConfirmDelete;
             }
-            KeyCode::Char('d') => {
-                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() {
-                    // Get the currently selected item
-                    let selected_idx = self.session_nav.selected_index;
-                    if selected_idx < self.session_tree.items.len() {
-                        if let Some(session) = self.session_tree.items[selected_idx].session() {
-                            let session_id = session.id.clone();
-                            let _ = db::delete_session(&self.conn, &session_id);
-                            self.sessions = db::list_sessions(&self.conn).unwrap_or_default();
-                            self.rebuild_tree();
-                            // Adjust selected index if needed
-                            if self.session_nav.selected_index >= self.session_tree.items.len()
-                                && !self.session_tree.items.is_empty()
-                            {
-                                self.session_nav.selected_index = self.session_tree.items.len() - 1;
-                            }
+            KeyCode::Char('d')
+                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() =>
+            {
+                // Get the currently selected item
+                let selected_idx = self.session_nav.selected_index;
+                if selected_idx < self.session_tree.items.len() {
+                    if let Some(session) = self.session_tree.items[selected_idx].session() {
+                        let session_id = session.id.clone();
+                        let _ = db::delete_session(&self.conn, &session_id);
+                        self.sessions = db::list_sessions(&self.conn).unwrap_or_default();
+                        self.rebuild_tree();
+                        // Adjust selected index if needed
+                        if self.session_nav.selected_index >= self.session_tree.items.len()
+                            && !self.session_tree.items.is_empty()
+                        {
+                            self.session_nav.selected_index = self.session_tree.items.len() - 1;
                         }
                     }
                 }
             }
-            KeyCode::Char(' ') => {
-                // Space bar toggles project expand/collapse
-                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() {
-                    let selected_idx = self.session_nav.selected_index;
-                    if selected_idx < self.session_tree.items.len() {
-                        let item = &self.session_tree.items[selected_idx];
-                        if item.is_project() {
-                            self.session_tree.toggle_project(selected_idx);
-                            self.rebuild_tree();
-                        }
+            // Space bar toggles project expand/collapse
+            KeyCode::Char(' ')
+                if self.screen == AppScreen::SessionList && !self.session_tree.items.is_empty() =>
+            {
+                let selected_idx = self.session_nav.selected_index;
+                if selected_idx < self.session_tree.items.len() {
+                    let item = &self.session_tree.items[selected_idx];
+                    if item.is_project() {
+                        self.session_tree.toggle_project(selected_idx);
+                        self.rebuild_tree();
                     }
                 }
             }
@@ -1505,6 +1867,10 @@ impl App {
                             if let Ok(messages) = db::load_messages(&self.conn, &session.id) {
                                 session.messages = messages;
                             }
+                            session.tokenizer = self.config.tokenizer_for_provider(
+                                &session.llm_provider,
+                                session.model.as_deref(),
+                            );
 
                             // Set session context for tools (for saving files)
                             // Note: We can't share the connection directly, so we'll handle saving in execute_tool
@@ -1548,8 +1914,7 @@ impl App {
                                         "[File: {}]\n\n{}",
                                         file.file_path, current_content
                                     );
-                                    let token_count =
-                                        Some(crate::session::estimate_tokens(&content));
+                                    let token_count = Some(session.tokenizer.count(&content));
                                     let context_message = crate::session::Message {
                                         role: "system".to_string(),
                                         content,
@@ -1558,6 +1923,14 @@ impl App {
                                         tools_executed: false,
                                         is_summary: false,
                                         token_count,
+                                        tool_call: None,
+                                        interrupted: false,
+                                        thinking: None,
+                                        thinking_signature: None,
+                                        provider: None,
+                                        attachments: Vec::new(),
+                                        usage: None,
+                                        cost: None,
                                     };
                                     session.messages.push(context_message);
                                 }
@@ -1620,7 +1993,8 @@ impl App {
                             let _ = db::save_session(&self.conn, session);
 
                             // Clear any active receiver
-                            self.response_receiver = None;
+                            self.response_stream = None;
+                            self.active_request = None;
                             self.waiting_for_response = false;
 
                             session.add_message(
@@ -1636,15 +2010,15 @@ impl App {
                 }
             }
             // Providers screen keys
-            KeyCode::Char('s') if self.screen == AppScreen::Providers => {
-                if !self.provider_list.is_empty() {
-                    let idx = self.provider_nav.selected_index;
-                    if idx < self.provider_list.len() {
-                        let name = self.provider_list[idx].name.clone();
-                        self.config.default_provider = name;
-                        let _ = self.config.save();
-                        self.refresh_provider_list();
-                    }
+            KeyCode::Char('s')
+                if self.screen == AppScreen::Providers && !self.provider_list.is_empty() =>
+            {
+                let idx = self.provider_nav.selected_index;
+                if idx < self.provider_list.len() {
+                    let name = self.provider_list[idx].name.clone();
+                    self.config.default_provider = name;
+                    let _ = self.config.save();
+                    self.refresh_provider_list();
                 }
             }
             KeyCode::Char('y')
@@ -1657,13 +2031,13 @@ impl App {
                     self.delete_provider(&name);
                 }
             }
-            KeyCode::Char('t') if self.screen == AppScreen::Providers => {
-                if !self.provider_list.is_empty() {
-                    let idx = self.provider_nav.selected_index;
-                    if idx < self.provider_list.len() {
-                        let name = self.provider_list[idx].name.clone();
-                        self.test_provider_connection(&name);
-                    }
+            KeyCode::Char('t')
+                if self.screen == AppScreen::Providers && !self.provider_list.is_empty() =>
+            {
+                let idx = self.provider_nav.selected_index;
+                if idx < self.provider_list.len() {
+                    let name = self.provider_list[idx].name.clone();
+                    self.test_provider_connection(&name);
                 }
             }
             KeyCode::Esc if self.screen == AppScreen::Providers => {
@@ -1776,9 +2150,13 @@ impl App {
                     self.refresh_provider_list();
                 }
             }
-            KeyCode::Esc if self.screen == AppScreen::Search => {
+            KeyCode::Esc if self.screen == AppScreen::Search || self.screen == AppScreen::Cost => {
                 self.screen = self.previous_screen.clone();
             }
+            KeyCode::Tab if self.screen == AppScreen::Search => {
+                self.search_mode = self.search_mode.next();
+                self.run_search();
+            }
             KeyCode::Char('/')
                 if self.screen == AppScreen::Search || self.screen == AppScreen::SessionList =>
             {
@@ -1790,6 +2168,94 @@ impl App {
         Ok(false)
     }
 
+    /// Run `search_query` in the current search mode
+    fn run_search(&mut self) {
+        let keyword = db::search_messages(&self.conn, &self.search_query).unwrap_or_default();
+        self.search_error = None;
+        self.search_unindexed = 0;
+        self.search_results = match self.search_mode {
+            SearchMode::Keyword => keyword,
+            mode => match self.semantic_search() {
+                Ok(semantic) if mode == SearchMode::Hybrid => {
+                    crate::semantic::hybrid(semantic, keyword)
+                }
+                Ok(semantic) => semantic,
+                Err(e) => {
+                    self.search_error = Some(e.to_string());
+                    Vec::new()
+                }
+            },
+        };
+        self.search_nav = ListNavigator::new();
+    }
+
+    fn semantic_search(&mut self) -> Result<Vec<db::SearchResult>> {
+        let embedding = &self.config.embedding;
+        let provider = self
+            .provider_registry
+            .get(&embedding.provider)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Embedding provider '{}' is not configured",
+                    embedding.provider
+                )
+            })?;
+        // Catch up on anything saved outside the usual paths
+        if let Some(ref indexer) = self.indexer {
+            indexer.notify();
+        }
+        self.search_unindexed = db::count_unembedded(&self.conn, &embedding.model)?;
+        crate::semantic::search(&self.conn, provider, &embedding.model, &self.search_query)
+    }
+
+    /// Send the message buffer as a user message, unless empty or a response is in flight
+    fn submit_message_buffer(&mut self) {
+        if self.message_buffer.is_empty() || self.waiting_for_response {
+            return;
+        }
+
+        if self.current_session.is_none() {
+            return;
+        }
+
+        // `@shot.png` in the message attaches that image
+        for path in mentioned_images(&self.message_buffer) {
+            match Attachment::from_path(&path) {
+                Ok(attachment) => self.pending_attachments.push(attachment),
+                Err(e) => {
+                    // Keep the message so the mention can be fixed
+                    if let Some(ref mut session) = self.current_session {
+                        session.add_message("system".to_string(), format!("Error: {}", e), None);
+                    }
+                    self.pending_attachments.clear();
+                    return;
+                }
+            }
+        }
+
+        if let Some(ref mut session) = self.current_session {
+            // The provider or model may have changed since the last message
+            session.tokenizer = self
+                .config
+                .tokenizer_for_provider(&session.llm_provider, session.model.as_deref());
+            session.add_user_message(
+                self.message_buffer.clone(),
+                std::mem::take(&mut self.pending_attachments),
+            );
+
+            // Save before sending: failed providers add notes after the message
+            match self.config.autosave_mode {
+                AutosaveMode::OnSend => self.save_current_message(),
+                AutosaveMode::Timer => self.needs_save = true,
+                AutosaveMode::Disabled => {}
+            }
+
+            let _ = self.send_llm_message();
+        }
+        self.message_buffer.clear();
+        self.input_scroll = 0;
+    }
+
     fn handle_command_mode(&mut self, key: KeyEvent) -> Result<bool> {
         match key.code {
             KeyCode::Esc => {
@@ -1878,21 +2344,7 @@ impl App {
             }
             KeyCode::Char(' ') if key.modifiers.contains(KeyModifiers::CONTROL) => {
                 // Ctrl+Space sends message in insert mode
-                if !self.message_buffer.is_empty() && !self.waiting_for_response {
-                    if let Some(ref mut session) = self.current_session {
-                        session.add_message("user".to_string(), self.message_buffer.clone(), None);
-
-                        let _ = self.send_llm_message();
-
-                        match self.config.autosave_mode {
-                            AutosaveMode::OnSend => self.save_current_message(),
-                            AutosaveMode::Timer => self.needs_save = true,
-                            AutosaveMode::Disabled => {}
-                        }
-                    }
-                    self.message_buffer.clear();
-                    self.input_scroll = 0;
-                }
+                self.submit_message_buffer();
             }
             KeyCode::Backspace => {
                 self.message_buffer.pop();
@@ -1941,14 +2393,192 @@ impl App {
             let query = cmd.strip_prefix("search").unwrap_or("").trim();
             if !query.is_empty() {
                 self.search_query = query.to_string();
-                self.search_results = db::search_messages(&self.conn, query).unwrap_or_default();
-                self.search_nav = ListNavigator::new();
+                self.run_search();
                 self.previous_screen = self.screen.clone();
                 self.screen = AppScreen::Search;
             }
             return Ok(false);
         }
 
+        // :cost - spend by day, project and model
+        if cmd == "cost" {
+            self.cost_report = db::cost_report(&self.conn)?;
+            if self.screen != AppScreen::Cost {
+                self.previous_screen = self.screen.clone();
+            }
+            self.screen = AppScreen::Cost;
+            return Ok(false);
+        }
+
+        // :tokens - count the next request's prompt with the provider
+        if cmd == "tokens" {
+            let message = match self.count_prompt_tokens() {
+                Ok(Some(count)) => format!("Next request: {} prompt tokens", count),
+                Ok(None) => "This provider can't count tokens ahead of time".to_string(),
+                Err(e) => format!("Error: {}", e),
+            };
+            if let Some(ref mut session) = self.current_session {
+                let estimate = session.total_tokens();
+                session.add_message(
+                    "system".to_string(),
+                    format!("{} (conversation so far: {} tokens)", message, estimate),
+                    None,
+                );
+            }
+            return Ok(false);
+        }
+
+        // :attach <path> - send an image with the next message
+        if cmd == "attach" || cmd.starts_with("attach ") {
+            let path = cmd.strip_prefix("attach").unwrap_or("").trim();
+            let message = if path.is_empty() {
+                "Usage: :attach <image path>".to_string()
+            } else {
+                match Attachment::from_path(std::path::Path::new(path)) {
+                    Ok(attachment) => {
+                        let message = format!(
+                            "Attached {} ({} KB); it will be sent with your next message",
+                            attachment.name,
+                            attachment.size() / 1024
+                        );
+                        self.pending_attachments.push(attachment);
+                        message
+                    }
+                    Err(e) => format!("Error: {}", e),
+                }
+            };
+            if let Some(ref mut session) = self.current_session {
+                session.add_message("system".to_string(), message, None);
+            }
+            return Ok(false);
+        }
+
+        // :detach - drop images attached for the next message
+        if cmd == "detach" {
+            let count = std::mem::take(&mut self.pending_attachments).len();
+            if let Some(ref mut session) = self.current_session {
+                session.add_message(
+                    "system".to_string(),
+                    format!("Removed {} attachment(s)", count),
+                    None,
+                );
+            }
+            return Ok(false);
+        }
+
+        // :budget [override] - show spend against limits, or allow this
+        // session to send past its hard limits
+        if cmd == "budget" || cmd == "budget override" {
+            let Some(session) = self.current_session.as_ref() else {
+                return Ok(false);
+            };
+            let provider = session.llm_provider.clone();
+            let message = if cmd == "budget override" {
+                self.budget_override = Some(session.id.clone());
+                "Hard spend limits overridden for this session".to_string()
+            } else {
+                let limits = self.budget_spend(&provider);
+                if limits.is_empty() {
+                    format!("No spend limits set for {} or this project", provider)
+                } else {
+                    let describe =
+                        |limit: Option<f64>| limit.map_or("none".to_string(), format_cost);
+                    limits
+                        .iter()
+                        .map(|(scope, spent, budget)| {
+                            format!(
+                                "{}: {} spent this month (soft: {}, hard: {})",
+                                scope,
+                                format_cost(*spent),
+                                describe(budget.soft),
+                                describe(budget.hard)
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                }
+            };
+            self.refresh_budget_warning(&provider);
+            if let Some(ref mut session) = self.current_session {
+                session.add_message("system".to_string(), message, None);
+            }
+            return Ok(false);
+        }
+
+        // :schema [<file>|off|retry] - require replies to match a JSON Schema
+        if cmd == "schema" || cmd.starts_with("schema ") {
+            let arg = cmd.strip_prefix("schema").unwrap_or("").trim();
+            if arg == "retry" {
+                let message = match self.schema_retry.take() {
+                    Some(_) if self.waiting_for_response => {
+                        "Wait for the current reply to finish".to_string()
+                    }
+                    Some(prompt) => {
+                        self.send_schema_retry(prompt);
+                        return Ok(false);
+                    }
+                    None => "No reply to retry".to_string(),
+                };
+                if let Some(ref mut session) = self.current_session {
+                    session.add_message("system".to_string(), message, None);
+                }
+                return Ok(false);
+            }
+
+            if let Some(ref mut session) = self.current_session {
+                let message = match arg {
+                    "" => match &session.response_schema {
+                        Some(schema) => format!("Schema: {}", schema),
+                        None => "No schema set; replies are free-form".to_string(),
+                    },
+                    "off" => {
+                        session.response_schema = None;
+                        let _ = db::save_session(&self.conn, session);
+                        "Schema cleared; replies are free-form".to_string()
+                    }
+                    path => match crate::schema::load(std::path::Path::new(path)) {
+                        Ok(schema) => {
+                            session.response_schema = Some(schema);
+                            let _ = db::save_session(&self.conn, session);
+                            format!("Replies must now match the schema in {}", path)
+                        }
+                        Err(e) => format!("Error: {}", e),
+                    },
+                };
+                session.add_message("system".to_string(), message, None);
+            }
+            self.schema_retry = None;
+            self.schema_retries_used = 0;
+            return Ok(false);
+        }
+
+        // :set [key [value]] - per-session sampling overrides; no value clears
+        if cmd == "set" || cmd.starts_with("set ") {
+            let args = cmd.strip_prefix("set").unwrap_or("").trim();
+            let (key, value) = args.split_once(' ').unwrap_or((args, ""));
+            if let Some(ref mut session) = self.current_session {
+                let message = if key.is_empty() {
+                    let provider = self.config.chat_options_for_provider(&session.llm_provider);
+                    format!(
+                        "Sampling: {} (provider: {}, session: {})",
+                        provider.sampling.merged(&session.sampling).describe(),
+                        provider.sampling.describe(),
+                        session.sampling.describe()
+                    )
+                } else {
+                    match session.sampling.set(key, value) {
+                        Ok(()) => {
+                            let _ = db::save_session(&self.conn, session);
+                            format!("Session sampling: {}", session.sampling.describe())
+                        }
+                        Err(e) => format!("Error: {}", e),
+                    }
+                };
+                session.add_message("system".to_string(), message, None);
+            }
+            return Ok(false);
+        }
+
         // :provider <name> - switch LLM provider for current session
         if cmd.starts_with("provider") {
             let parts: Vec<&str> = cmd.split_whitespace().collect();
@@ -1978,7 +2608,8 @@ impl App {
                         let _ = db::save_session(&self.conn, session);
 
                         // Clear any active receiver from previous provider
-                        self.response_receiver = None;
+                        self.response_stream = None;
+                        self.active_request = None;
                         self.waiting_for_response = false;
 
                         session.add_message(
@@ -2184,7 +2815,7 @@ impl App {
                     if let Some(ref mut session) = self.current_session {
                         session.add_message(
                             "system".to_string(),
-                            format!("Context loaded from file '{}':\n\n{}", target, content),
+                            format!("{}file '{}':\n\n{}", LOADED_CONTEXT_PREFIX, target, content),
                             None,
                         );
                         match self.config.autosave_mode {
@@ -2245,7 +2876,8 @@ impl App {
                                 session.add_message(
                                     "system".to_string(),
                                     format!(
-                                        "Context loaded from session '{}':\n\n{}",
+                                        "{}session '{}':\n\n{}",
+                                        LOADED_CONTEXT_PREFIX,
                                         found_session.display_name(),
                                         context
                                     ),
@@ -2281,6 +2913,7 @@ impl App {
         self.ollama_status = None;
         self.claude_status = None;
         self.bedrock_status = None;
+        self.azure_status = None;
     }
 
     pub fn advance_setup_step(&mut self) {
@@ -2301,10 +2934,15 @@ impl App {
                 self.check_bedrock_status();
             }
             3 => {
-                // Bedrock -> Complete
+                // Bedrock -> Check Azure OpenAI
                 self.setup_step = 4;
+                self.check_azure_status();
             }
             4 => {
+                // Azure OpenAI -> Complete
+                self.setup_step = 5;
+            }
+            5 => {
                 // Complete -> Exit to session list
                 self.screen = AppScreen::SessionList;
                 self.setup_step = 0;
@@ -2323,8 +2961,10 @@ impl App {
             })
             .unwrap_or_else(|| "http://localhost:11434".to_string());
 
-        let client = reqwest::blocking::Client::new();
-        match client.get(format!("{}/api/tags", ollama_url)).send() {
+        let request = crate::provider::http_client()
+            .get(format!("{}/api/tags", ollama_url))
+            .send();
+        match crate::provider::block_on(request) {
             Ok(resp) if resp.status().is_success() => {
                 self.ollama_status = Some(true);
                 self.setup_message = format!("✓ Connected to Ollama at {}", ollama_url);
@@ -2352,6 +2992,26 @@ impl App {
         }
     }
 
+    fn check_azure_status(&mut self) {
+        // Check for an azure_openai provider that can resolve its key
+        let deployment = self.config.providers.values().find_map(|p| match p {
+            crate::config::ProviderConfig::AzureOpenai {
+                endpoint,
+                deployment,
+                ..
+            } if p.resolve_api_key().is_some() => Some(format!("{} at {}", deployment, endpoint)),
+            _ => None,
+        });
+
+        if let Some(deployment) = deployment {
+            self.azure_status = Some(true);
+            self.setup_message = format!("✓ Azure OpenAI deployment {}", deployment);
+        } else {
+            self.azure_status = Some(false);
+            self.setup_message = "✗ No Azure OpenAI deployment configured".to_string();
+        }
+    }
+
     fn check_bedrock_status(&mut self) {
         // Try to detect AWS credentials
         // Check environment variables first
@@ -2404,3 +3064,452 @@ impl App {
         self.setup_message = "✗ No AWS credentials found".to_string();
     }
 }
+
+fn system_prompt() -> String {
+    let cwd = std::env::current_dir()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "/".to_string());
+
+    format!(
+        "You are a helpful assistant with access to tools for reading files, editing code, and searching the codebase.\n\nONLY use tools when the user explicitly asks you to work with files or code. Do NOT use tools for casual conversation.\n\nCurrent working directory: {}",
+        cwd
+    )
+}
+
+/// Image files mentioned as `@path` in a message
+fn mentioned_images(text: &str) -> Vec<std::path::PathBuf> {
+    text.split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|path| path.trim_end_matches([',', '.', ';', ':', '!', '?', ')']))
+        .map(std::path::PathBuf::from)
+        .filter(|path| image_media_type(path).is_some())
+        .collect()
+}
+
+/// Prefix of the system messages `:load` adds
+const LOADED_CONTEXT_PREFIX: &str = "Context loaded from ";
+
+/// Convert session history to provider messages.
+/// Tool records become tool_use blocks on the assistant message that issued
+/// them, followed by a user message carrying the results. Compacted messages
+/// are left out. Context added with `:load` and compaction summaries come
+/// first as system messages, so providers append them to the system prompt;
+/// other system notes stay local.
+fn history_messages(session: &Session) -> Vec<ProviderMessage> {
+    let mut messages: Vec<ProviderMessage> = session
+        .messages
+        .iter()
+        .filter(|m| {
+            m.role == "system" && (m.is_summary || m.content.starts_with(LOADED_CONTEXT_PREFIX))
+        })
+        .map(|m| ProviderMessage::text("system", m.content.clone()))
+        .collect();
+    // True while the last two messages are an assistant tool_use turn and its results
+    let mut tool_turn_open = false;
+
+    for m in session.messages.iter().filter(|m| !m.tools_executed) {
+        let Some(ref call) = m.tool_call else {
+            tool_turn_open = false;
+            if m.role == "system" || m.role == "tool" {
+                continue;
+            }
+            let mut content = Vec::new();
+            if let Some(ref thinking) = m.thinking {
+                content.push(ContentBlock::Thinking {
+                    thinking: thinking.clone(),
+                    signature: m.thinking_signature.clone(),
+                });
+            }
+            content.extend(m.attachments.iter().map(|a| ContentBlock::Image {
+                media_type: a.media_type.clone(),
+                data: a.data.clone(),
+            }));
+            if !m.content.trim().is_empty() {
+                content.push(ContentBlock::Text {
+                    text: m.content.clone(),
+                });
+            }
+            if !content.is_empty() {
+                messages.push(ProviderMessage {
+                    role: m.role.clone(),
+                    content,
+                });
+            }
+            continue;
+        };
+
+        let tool_use = ContentBlock::ToolUse {
+            id: call.id.clone(),
+            name: call.name.clone(),
+            input: call.input.clone(),
+        };
+        let tool_result = ContentBlock::ToolResult {
+            tool_use_id: call.id.clone(),
+            content: m.content.clone(),
+            is_error: call.is_error,
+        };
+
+        if tool_turn_open {
+            let n = messages.len();
+            messages[n - 2].content.push(tool_use);
+            messages[n - 1].content.push(tool_result);
+            continue;
+        }
+
+        // The assistant text may have been empty and skipped above
+        match messages.last_mut() {
+            Some(last) if last.role == "assistant" => last.content.push(tool_use),
+            _ => messages.push(ProviderMessage {
+                role: "assistant".to_string(),
+                content: vec![tool_use],
+            }),
+        }
+        messages.push(ProviderMessage {
+            role: "user".to_string(),
+            content: vec![tool_result],
+        });
+        tool_turn_open = true;
+    }
+
+    messages
+}
+
+/// Index messages with the configured embedding model, if its provider exists
+fn start_indexer(config: &Config, registry: &ProviderRegistry) -> Option<Indexer> {
+    let embedding = &config.embedding;
+    let provider = registry.get_shared(&embedding.provider)?;
+    Some(Indexer::start(provider, embedding.model.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ProjectConfig, ProviderCommon, ProviderConfig};
+    use crate::provider::Usage;
+    use crate::session::{ToolCallRecord, ToolStatus};
+    use serde_json::json;
+    use std::collections::HashMap;
+
+    fn session() -> Session {
+        Session::new(None, None, "claude".to_string(), None)
+    }
+
+    fn tool_call(id: &str) -> ToolCallRecord {
+        ToolCallRecord {
+            id: id.to_string(),
+            name: "read_file".to_string(),
+            input: serde_json::json!({"path": "a.txt"}),
+            status: ToolStatus::Approved,
+            is_error: false,
+        }
+    }
+
+    fn roles(messages: &[ProviderMessage]) -> Vec<&str> {
+        messages.iter().map(|m| m.role.as_str()).collect()
+    }
+
+    #[test]
+    fn loaded_context_leads_and_other_notes_stay_local() {
+        let mut session = session();
+        session.add_message("user".to_string(), "Hi".to_string(), None);
+        session.add_message("assistant".to_string(), "Hello".to_string(), None);
+        session.add_message("system".to_string(), "Error: timed out".to_string(), None);
+        let loaded = format!("{}file 'notes.md':\n\nremember this", LOADED_CONTEXT_PREFIX);
+        session.add_message("system".to_string(), loaded.clone(), None);
+        session.add_message("user".to_string(), "  ".to_string(), None);
+
+        let messages = history_messages(&session);
+        assert_eq!(roles(&messages), vec!["system", "user", "assistant"]);
+        assert_eq!(
+            messages[0].content,
+            vec![ContentBlock::Text { text: loaded }]
+        );
+    }
+
+    #[test]
+    fn tool_records_join_the_assistant_turn() {
+        let mut session = session();
+        session.add_message("user".to_string(), "Read both".to_string(), None);
+        session.add_message("assistant".to_string(), "Reading".to_string(), None);
+        session.add_tool_message(tool_call("call-1"), "one".to_string());
+        session.add_tool_message(tool_call("call-2"), "two".to_string());
+        session.add_message("assistant".to_string(), "Done".to_string(), None);
+
+        let messages = history_messages(&session);
+        assert_eq!(
+            roles(&messages),
+            vec!["user", "assistant", "user", "assistant"]
+        );
+        assert!(matches!(
+            &messages[1].content[..],
+            [ContentBlock::Text { .. }, ContentBlock::ToolUse { id: a, .. }, ContentBlock::ToolUse { id: b, .. }]
+                if a == "call-1" && b == "call-2"
+        ));
+        assert!(matches!(
+            &messages[2].content[..],
+            [ContentBlock::ToolResult { tool_use_id: a, content, .. }, ContentBlock::ToolResult { tool_use_id: b, .. }]
+                if a == "call-1" && content == "one" && b == "call-2"
+        ));
+    }
+
+    #[test]
+    fn tool_calls_without_text_get_an_assistant_message() {
+        let mut session = session();
+        session.add_message("user".to_string(), "Read it".to_string(), None);
+        session.add_message("assistant".to_string(), String::new(), None);
+        session.add_tool_message(tool_call("call-1"), "one".to_string());
+        session.add_tool_message(tool_call("call-2"), "two".to_string());
+        // Not after an assistant message, so it needs one of its own
+        session.add_message("user".to_string(), "Thanks".to_string(), None);
+        session.add_tool_message(tool_call("call-3"), "three".to_string());
+
+        let messages = history_messages(&session);
+        assert_eq!(
+            roles(&messages),
+            vec!["user", "assistant", "user", "user", "assistant", "user"]
+        );
+        assert_eq!(messages[1].content.len(), 2);
+        assert!(matches!(
+            &messages[4].content[..],
+            [ContentBlock::ToolUse { id, .. }] if id == "call-3"
+        ));
+    }
+
+    #[test]
+    fn compacted_messages_give_way_to_their_summary() {
+        let mut session = session();
+        session.add_message("user".to_string(), "Read it".to_string(), None);
+        session.add_message("assistant".to_string(), String::new(), None);
+        session.add_tool_message(tool_call("call-1"), "one".to_string());
+        session.add_message("assistant".to_string(), "It says one".to_string(), None);
+        for message in &mut session.messages {
+            message.tools_executed = true;
+        }
+        session.add_message_full(
+            "system".to_string(),
+            "Summary: read a file".to_string(),
+            None,
+            false,
+            true,
+            None,
+        );
+        session.add_message("user".to_string(), "And now?".to_string(), None);
+
+        let messages = history_messages(&session);
+        assert_eq!(roles(&messages), vec!["system", "user"]);
+        assert_eq!(
+            messages[0].content,
+            vec![ContentBlock::Text {
+                text: "Summary: read a file".to_string()
+            }]
+        );
+    }
+
+    /// An app whose only provider replays `cassette`, with an in-memory database
+    fn replay_app(cassette: &std::path::Path) -> App {
+        let config = Config {
+            autosave_mode: AutosaveMode::OnSend,
+            default_provider: "replay".to_string(),
+            providers: HashMap::from([(
+                "replay".to_string(),
+                ProviderConfig::Replay {
+                    common: ProviderCommon {
+                        model: "replay-model".to_string(),
+                        ..Default::default()
+                    },
+                    cassette: cassette.to_path_buf(),
+                    record: None,
+                },
+            )]),
+            ..Default::default()
+        };
+        let conn = db::create_schema(Connection::open_in_memory().unwrap()).unwrap();
+        let mut app = App::with_config(config, conn).unwrap();
+
+        let session = Session::new(None, None, "replay".to_string(), None);
+        db::save_session(&app.conn, &session).unwrap();
+        app.current_session = Some(session);
+        app
+    }
+
+    /// Write `turns` to a cassette file unique to this test process
+    fn write_cassette(name: &str, turns: serde_json::Value) -> std::path::PathBuf {
+        let cassette =
+            std::env::temp_dir().join(format!("llm-tui-test-{}-{}.json", std::process::id(), name));
+        std::fs::write(&cassette, json!({ "turns": turns }).to_string()).unwrap();
+        cassette
+    }
+
+    /// Send `text` and handle events until the reply is finished
+    fn send_and_wait(app: &mut App, text: &str) {
+        app.message_buffer = text.to_string();
+        app.submit_message_buffer();
+        let started = Instant::now();
+        while app.waiting_for_response {
+            assert!(
+                started.elapsed() < Duration::from_secs(5),
+                "reply never finished"
+            );
+            app.check_response();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn replayed_tool_loop_is_saved() {
+        let manifest = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+        let cassette = write_cassette(
+            "tool-loop",
+            json!([
+                {"events": [
+                    {"type": "text", "text": "Let me look."},
+                    {"type": "tool_use", "id": "call-1", "name": "read", "input": {"file_path": manifest}},
+                    {"type": "done", "usage": {"input_tokens": 100, "output_tokens": 20}},
+                ]},
+                {"events": [
+                    {"type": "text", "text": "It's a Rust crate."},
+                    {"type": "done", "usage": {"input_tokens": 150, "output_tokens": 10}},
+                ]},
+            ]),
+        );
+
+        let mut app = replay_app(&cassette);
+        app.auto_approve_tools = true;
+        send_and_wait(&mut app, "What is this?");
+        let _ = std::fs::remove_file(&cassette);
+
+        // The tool ran the same way it would outside the app
+        let expected_output = Tools::new().read(crate::tools::ReadParams {
+            file_path: manifest.to_string(),
+            offset: None,
+            limit: None,
+        });
+        let expected_output = match expected_output {
+            Ok(output) => output,
+            Err(e) => format!("Error: {}", e),
+        };
+
+        let session_id = app.current_session.as_ref().unwrap().id.clone();
+        let saved = db::load_messages(&app.conn, &session_id).unwrap();
+        let roles: Vec<&str> = saved.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(roles, vec!["user", "assistant", "tool", "assistant"]);
+
+        assert_eq!(saved[0].content, "What is this?");
+
+        let issuing = &saved[1];
+        assert_eq!(issuing.content, "Let me look.");
+        assert_eq!(issuing.usage, Some(Usage::new(100, 20)));
+        assert_eq!(issuing.provider.as_deref(), Some("replay"));
+        assert_eq!(issuing.cost, Some(0.0));
+
+        let tool = &saved[2];
+        let call = tool.tool_call.as_ref().unwrap();
+        assert_eq!(call.id, "call-1");
+        assert_eq!(call.name, "read");
+        assert_eq!(call.input, json!({"file_path": manifest}));
+        assert_eq!(call.status, ToolStatus::AutoApproved);
+        assert_eq!(tool.content, expected_output);
+
+        let answer = &saved[3];
+        assert_eq!(answer.content, "It's a Rust crate.");
+        assert_eq!(answer.usage, Some(Usage::new(150, 10)));
+        assert_eq!(answer.model.as_deref(), Some("replay-model"));
+
+        // What was saved is what the app holds
+        let session = app.current_session.as_ref().unwrap();
+        assert_eq!(session.messages.len(), saved.len());
+        assert_eq!(session.last_usage, Some(Usage::new(150, 10)));
+        assert!(app.assistant_buffer.is_empty());
+        assert!(app.pending_tool_results.is_empty());
+    }
+
+    #[test]
+    fn tool_turn_saves_corrected_user_tokens() {
+        let cassette = write_cassette(
+            "tool-turn-tokens",
+            json!([
+                {"events": [
+                    {"type": "text", "text": "Hello."},
+                    {"type": "done", "usage": {"input_tokens": 100, "output_tokens": 20}},
+                ]},
+                {"events": [
+                    {"type": "tool_use", "id": "call-1", "name": "unknown", "input": {}},
+                    {"type": "done", "usage": {"input_tokens": 150, "output_tokens": 10}},
+                ]},
+                {"events": [
+                    {"type": "text", "text": "That tool doesn't exist."},
+                    {"type": "done", "usage": {"input_tokens": 200, "output_tokens": 5}},
+                ]},
+            ]),
+        );
+
+        let mut app = replay_app(&cassette);
+        app.auto_approve_tools = true;
+        send_and_wait(&mut app, "Hi");
+        send_and_wait(&mut app, "Use a tool");
+        let _ = std::fs::remove_file(&cassette);
+
+        let session_id = app.current_session.as_ref().unwrap().id.clone();
+        let saved = db::load_messages(&app.conn, &session_id).unwrap();
+        let roles: Vec<&str> = saved.iter().map(|m| m.role.as_str()).collect();
+        assert_eq!(
+            roles,
+            vec![
+                "user",
+                "assistant",
+                "user",
+                "assistant",
+                "tool",
+                "assistant"
+            ]
+        );
+        // The prompt grew by 150 - 100 - 20 tokens with the second message
+        assert_eq!(saved[2].token_count, Some(30));
+        assert!(saved[4].tool_call.as_ref().unwrap().is_error);
+    }
+
+    #[test]
+    fn hard_limit_counts_unsaved_replies() {
+        let cassette = write_cassette("budget", json!([]));
+        let mut app = replay_app(&cassette);
+        let _ = std::fs::remove_file(&cassette);
+        app.config.projects.insert(
+            "app".to_string(),
+            ProjectConfig {
+                budget: Some(Budget {
+                    soft: Some(0.5),
+                    hard: Some(1.0),
+                }),
+                ..Default::default()
+            },
+        );
+        let session = app.current_session.as_mut().unwrap();
+        session.project = Some("app".to_string());
+        let session_id = session.id.clone();
+
+        let add_reply = |app: &mut App, cost: f64| {
+            let session = app.current_session.as_mut().unwrap();
+            session.add_message("assistant".to_string(), "Reply".to_string(), None);
+            session.messages.last_mut().unwrap().cost = Some(cost);
+        };
+        add_reply(&mut app, 0.6);
+        let session = app.current_session.as_ref().unwrap();
+        db::save_session(&app.conn, session).unwrap();
+        db::save_message(&app.conn, &session.id, &session.messages[0]).unwrap();
+        assert_eq!(app.hard_limit_reached("replay"), None);
+        app.refresh_budget_warning("replay");
+        assert!(app.budget_warning.is_some());
+
+        // Not saved yet, but still spent
+        add_reply(&mut app, 0.5);
+        let reason = app.hard_limit_reached("replay").unwrap();
+        assert!(
+            reason.starts_with("project app has spent $1.10"),
+            "{}",
+            reason
+        );
+
+        app.budget_override = Some(session_id);
+        assert_eq!(app.hard_limit_reached("replay"), None);
+    }
+}
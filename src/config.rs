@@ -1,3 +1,6 @@
+use crate::pricing::{self, Budget, ModelPrice};
+use crate::provider::{ChatOptions, SamplingParams, Usage};
+use crate::session::Tokenizer;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -22,6 +25,22 @@ pub struct ProviderCommon {
     pub context_window: i64,
     #[serde(default = "default_max_output_tokens")]
     pub max_output_tokens: u32,
+    /// Ask the model to reason before answering, where supported
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub thinking: bool,
+    /// Reasoning token budget (Claude, Bedrock Claude, Gemini)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thinking_budget: Option<u32>,
+    /// Hugging Face tokenizer.json files for counting tokens, keyed by the
+    /// model id (or the start of it) they belong to
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tokenizers: HashMap<String, PathBuf>,
+    /// Monthly spend limits for this provider
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+    /// temperature, top_p, top_k, stop, seed
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
 }
 
 impl Default for ProviderCommon {
@@ -30,6 +49,11 @@ impl Default for ProviderCommon {
             model: default_model(),
             context_window: default_context_window(),
             max_output_tokens: default_max_output_tokens(),
+            thinking: false,
+            thinking_budget: None,
+            tokenizers: HashMap::new(),
+            budget: None,
+            sampling: SamplingParams::default(),
         }
     }
 }
@@ -98,6 +122,10 @@ impl ApiKeyConfig {
 }
 
 /// Per-provider configuration, tagged by type.
+///
+/// HTTP providers take `headers`, sent with every request, and most take a
+/// `base_url` overriding the API root, for pointing them at an LLM gateway
+/// or a local stub.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ProviderConfig {
@@ -106,6 +134,10 @@ pub enum ProviderConfig {
         common: ProviderCommon,
         #[serde(flatten)]
         auth: ApiKeyConfig,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_url: Option<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
     Openai {
         #[serde(flatten)]
@@ -114,6 +146,8 @@ pub enum ProviderConfig {
         auth: ApiKeyConfig,
         #[serde(default, skip_serializing_if = "Option::is_none")]
         base_url: Option<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
     OpenaiCompatible {
         #[serde(flatten)]
@@ -121,16 +155,47 @@ pub enum ProviderConfig {
         #[serde(flatten)]
         auth: ApiKeyConfig,
         base_url: String,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
+    },
+    AzureOpenai {
+        #[serde(flatten)]
+        common: ProviderCommon,
+        #[serde(flatten)]
+        auth: ApiKeyConfig,
+        /// Resource endpoint, e.g. https://my-resource.openai.azure.com
+        endpoint: String,
+        /// Deployment name; `model` names the model it serves, for pricing
+        /// and token counting
+        deployment: String,
+        #[serde(default = "default_azure_api_version")]
+        api_version: String,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
     },
     Gemini {
         #[serde(flatten)]
         common: ProviderCommon,
         #[serde(flatten)]
         auth: ApiKeyConfig,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        base_url: Option<String>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        headers: HashMap<String, String>,
+        /// Block threshold per harm category, sent as `safetySettings`
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        safety_settings: HashMap<String, String>,
     },
     Bedrock {
         #[serde(flatten)]
         common: ProviderCommon,
+        /// Override the Bedrock runtime endpoint (e.g. a local stub)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint_url: Option<String>,
+        /// Use the Converse API. Unset: Anthropic models use InvokeModel,
+        /// everything else uses Converse.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        use_converse: Option<bool>,
     },
     Ollama {
         #[serde(flatten)]
@@ -140,6 +205,16 @@ pub enum ProviderConfig {
         #[serde(default)]
         auto_start: bool,
     },
+    /// Answers from a cassette file, for offline and repeatable runs
+    Replay {
+        #[serde(flatten)]
+        common: ProviderCommon,
+        cassette: PathBuf,
+        /// Record: send requests to this provider and append its streams
+        /// to the cassette
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        record: Option<String>,
+    },
 }
 
 impl ProviderConfig {
@@ -148,9 +223,11 @@ impl ProviderConfig {
             Self::Anthropic { common, .. }
             | Self::Openai { common, .. }
             | Self::OpenaiCompatible { common, .. }
+            | Self::AzureOpenai { common, .. }
             | Self::Gemini { common, .. }
             | Self::Bedrock { common, .. }
-            | Self::Ollama { common, .. } => common,
+            | Self::Ollama { common, .. }
+            | Self::Replay { common, .. } => common,
         }
     }
 
@@ -159,9 +236,11 @@ impl ProviderConfig {
             Self::Anthropic { common, .. }
             | Self::Openai { common, .. }
             | Self::OpenaiCompatible { common, .. }
+            | Self::AzureOpenai { common, .. }
             | Self::Gemini { common, .. }
             | Self::Bedrock { common, .. }
-            | Self::Ollama { common, .. } => common,
+            | Self::Ollama { common, .. }
+            | Self::Replay { common, .. } => common,
         }
     }
 
@@ -170,9 +249,11 @@ impl ProviderConfig {
             Self::Anthropic { .. } => "anthropic",
             Self::Openai { .. } => "openai",
             Self::OpenaiCompatible { .. } => "openai_compatible",
+            Self::AzureOpenai { .. } => "azure_openai",
             Self::Gemini { .. } => "gemini",
             Self::Bedrock { .. } => "bedrock",
             Self::Ollama { .. } => "ollama",
+            Self::Replay { .. } => "replay",
         }
     }
 
@@ -188,27 +269,53 @@ impl ProviderConfig {
         self.common().context_window
     }
 
-    pub fn max_output_tokens(&self) -> u32 {
-        self.common().max_output_tokens
+    /// Generation settings for requests to this provider
+    pub fn chat_options(&self) -> ChatOptions {
+        let common = self.common();
+        ChatOptions {
+            max_tokens: common.max_output_tokens,
+            thinking_budget: common
+                .thinking
+                .then(|| common.thinking_budget.unwrap_or(DEFAULT_THINKING_BUDGET)),
+            sampling: common.sampling.clone(),
+            response_schema: None,
+        }
     }
 
     pub fn base_url(&self) -> Option<&str> {
         match self {
-            Self::Openai { base_url, .. } => base_url.as_deref(),
+            Self::Anthropic { base_url, .. }
+            | Self::Openai { base_url, .. }
+            | Self::Gemini { base_url, .. } => base_url.as_deref(),
             Self::OpenaiCompatible { base_url, .. } => Some(base_url.as_str()),
+            Self::AzureOpenai { endpoint, .. } => Some(endpoint.as_str()),
             Self::Ollama { base_url, .. } => Some(base_url.as_str()),
             _ => None,
         }
     }
 
+    /// Extra request headers, for providers that send their own HTTP requests
+    pub fn headers(&self) -> Option<&HashMap<String, String>> {
+        match self {
+            Self::Anthropic { headers, .. }
+            | Self::Openai { headers, .. }
+            | Self::OpenaiCompatible { headers, .. }
+            | Self::AzureOpenai { headers, .. }
+            | Self::Gemini { headers, .. } => Some(headers),
+            Self::Bedrock { .. } | Self::Ollama { .. } | Self::Replay { .. } => None,
+        }
+    }
+
     pub fn key_source_description(&self) -> String {
         match self {
             Self::Anthropic { auth, .. }
             | Self::Openai { auth, .. }
             | Self::OpenaiCompatible { auth, .. }
+            | Self::AzureOpenai { auth, .. }
             | Self::Gemini { auth, .. } => auth.source_description(),
             Self::Bedrock { .. } => "AWS credentials".to_string(),
             Self::Ollama { .. } => "none (local)".to_string(),
+            Self::Replay { cassette, .. } => format!("cassette: {}", cassette.display()),
         }
     }
 
@@ -217,8 +324,9 @@ impl ProviderConfig {
             Self::Anthropic { auth, .. }
             | Self::Openai { auth, .. }
             | Self::OpenaiCompatible { auth, .. }
+            | Self::AzureOpenai { auth, .. }
             | Self::Gemini { auth, .. } => auth.resolve(),
-            Self::Bedrock { .. } | Self::Ollama { .. } => None,
+            Self::Bedrock { .. } | Self::Ollama { .. } | Self::Replay { .. } => None,
         }
     }
 }
@@ -234,6 +342,10 @@ pub struct Config {
     #[serde(default = "default_provider_name")]
     pub default_provider: String,
 
+    /// Providers to try, in order, when a session's provider can't answer
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback: Vec<String>,
+
     #[serde(default = "default_providers")]
     pub providers: HashMap<String, ProviderConfig>,
 
@@ -242,6 +354,52 @@ pub struct Config {
 
     #[serde(default = "default_autocompact_keep_recent")]
     pub autocompact_keep_recent: usize,
+
+    /// Per-project settings, keyed by project name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub projects: HashMap<String, ProjectConfig>,
+
+    /// Per-model prices in USD per million tokens, keyed by model id.
+    /// Adds to and overrides the built-in table.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub pricing: HashMap<String, ModelPrice>,
+
+    /// Times to resend a reply that doesn't match the session's schema,
+    /// with the validation errors, before leaving it to `:schema retry`
+    #[serde(default)]
+    pub schema_retries: u32,
+
+    /// Model used to embed text, for semantic search
+    #[serde(default)]
+    pub embedding: EmbeddingConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProjectConfig {
+    /// Overrides the global `fallback` list for this project's sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<Vec<String>>,
+    /// Monthly spend limits across all of this project's sessions
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub budget: Option<Budget>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingConfig {
+    /// Name of a configured provider that supports embeddings
+    #[serde(default = "default_provider_name")]
+    pub provider: String,
+    #[serde(default = "default_embedding_model")]
+    pub model: String,
+}
+
+impl Default for EmbeddingConfig {
+    fn default() -> Self {
+        Self {
+            provider: default_provider_name(),
+            model: default_embedding_model(),
+        }
+    }
 }
 
 // Default functions
@@ -258,10 +416,18 @@ fn default_provider_name() -> String {
     "ollama".to_string()
 }
 
+fn default_embedding_model() -> String {
+    "nomic-embed-text".to_string()
+}
+
 fn default_ollama_url() -> String {
     "http://localhost:11434".to_string()
 }
 
+fn default_azure_api_version() -> String {
+    "2024-10-21".to_string()
+}
+
 fn default_model() -> String {
     "unknown".to_string()
 }
@@ -274,6 +440,9 @@ fn default_max_output_tokens() -> u32 {
     4096
 }
 
+/// Used when `thinking = true` but no budget is given
+const DEFAULT_THINKING_BUDGET: u32 = 4096;
+
 fn default_autocompact_threshold() -> f64 {
     0.75
 }
@@ -291,6 +460,7 @@ fn default_providers() -> HashMap<String, ProviderConfig> {
                 model: "llama2".to_string(),
                 context_window: 4096,
                 max_output_tokens: 4096,
+                ..Default::default()
             },
             base_url: default_ollama_url(),
             auto_start: true,
@@ -311,8 +481,11 @@ impl Default for Config {
                         model: "claude-3-5-sonnet-20241022".to_string(),
                         context_window: 200000,
                         max_output_tokens: 8192,
+                        ..Default::default()
                     },
                     auth: ApiKeyConfig::from_env("ANTHROPIC_API_KEY"),
+                    base_url: None,
+                    headers: HashMap::new(),
                 },
             );
         }
@@ -324,7 +497,10 @@ impl Default for Config {
                     model: "us.anthropic.claude-sonnet-4-20250514-v1:0".to_string(),
                     context_window: 200000,
                     max_output_tokens: 8192,
+                    ..Default::default()
                 },
+                endpoint_url: None,
+                use_converse: None,
             },
         );
 
@@ -336,9 +512,11 @@ impl Default for Config {
                         model: "gpt-4o".to_string(),
                         context_window: 128000,
                         max_output_tokens: 16384,
+                        ..Default::default()
                     },
                     auth: ApiKeyConfig::from_env("OPENAI_API_KEY"),
                     base_url: std::env::var("OPENAI_BASE_URL").ok(),
+                    headers: HashMap::new(),
                 },
             );
         }
@@ -351,8 +529,12 @@ impl Default for Config {
                         model: "gemini-2.5-flash".to_string(),
                         context_window: 1000000,
                         max_output_tokens: 8192,
+                        ..Default::default()
                     },
                     auth: ApiKeyConfig::from_env("GEMINI_API_KEY"),
+                    base_url: None,
+                    headers: HashMap::new(),
+                    safety_settings: HashMap::new(),
                 },
             );
         }
@@ -361,9 +543,14 @@ impl Default for Config {
             autosave_mode: default_autosave_mode(),
             autosave_interval_seconds: default_autosave_interval_seconds(),
             default_provider: default_provider_name(),
+            fallback: Vec::new(),
             providers,
             autocompact_threshold: default_autocompact_threshold(),
             autocompact_keep_recent: default_autocompact_keep_recent(),
+            projects: HashMap::new(),
+            pricing: HashMap::new(),
+            schema_retries: 0,
+            embedding: EmbeddingConfig::default(),
         }
     }
 }
@@ -445,11 +632,69 @@ impl Config {
             .unwrap_or(4096)
     }
 
-    pub fn max_output_tokens_for_provider(&self, name: &str) -> u32 {
+    /// Tokenizer for counting messages sent to `model` (or the provider's
+    /// default model) on the named provider
+    pub fn tokenizer_for_provider(&self, name: &str, model: Option<&str>) -> Tokenizer {
         self.providers
             .get(name)
-            .map(|p| p.max_output_tokens())
-            .unwrap_or(4096)
+            .map(|p| {
+                Tokenizer::for_model(
+                    p.provider_type_name(),
+                    model.unwrap_or(p.model()),
+                    &p.common().tokenizers,
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn chat_options_for_provider(&self, name: &str) -> ChatOptions {
+        self.providers
+            .get(name)
+            .map(|p| p.chat_options())
+            .unwrap_or_else(|| ChatOptions::new(4096))
+    }
+
+    /// Providers to try for a request: `primary` first, then the project's
+    /// fallback list (or the global one), skipping duplicates
+    pub fn provider_chain(&self, primary: &str, project: Option<&str>) -> Vec<String> {
+        let fallback = project
+            .and_then(|p| self.projects.get(p))
+            .and_then(|p| p.fallback.as_ref())
+            .unwrap_or(&self.fallback);
+
+        let mut chain = vec![primary.to_string()];
+        for name in fallback {
+            if !chain.contains(name) {
+                chain.push(name.clone());
+            }
+        }
+        chain
+    }
+
+    /// Cost in USD of a reply from `model` on the named provider, or None
+    /// if the model has no known price. Local Ollama models are free.
+    pub fn cost(&self, provider: &str, model: &str, usage: &Usage) -> Option<f64> {
+        // Replayed replies cost nothing; recorded ones are real requests
+        if let Some(ProviderConfig::Replay { record: None, .. }) = self.providers.get(provider) {
+            return Some(0.0);
+        }
+        if let Some(price) = pricing::find_price(&self.pricing, model) {
+            return Some(price.cost(usage));
+        }
+        self.providers
+            .get(provider)
+            .filter(|p| p.provider_type_name() == "ollama")
+            .map(|_| 0.0)
+    }
+
+    /// Spend limits configured for the named provider
+    pub fn provider_budget(&self, name: &str) -> Option<Budget> {
+        self.providers.get(name)?.common().budget
+    }
+
+    /// Spend limits configured for the named project
+    pub fn project_budget(&self, name: &str) -> Option<Budget> {
+        self.projects.get(name)?.budget
     }
 
     pub fn set_model_for_provider(&mut self, name: &str, model: String) {
@@ -500,6 +745,8 @@ impl Config {
                         ..Default::default()
                     },
                     auth: ApiKeyConfig::from_env("ANTHROPIC_API_KEY"),
+                    base_url: None,
+                    headers: HashMap::new(),
                 },
             );
         }
@@ -512,6 +759,8 @@ impl Config {
                     context_window: legacy.bedrock_context_window,
                     ..Default::default()
                 },
+                endpoint_url: None,
+                use_converse: None,
             },
         );
 
@@ -526,6 +775,7 @@ impl Config {
                     },
                     auth: ApiKeyConfig::from_env("OPENAI_API_KEY"),
                     base_url: legacy.openai_base_url,
+                    headers: HashMap::new(),
                 },
             );
         }
@@ -540,6 +790,9 @@ impl Config {
                         ..Default::default()
                     },
                     auth: ApiKeyConfig::from_env("GEMINI_API_KEY"),
+                    base_url: None,
+                    headers: HashMap::new(),
+                    safety_settings: HashMap::new(),
                 },
             );
         }
@@ -548,9 +801,14 @@ impl Config {
             autosave_mode: legacy.autosave_mode,
             autosave_interval_seconds: legacy.autosave_interval_seconds,
             default_provider: legacy.default_llm_provider,
+            fallback: Vec::new(),
             providers,
             autocompact_threshold: legacy.autocompact_threshold,
             autocompact_keep_recent: legacy.autocompact_keep_recent,
+            projects: HashMap::new(),
+            pricing: HashMap::new(),
+            schema_retries: 0,
+            embedding: EmbeddingConfig::default(),
         }
     }
 }
@@ -601,3 +859,55 @@ struct LegacyConfig {
     #[serde(default = "default_autocompact_keep_recent")]
     autocompact_keep_recent: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        toml::from_str(
+            r#"
+            fallback = ["openai", "claude", "openai"]
+
+            [projects.local]
+            fallback = ["ollama", "claude"]
+
+            [projects.pinned]
+            fallback = []
+
+            [projects.plain]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn provider_chain_starts_with_the_primary() {
+        let config = config();
+        assert_eq!(
+            config.provider_chain("claude", None),
+            vec!["claude", "openai"]
+        );
+        assert_eq!(
+            config.provider_chain("gemini", Some("unknown")),
+            vec!["gemini", "openai", "claude"]
+        );
+    }
+
+    #[test]
+    fn project_fallback_replaces_the_global_one() {
+        let config = config();
+        assert_eq!(
+            config.provider_chain("claude", Some("local")),
+            vec!["claude", "ollama"]
+        );
+        assert_eq!(
+            config.provider_chain("claude", Some("pinned")),
+            vec!["claude"]
+        );
+        assert_eq!(
+            config.provider_chain("claude", Some("plain")),
+            vec!["claude", "openai"]
+        );
+    }
+}
@@ -2,7 +2,8 @@ use anyhow::Result;
 use rusqlite::{params, Connection};
 use std::path::PathBuf;
 
-use crate::session::{Message, Session};
+use crate::provider::Usage;
+use crate::session::{Message, Session, ToolCallRecord, ToolStatus};
 
 pub fn get_db_path() -> Result<PathBuf> {
     let mut path = dirs::data_local_dir()
@@ -13,10 +14,40 @@ pub fn get_db_path() -> Result<PathBuf> {
     Ok(path)
 }
 
+/// Add a column to a table unless it already exists
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let exists: bool = conn
+        .prepare(&format!(
+            "SELECT COUNT(*) FROM pragma_table_info('{}') WHERE name='{}'",
+            table, column
+        ))?
+        .query_row([], |row| row.get(0))
+        .map(|count: i32| count > 0)?;
+
+    if !exists {
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
 pub fn init_db() -> Result<Connection> {
     let db_path = get_db_path()?;
     let conn = Connection::open(db_path)?;
+    // The background indexer writes through its own connection
+    conn.busy_timeout(std::time::Duration::from_secs(5))?;
+    create_schema(conn)
+}
 
+/// Create missing tables and columns
+pub fn create_schema(conn: Connection) -> Result<Connection> {
     conn.execute(
         "CREATE TABLE IF NOT EXISTS sessions (
             id TEXT PRIMARY KEY,
@@ -114,6 +145,32 @@ pub fn init_db() -> Result<Connection> {
         conn.execute("ALTER TABLE messages ADD COLUMN token_count INTEGER", [])?;
     }
 
+    // Migration: Tool call records (role = 'tool', content holds the output)
+    add_column_if_missing(&conn, "messages", "tool_call_id", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "tool_name", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "tool_input", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "tool_status", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "tool_is_error", "BOOLEAN DEFAULT 0")?;
+    add_column_if_missing(&conn, "messages", "interrupted", "BOOLEAN DEFAULT 0")?;
+    add_column_if_missing(&conn, "messages", "thinking", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "thinking_signature", "TEXT")?;
+    add_column_if_missing(&conn, "messages", "provider", "TEXT")?;
+    // Images sent with the message, stored as JSON
+    add_column_if_missing(&conn, "messages", "attachments", "TEXT")?;
+
+    // Migration: Token usage reported by the provider for each reply
+    add_column_if_missing(&conn, "messages", "input_tokens", "INTEGER")?;
+    add_column_if_missing(&conn, "messages", "output_tokens", "INTEGER")?;
+    add_column_if_missing(&conn, "messages", "cache_read_tokens", "INTEGER")?;
+    add_column_if_missing(&conn, "messages", "cache_creation_tokens", "INTEGER")?;
+    // Cost of each reply in USD, at the prices when it was received
+    add_column_if_missing(&conn, "messages", "cost", "REAL")?;
+
+    // Migration: Per-session sampling overrides, stored as JSON
+    add_column_if_missing(&conn, "sessions", "sampling", "TEXT")?;
+    // Migration: Structured output schema, stored as JSON
+    add_column_if_missing(&conn, "sessions", "response_schema", "TEXT")?;
+
     // Create session_files table for context loading
     conn.execute(
         "CREATE TABLE IF NOT EXISTS session_files (
@@ -134,13 +191,35 @@ pub fn init_db() -> Result<Connection> {
         [],
     )?;
 
+    // Embeddings of message chunks for semantic search, one row per chunk
+    // and embedding model. Vectors are little-endian f32s.
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS message_embeddings (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            message_id INTEGER NOT NULL,
+            session_id TEXT NOT NULL,
+            model TEXT NOT NULL,
+            chunk INTEGER NOT NULL,
+            content TEXT NOT NULL,
+            embedding BLOB NOT NULL,
+            FOREIGN KEY (message_id) REFERENCES messages(id),
+            UNIQUE(message_id, model, chunk)
+        )",
+        [],
+    )?;
+
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS idx_message_embeddings_model ON message_embeddings(model)",
+        [],
+    )?;
+
     Ok(conn)
 }
 
 pub fn save_session(conn: &Connection, session: &Session) -> Result<()> {
     conn.execute(
-        "INSERT OR REPLACE INTO sessions (id, name, project, created_at, updated_at, llm_provider, model)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        "INSERT OR REPLACE INTO sessions (id, name, project, created_at, updated_at, llm_provider, model, sampling, response_schema)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
         params![
             session.id,
             session.name,
@@ -149,15 +228,32 @@ pub fn save_session(conn: &Connection, session: &Session) -> Result<()> {
             session.updated_at.timestamp(),
             session.llm_provider,
             session.model,
+            serde_json::to_string(&session.sampling)?,
+            session
+                .response_schema
+                .as_ref()
+                .map(serde_json::to_string)
+                .transpose()?,
         ],
     )?;
     Ok(())
 }
 
 pub fn save_message(conn: &Connection, session_id: &str, message: &Message) -> Result<()> {
+    let tool = message.tool_call.as_ref();
+    let usage = message.usage;
+    let attachments = if message.attachments.is_empty() {
+        None
+    } else {
+        Some(serde_json::to_string(&message.attachments)?)
+    };
     conn.execute(
-        "INSERT INTO messages (session_id, role, content, timestamp, model, tools_executed, is_summary, token_count)
-         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        "INSERT INTO messages (session_id, role, content, timestamp, model, tools_executed, is_summary, token_count,
+                               tool_call_id, tool_name, tool_input, tool_status, tool_is_error, interrupted,
+                               thinking, thinking_signature, provider, attachments,
+                               input_tokens, output_tokens, cache_read_tokens, cache_creation_tokens, cost)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18,
+                 ?19, ?20, ?21, ?22, ?23)",
         params![
             session_id,
             message.role,
@@ -167,6 +263,21 @@ pub fn save_message(conn: &Connection, session_id: &str, message: &Message) -> R
             message.tools_executed,
             message.is_summary,
             message.token_count,
+            tool.map(|t| t.id.as_str()),
+            tool.map(|t| t.name.as_str()),
+            tool.map(|t| t.input.to_string()),
+            tool.map(|t| t.status.as_str()),
+            tool.is_some_and(|t| t.is_error),
+            message.interrupted,
+            message.thinking,
+            message.thinking_signature,
+            message.provider,
+            attachments,
+            usage.and_then(|u| u.input_tokens),
+            usage.and_then(|u| u.output_tokens),
+            usage.and_then(|u| u.cache_read_tokens),
+            usage.and_then(|u| u.cache_creation_tokens),
+            message.cost,
         ],
     )?;
     Ok(())
@@ -191,12 +302,45 @@ pub fn update_message(conn: &Connection, session_id: &str, message: &Message) ->
 
 pub fn load_messages(conn: &Connection, session_id: &str) -> Result<Vec<Message>> {
     let mut stmt = conn.prepare(
-        "SELECT role, content, timestamp, model, tools_executed, is_summary, token_count FROM messages
-         WHERE session_id = ?1 ORDER BY timestamp ASC"
+        "SELECT role, content, timestamp, model, tools_executed, is_summary, token_count,
+                tool_call_id, tool_name, tool_input, tool_status, tool_is_error, interrupted,
+                thinking, thinking_signature, provider, attachments,
+                input_tokens, output_tokens, cache_read_tokens, cache_creation_tokens, cost
+         FROM messages
+         WHERE session_id = ?1 ORDER BY timestamp ASC, id ASC",
     )?;
 
     let messages = stmt
         .query_map([session_id], |row| {
+            let tool_call_id: Option<String> = row.get(7)?;
+            let tool_call = match tool_call_id {
+                Some(id) => {
+                    let input: Option<String> = row.get(9)?;
+                    let status: Option<String> = row.get(10)?;
+                    Some(ToolCallRecord {
+                        id,
+                        name: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                        input: input
+                            .and_then(|s| serde_json::from_str(&s).ok())
+                            .unwrap_or(serde_json::Value::Null),
+                        status: status
+                            .as_deref()
+                            .and_then(ToolStatus::parse)
+                            .unwrap_or(ToolStatus::Approved),
+                        is_error: row.get(11).unwrap_or(false),
+                    })
+                }
+                None => None,
+            };
+
+            let usage = Usage {
+                input_tokens: row.get(17)?,
+                output_tokens: row.get(18)?,
+                cache_read_tokens: row.get(19)?,
+                cache_creation_tokens: row.get(20)?,
+            };
+            let usage = (usage != Usage::default()).then_some(usage);
+
             Ok(Message {
                 role: row.get(0)?,
                 content: row.get(1)?,
@@ -206,6 +350,17 @@ pub fn load_messages(conn: &Connection, session_id: &str) -> Result<Vec<Message>
                 tools_executed: row.get(4).unwrap_or(false), // Handle potential NULL values gracefully
                 is_summary: row.get(5).unwrap_or(false),
                 token_count: row.get(6).ok(),
+                tool_call,
+                interrupted: row.get(12).unwrap_or(false),
+                thinking: row.get(13)?,
+                thinking_signature: row.get(14)?,
+                provider: row.get(15)?,
+                attachments: row
+                    .get::<_, Option<String>>(16)?
+                    .and_then(|s| serde_json::from_str(&s).ok())
+                    .unwrap_or_default(),
+                usage,
+                cost: row.get(21)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
@@ -215,7 +370,8 @@ pub fn load_messages(conn: &Connection, session_id: &str) -> Result<Vec<Message>
 
 pub fn list_sessions(conn: &Connection) -> Result<Vec<Session>> {
     let mut stmt = conn.prepare(
-        "SELECT id, name, project, created_at, updated_at, llm_provider, model
+        "SELECT id, name, project, created_at, updated_at, llm_provider, model, sampling, response_schema,
+                (SELECT SUM(cost) FROM messages WHERE session_id = sessions.id)
          FROM sessions ORDER BY updated_at DESC",
     )?;
 
@@ -232,6 +388,16 @@ pub fn list_sessions(conn: &Connection) -> Result<Vec<Session>> {
                 llm_provider: row.get(5)?,
                 model: row.get(6)?,
                 messages: Vec::new(),
+                sampling: row
+                    .get::<_, Option<String>>(7)?
+                    .and_then(|s| serde_json::from_str(&s).ok())
+                    .unwrap_or_default(),
+                response_schema: row
+                    .get::<_, Option<String>>(8)?
+                    .and_then(|s| serde_json::from_str(&s).ok()),
+                last_usage: None,
+                tokenizer: Default::default(),
+                cost: row.get(9)?,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
@@ -240,7 +406,11 @@ pub fn list_sessions(conn: &Connection) -> Result<Vec<Session>> {
 }
 
 pub fn delete_session(conn: &Connection, session_id: &str) -> Result<()> {
-    // Delete messages first (foreign key)
+    // Delete embeddings and messages first (foreign keys)
+    conn.execute(
+        "DELETE FROM message_embeddings WHERE session_id = ?1",
+        [session_id],
+    )?;
     conn.execute("DELETE FROM messages WHERE session_id = ?1", [session_id])?;
 
     // Delete session
@@ -293,6 +463,98 @@ pub fn load_session_files(conn: &Connection, session_id: &str) -> Result<Vec<Ses
     Ok(files)
 }
 
+/// Start of the current month in local time, as a Unix timestamp
+const MONTH_START: &str =
+    "CAST(strftime('%s', 'now', 'localtime', 'start of month', 'utc') AS INTEGER)";
+
+pub fn month_start(conn: &Connection) -> Result<i64> {
+    Ok(conn.query_row(&format!("SELECT {}", MONTH_START), [], |row| row.get(0))?)
+}
+
+/// Spend on saved priced replies since the start of the current month
+/// (local time), limited to a provider, a project and/or a session
+pub fn month_spend(
+    conn: &Connection,
+    provider: Option<&str>,
+    project: Option<&str>,
+    session_id: Option<&str>,
+) -> Result<f64> {
+    let spend = conn.query_row(
+        &format!(
+            "SELECT COALESCE(SUM(m.cost), 0)
+             FROM messages m
+             JOIN sessions s ON s.id = m.session_id
+             WHERE m.cost IS NOT NULL
+               AND m.timestamp >= {}
+               AND (?1 IS NULL OR COALESCE(m.provider, s.llm_provider) = ?1)
+               AND (?2 IS NULL OR s.project = ?2)
+               AND (?3 IS NULL OR m.session_id = ?3)",
+            MONTH_START
+        ),
+        params![provider, project, session_id],
+        |row| row.get(0),
+    )?;
+    Ok(spend)
+}
+
+/// Spend for one day, project or model in the cost report
+#[derive(Debug, Clone)]
+pub struct CostRow {
+    pub label: String,
+    pub cost: f64,
+    pub replies: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CostReport {
+    /// Most recent first, for the last 30 days with spend
+    pub by_day: Vec<CostRow>,
+    pub by_project: Vec<CostRow>,
+    pub by_model: Vec<CostRow>,
+}
+
+/// Sum the cost of priced replies, grouped by a SQL expression
+fn cost_rows(conn: &Connection, group: &str, order: &str) -> Result<Vec<CostRow>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} AS label, SUM(m.cost), COUNT(*)
+         FROM messages m
+         JOIN sessions s ON s.id = m.session_id
+         WHERE m.cost IS NOT NULL
+         GROUP BY label
+         ORDER BY {}
+         LIMIT 30",
+        group, order
+    ))?;
+
+    let rows = stmt
+        .query_map([], |row| {
+            Ok(CostRow {
+                label: row.get(0)?,
+                cost: row.get(1)?,
+                replies: row.get::<_, i64>(2)? as usize,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(rows)
+}
+
+pub fn cost_report(conn: &Connection) -> Result<CostReport> {
+    Ok(CostReport {
+        by_day: cost_rows(
+            conn,
+            "date(m.timestamp, 'unixepoch', 'localtime')",
+            "label DESC",
+        )?,
+        by_project: cost_rows(
+            conn,
+            "COALESCE(s.project, '(no project)')",
+            "SUM(m.cost) DESC",
+        )?,
+        by_model: cost_rows(conn, "COALESCE(m.model, 'unknown')", "SUM(m.cost) DESC")?,
+    })
+}
+
 #[derive(Debug, Clone)]
 pub struct SearchResult {
     pub session_id: String,
@@ -301,6 +563,8 @@ pub struct SearchResult {
     pub updated_at: i64,
     pub snippet: String,
     pub match_count: usize,
+    /// Cosine similarity of the best matching chunk, for semantic results
+    pub score: Option<f32>,
 }
 
 pub fn search_messages(conn: &Connection, query: &str) -> Result<Vec<SearchResult>> {
@@ -360,6 +624,7 @@ pub fn search_messages(conn: &Connection, query: &str) -> Result<Vec<SearchResul
                 updated_at: row.get(3)?,
                 snippet,
                 match_count,
+                score: None,
             })
         })?
         .collect::<Result<Vec<_>, _>>()?;
@@ -379,3 +644,204 @@ pub fn should_reload_file(file_path: &str, stored_hash: &str) -> Result<bool> {
         }
     }
 }
+
+/// A saved message with no embeddings from the current model yet
+pub struct UnembeddedMessage {
+    pub id: i64,
+    pub session_id: String,
+    pub content: String,
+}
+
+/// Messages searchable by meaning that `model` hasn't embedded, oldest first
+pub fn unembedded_messages(
+    conn: &Connection,
+    model: &str,
+    limit: usize,
+) -> Result<Vec<UnembeddedMessage>> {
+    let mut stmt = conn.prepare(
+        "SELECT m.id, m.session_id, m.content FROM messages m
+         WHERE m.role IN ('user', 'assistant')
+           AND TRIM(m.content, ' ' || char(9, 10, 13)) != ''
+           AND NOT EXISTS (SELECT 1 FROM message_embeddings e
+                           WHERE e.message_id = m.id AND e.model = ?1)
+         ORDER BY m.id
+         LIMIT ?2",
+    )?;
+
+    let messages = stmt
+        .query_map(params![model, limit as i64], |row| {
+            Ok(UnembeddedMessage {
+                id: row.get(0)?,
+                session_id: row.get(1)?,
+                content: row.get(2)?,
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(messages)
+}
+
+/// Number of messages still waiting to be embedded by `model`
+pub fn count_unembedded(conn: &Connection, model: &str) -> Result<usize> {
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM messages m
+         WHERE m.role IN ('user', 'assistant')
+           AND TRIM(m.content, ' ' || char(9, 10, 13)) != ''
+           AND NOT EXISTS (SELECT 1 FROM message_embeddings e
+                           WHERE e.message_id = m.id AND e.model = ?1)",
+        [model],
+        |row| row.get(0),
+    )?;
+    Ok(count as usize)
+}
+
+/// Store a message's chunks and their embeddings
+pub fn save_embeddings(
+    conn: &mut Connection,
+    message: &UnembeddedMessage,
+    model: &str,
+    chunks: &[(String, Vec<f32>)],
+) -> Result<()> {
+    let tx = conn.transaction()?;
+    for (i, (content, embedding)) in chunks.iter().enumerate() {
+        let bytes: Vec<u8> = embedding.iter().flat_map(|x| x.to_le_bytes()).collect();
+        tx.execute(
+            "INSERT OR REPLACE INTO message_embeddings
+                 (message_id, session_id, model, chunk, content, embedding)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                message.id,
+                message.session_id,
+                model,
+                i as i64,
+                content,
+                bytes
+            ],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+/// Record that `model` couldn't embed a message, so indexing doesn't pick
+/// it up again. The marker is an empty embedding, which searches skip.
+pub fn mark_unembeddable(
+    conn: &Connection,
+    message: &UnembeddedMessage,
+    model: &str,
+) -> Result<()> {
+    conn.execute(
+        "INSERT OR REPLACE INTO message_embeddings
+             (message_id, session_id, model, chunk, content, embedding)
+         VALUES (?1, ?2, ?3, 0, '', X'')",
+        params![message.id, message.session_id, model],
+    )?;
+    Ok(())
+}
+
+/// An embedded message chunk and the session it belongs to
+pub struct EmbeddedChunk {
+    pub session_id: String,
+    pub session_name: Option<String>,
+    pub project: Option<String>,
+    pub updated_at: i64,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+/// Every chunk embedded by `model`
+pub fn load_embeddings(conn: &Connection, model: &str) -> Result<Vec<EmbeddedChunk>> {
+    let mut stmt = conn.prepare(
+        "SELECT s.id, s.name, s.project, s.updated_at, e.content, e.embedding
+         FROM message_embeddings e
+         JOIN sessions s ON s.id = e.session_id
+         WHERE e.model = ?1 AND length(e.embedding) > 0",
+    )?;
+
+    let chunks = stmt
+        .query_map([model], |row| {
+            let bytes: Vec<u8> = row.get(5)?;
+            Ok(EmbeddedChunk {
+                session_id: row.get(0)?,
+                session_name: row.get(1)?,
+                project: row.get(2)?,
+                updated_at: row.get(3)?,
+                content: row.get(4)?,
+                embedding: bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(chunks)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, Utc};
+
+    fn memory_db() -> Connection {
+        create_schema(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    /// Save a session with one priced reply per (cost, provider, days ago)
+    fn priced_session(
+        conn: &Connection,
+        provider: &str,
+        project: Option<&str>,
+        replies: &[(f64, Option<&str>, i64)],
+    ) -> Session {
+        let mut session = Session::new(
+            None,
+            project.map(str::to_string),
+            provider.to_string(),
+            None,
+        );
+        // Ids are timestamps, which sessions made in one test would share
+        session.id = format!("{}-{}", provider, project.unwrap_or("none"));
+        save_session(conn, &session).unwrap();
+        for &(cost, answered_by, days_ago) in replies {
+            session.add_message("assistant".to_string(), "Reply".to_string(), None);
+            let message = session.messages.last_mut().unwrap();
+            message.cost = Some(cost);
+            message.provider = answered_by.map(str::to_string);
+            message.timestamp = Utc::now() - Duration::days(days_ago);
+            save_message(conn, &session.id, message).unwrap();
+        }
+        session
+    }
+
+    #[test]
+    fn month_spend_counts_this_month_only() {
+        let conn = memory_db();
+        // Well before the start of any month
+        priced_session(&conn, "claude", None, &[(1.0, None, 0), (5.0, None, 40)]);
+        let spend = month_spend(&conn, None, None, None).unwrap();
+        assert!((spend - 1.0).abs() < 1e-9, "{}", spend);
+    }
+
+    #[test]
+    fn month_spend_filters_by_provider_project_and_session() {
+        let conn = memory_db();
+        let first = priced_session(
+            &conn,
+            "claude",
+            Some("app"),
+            &[(1.0, None, 0), (2.0, Some("openai"), 0)],
+        );
+        priced_session(&conn, "openai", None, &[(4.0, None, 0)]);
+
+        let spend =
+            |provider, project, session| month_spend(&conn, provider, project, session).unwrap();
+        // A fallback reply counts against the provider that answered it
+        assert_eq!(spend(Some("claude"), None, None), 1.0);
+        assert_eq!(spend(Some("openai"), None, None), 6.0);
+        assert_eq!(spend(None, Some("app"), None), 3.0);
+        assert_eq!(spend(Some("openai"), Some("app"), None), 2.0);
+        assert_eq!(spend(None, None, Some(&first.id)), 3.0);
+        assert_eq!(spend(None, Some("other"), None), 0.0);
+    }
+}
@@ -1,7 +1,10 @@
 mod app;
 mod config;
 mod db;
+mod pricing;
 mod provider;
+mod schema;
+mod semantic;
 mod session;
 mod tools;
 mod tree;
@@ -77,7 +80,7 @@ fn main() -> Result<()> {
         app.check_autosave();
 
         // Check for LLM response tokens (unified handler for all providers)
-        let had_response_data = app.response_receiver.is_some();
+        let had_response_data = app.response_stream.is_some();
         app.check_response();
         if had_response_data {
             needs_redraw = true;
@@ -0,0 +1,190 @@
+//! Model prices for cost tracking
+//!
+//! Prices are in USD per million tokens. Built-in defaults cover the hosted
+//! models we know about; `[pricing]` in config.toml adds models or overrides
+//! these rates.
+
+use crate::provider::Usage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub input: f64,
+    pub output: f64,
+    /// Rate for prompt tokens read from the cache (default: the input rate)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_read: Option<f64>,
+    /// Rate for prompt tokens written to the cache (default: the input rate)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache_write: Option<f64>,
+}
+
+const fn price(input: f64, output: f64, cache_read: f64, cache_write: f64) -> ModelPrice {
+    ModelPrice {
+        input,
+        output,
+        cache_read: Some(cache_read),
+        cache_write: Some(cache_write),
+    }
+}
+
+/// Built-in prices, matched against model ids the same way as config keys
+const DEFAULT_PRICES: &[(&str, ModelPrice)] = &[
+    // Anthropic (also Bedrock ids like "us.anthropic.claude-sonnet-4-...")
+    ("claude-opus-4-5", price(5.0, 25.0, 0.5, 6.25)),
+    ("claude-opus-4", price(15.0, 75.0, 1.5, 18.75)),
+    ("claude-sonnet-4", price(3.0, 15.0, 0.3, 3.75)),
+    ("claude-haiku-4", price(1.0, 5.0, 0.1, 1.25)),
+    ("claude-3-opus", price(15.0, 75.0, 1.5, 18.75)),
+    ("claude-3-7-sonnet", price(3.0, 15.0, 0.3, 3.75)),
+    ("claude-3-5-sonnet", price(3.0, 15.0, 0.3, 3.75)),
+    ("claude-3-5-haiku", price(0.8, 4.0, 0.08, 1.0)),
+    ("claude-3-haiku", price(0.25, 1.25, 0.03, 0.3)),
+    // OpenAI
+    ("gpt-5", price(1.25, 10.0, 0.125, 1.25)),
+    ("gpt-5-mini", price(0.25, 2.0, 0.025, 0.25)),
+    ("gpt-5-nano", price(0.05, 0.4, 0.005, 0.05)),
+    ("gpt-4.1", price(2.0, 8.0, 0.5, 2.0)),
+    ("gpt-4.1-mini", price(0.4, 1.6, 0.1, 0.4)),
+    ("gpt-4.1-nano", price(0.1, 0.4, 0.025, 0.1)),
+    ("gpt-4o", price(2.5, 10.0, 1.25, 2.5)),
+    ("gpt-4o-mini", price(0.15, 0.6, 0.075, 0.15)),
+    ("gpt-4-turbo", price(10.0, 30.0, 10.0, 10.0)),
+    ("gpt-3.5-turbo", price(0.5, 1.5, 0.5, 0.5)),
+    ("o1", price(15.0, 60.0, 7.5, 15.0)),
+    ("o1-mini", price(1.1, 4.4, 0.55, 1.1)),
+    ("o3", price(2.0, 8.0, 0.5, 2.0)),
+    ("o3-mini", price(1.1, 4.4, 0.55, 1.1)),
+    ("o4-mini", price(1.1, 4.4, 0.275, 1.1)),
+    // Gemini (prompts up to 200k tokens)
+    ("gemini-2.5-pro", price(1.25, 10.0, 0.31, 1.25)),
+    ("gemini-2.5-flash", price(0.3, 2.5, 0.075, 0.3)),
+    ("gemini-2.5-flash-lite", price(0.1, 0.4, 0.025, 0.1)),
+    ("gemini-2.0-flash", price(0.1, 0.4, 0.025, 0.1)),
+    ("gemini-2.0-flash-lite", price(0.075, 0.3, 0.075, 0.075)),
+    ("gemini-1.5-pro", price(1.25, 5.0, 0.3125, 1.25)),
+    ("gemini-1.5-flash", price(0.075, 0.3, 0.01875, 0.075)),
+];
+
+/// Whether `key` names `model`: the model id contains the key, starting at
+/// the beginning or after a routing prefix ("openai/", "us.anthropic.",
+/// "models/")
+fn matches_model(key: &str, model: &str) -> bool {
+    model
+        .match_indices(key)
+        .any(|(i, _)| i == 0 || matches!(model.as_bytes()[i - 1], b'/' | b'.' | b':'))
+}
+
+/// Price for `model`: the longest matching key in `overrides`, else the
+/// longest matching built-in entry
+pub fn find_price(overrides: &HashMap<String, ModelPrice>, model: &str) -> Option<ModelPrice> {
+    let longest = |entries: &mut dyn Iterator<Item = (&str, ModelPrice)>| {
+        entries
+            .filter(|(key, _)| matches_model(key, model))
+            .max_by_key(|(key, _)| key.len())
+            .map(|(_, price)| price)
+    };
+
+    longest(&mut overrides.iter().map(|(k, p)| (k.as_str(), *p)))
+        .or_else(|| longest(&mut DEFAULT_PRICES.iter().copied()))
+}
+
+impl ModelPrice {
+    /// Cost in USD of a reply with this usage
+    pub fn cost(&self, usage: &Usage) -> f64 {
+        let tokens = |count: Option<u32>| count.unwrap_or(0) as f64;
+        let total = tokens(usage.input_tokens) * self.input
+            + tokens(usage.output_tokens) * self.output
+            + tokens(usage.cache_read_tokens) * self.cache_read.unwrap_or(self.input)
+            + tokens(usage.cache_creation_tokens) * self.cache_write.unwrap_or(self.input);
+        total / 1_000_000.0
+    }
+}
+
+/// Monthly spend limits in USD
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Budget {
+    /// Warn once spend reaches this
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft: Option<f64>,
+    /// Refuse to send once spend reaches this, until overridden
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hard: Option<f64>,
+}
+
+/// Format a cost in USD, with more precision for small amounts
+pub fn format_cost(cost: f64) -> String {
+    if cost < 1.0 {
+        format!("${:.4}", cost)
+    } else {
+        format!("${:.2}", cost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_builtin_key_wins() {
+        let none = HashMap::new();
+        assert_eq!(
+            find_price(&none, "gpt-4o-mini-2024-07-18").unwrap().input,
+            0.15
+        );
+        assert_eq!(find_price(&none, "gpt-4o-2024-08-06").unwrap().input, 2.5);
+        assert_eq!(
+            find_price(&none, "claude-opus-4-5-20251101")
+                .unwrap()
+                .output,
+            25.0
+        );
+    }
+
+    #[test]
+    fn matches_after_routing_prefixes_only() {
+        let none = HashMap::new();
+        let bedrock = find_price(&none, "us.anthropic.claude-sonnet-4-20250514-v1:0");
+        assert_eq!(bedrock.unwrap().input, 3.0);
+        assert_eq!(find_price(&none, "openai/gpt-5").unwrap().input, 1.25);
+        assert_eq!(
+            find_price(&none, "models/gemini-2.5-pro").unwrap().input,
+            1.25
+        );
+        assert!(find_price(&none, "my-gpt-4o").is_none());
+        assert!(find_price(&none, "llama3.2").is_none());
+    }
+
+    #[test]
+    fn overrides_take_precedence() {
+        let custom = ModelPrice {
+            input: 1.0,
+            output: 2.0,
+            cache_read: None,
+            cache_write: None,
+        };
+        let overrides = HashMap::from([("gpt-4o".to_string(), custom)]);
+        // Even over a longer built-in key
+        assert_eq!(find_price(&overrides, "gpt-4o-mini"), Some(custom));
+        assert_eq!(find_price(&overrides, "llama3.2:gpt-4o"), Some(custom));
+    }
+
+    #[test]
+    fn cost_defaults_cache_rates_to_input() {
+        let price = ModelPrice {
+            input: 2.0,
+            output: 10.0,
+            cache_read: None,
+            cache_write: None,
+        };
+        let usage = Usage {
+            input_tokens: Some(1_000_000),
+            output_tokens: Some(500_000),
+            cache_read_tokens: Some(1_000_000),
+            cache_creation_tokens: None,
+        };
+        assert_eq!(price.cost(&usage), 2.0 + 5.0 + 2.0);
+        assert_eq!(price.cost(&Usage::default()), 0.0);
+    }
+}
@@ -1,17 +1,22 @@
 //! Bedrock provider implementation
 
-use super::{LlmEvent, LlmProvider, ModelInfo, ProviderMessage, ToolDef};
+use super::claude::{
+    apply_cache_control, apply_options, to_anthropic_messages, AnthropicStreamDecoder, Message,
+};
+use super::{
+    block_on, collect_system_prompt, ChatOptions, ChatStream, ContentBlock, ErrorKind, EventSender,
+    LlmEvent, LlmProvider, ModelCache, ModelInfo, ProviderError, ProviderMessage, ToolDef, Usage,
+};
 use anyhow::Result;
+use aws_config::SdkConfig;
+use aws_sdk_bedrockruntime::error::{DisplayErrorContext, ProvideErrorMetadata, SdkError};
+use aws_sdk_bedrockruntime::types::{self as converse, ConverseStreamOutput, ResponseStream};
+use aws_smithy_types::{Blob, Document, Number};
+use base64::Engine;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
-
-#[derive(Debug, Serialize, Deserialize)]
-struct Message {
-    role: String,
-    content: String,
-}
+use std::sync::Arc;
+use tokio::sync::OnceCell;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Tool {
@@ -20,22 +25,279 @@ struct Tool {
     input_schema: serde_json::Value,
 }
 
-pub struct BedrockProvider {}
+pub struct BedrockProvider {
+    endpoint_url: Option<String>,
+    use_converse: Option<bool>,
+    models: ModelCache,
+    clients: Arc<Clients>,
+}
+
+/// AWS config and runtime client, created on first use and shared by every
+/// request so credentials and connections are reused
+#[derive(Default)]
+struct Clients {
+    config: OnceCell<SdkConfig>,
+    runtime: OnceCell<aws_sdk_bedrockruntime::Client>,
+}
+
+impl Clients {
+    async fn config(&self) -> &SdkConfig {
+        self.config.get_or_init(aws_config::load_from_env).await
+    }
+
+    async fn runtime(&self, endpoint_url: Option<&str>) -> aws_sdk_bedrockruntime::Client {
+        let client = self.runtime.get_or_init(|| async {
+            let mut builder = aws_sdk_bedrockruntime::config::Builder::from(self.config().await);
+            if let Some(url) = endpoint_url {
+                builder = builder.endpoint_url(url);
+            }
+            aws_sdk_bedrockruntime::Client::from_conf(builder.build())
+        });
+        client.await.clone()
+    }
+}
+
+/// Convert a JSON value to the SDK's document type
+fn json_to_document(value: serde_json::Value) -> Document {
+    match value {
+        serde_json::Value::Null => Document::Null,
+        serde_json::Value::Bool(b) => Document::Bool(b),
+        serde_json::Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                Document::Number(Number::PosInt(u))
+            } else if let Some(i) = n.as_i64() {
+                Document::Number(Number::NegInt(i))
+            } else {
+                Document::Number(Number::Float(n.as_f64().unwrap_or(0.0)))
+            }
+        }
+        serde_json::Value::String(s) => Document::String(s),
+        serde_json::Value::Array(items) => {
+            Document::Array(items.into_iter().map(json_to_document).collect())
+        }
+        serde_json::Value::Object(map) => Document::Object(
+            map.into_iter()
+                .map(|(k, v)| (k, json_to_document(v)))
+                .collect(),
+        ),
+    }
+}
 
 impl BedrockProvider {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(endpoint_url: Option<String>, use_converse: Option<bool>) -> Self {
+        Self {
+            endpoint_url,
+            use_converse,
+            models: ModelCache::default(),
+            clients: Arc::default(),
+        }
+    }
+
+    /// Streaming-capable on-demand foundation models plus the account's
+    /// inference profiles (needed for cross-region model ids)
+    fn fetch_models(&self) -> Result<Vec<ModelInfo>> {
+        use aws_sdk_bedrock::types::{InferenceType, ModelModality};
+
+        block_on(async {
+            let bedrock_client = aws_sdk_bedrock::Client::new(self.clients.config().await);
+
+            let response = bedrock_client
+                .list_foundation_models()
+                .by_output_modality(ModelModality::Text)
+                .send()
+                .await
+                .map_err(Self::classify)?;
+
+            let mut models: Vec<ModelInfo> = response
+                .model_summaries()
+                .iter()
+                .filter(|m| {
+                    m.response_streaming_supported() == Some(true)
+                        && m.inference_types_supported()
+                            .contains(&InferenceType::OnDemand)
+                })
+                .map(|m| ModelInfo {
+                    id: m.model_id().to_string(),
+                    name: m.model_name().unwrap_or(m.model_id()).to_string(),
+                    provider: "bedrock".to_string(),
+                })
+                .collect();
+
+            let mut next_token: Option<String> = None;
+            loop {
+                let response = bedrock_client
+                    .list_inference_profiles()
+                    .set_next_token(next_token.take())
+                    .send()
+                    .await
+                    .map_err(Self::classify)?;
+
+                models.extend(
+                    response
+                        .inference_profile_summaries()
+                        .iter()
+                        .map(|profile| ModelInfo {
+                            id: profile.inference_profile_id().to_string(),
+                            name: profile.inference_profile_name().to_string(),
+                            provider: "bedrock".to_string(),
+                        }),
+                );
+
+                match response.next_token() {
+                    Some(token) => next_token = Some(token.to_string()),
+                    None => break,
+                }
+            }
+
+            Ok(models)
+        })
+    }
+
+    /// Offline fallback when Bedrock can't be reached. Access differs per
+    /// account and region, so this only lists common cross-region profiles.
+    fn fallback_models() -> Vec<ModelInfo> {
+        [
+            (
+                "us.anthropic.claude-sonnet-4-5-20250929-v1:0",
+                "US Claude Sonnet 4.5",
+            ),
+            (
+                "us.anthropic.claude-sonnet-4-20250514-v1:0",
+                "US Claude Sonnet 4",
+            ),
+            (
+                "us.anthropic.claude-haiku-4-5-20251001-v1:0",
+                "US Claude Haiku 4.5",
+            ),
+            ("us.amazon.nova-pro-v1:0", "US Nova Pro"),
+        ]
+        .into_iter()
+        .map(|(id, name)| ModelInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            provider: "bedrock".to_string(),
+        })
+        .collect()
+    }
+
+    /// Anthropic models use their native body; everything else goes through Converse
+    fn uses_converse(&self, model_id: &str) -> bool {
+        self.use_converse
+            .unwrap_or_else(|| !model_id.contains("anthropic."))
+    }
+
+    fn convert_converse_messages(messages: Vec<ProviderMessage>) -> Result<Vec<converse::Message>> {
+        let mut converted = Vec::new();
+
+        for m in messages {
+            let role = match m.role.as_str() {
+                "system" => continue, // Sent as the `system` field instead
+                "assistant" => converse::ConversationRole::Assistant,
+                _ => converse::ConversationRole::User,
+            };
+
+            let mut content = Vec::new();
+            for block in m.content {
+                match block {
+                    // Converse rejects blank text blocks
+                    ContentBlock::Text { text } if text.trim().is_empty() => {}
+                    ContentBlock::Text { text } => content.push(converse::ContentBlock::Text(text)),
+                    ContentBlock::ToolUse { id, name, input } => {
+                        content.push(converse::ContentBlock::ToolUse(
+                            converse::ToolUseBlock::builder()
+                                .tool_use_id(id)
+                                .name(name)
+                                .input(json_to_document(input))
+                                .build()?,
+                        ));
+                    }
+                    ContentBlock::ToolResult {
+                        tool_use_id,
+                        content: output,
+                        is_error,
+                    } => {
+                        let status = if is_error {
+                            converse::ToolResultStatus::Error
+                        } else {
+                            converse::ToolResultStatus::Success
+                        };
+                        content.push(converse::ContentBlock::ToolResult(
+                            converse::ToolResultBlock::builder()
+                                .tool_use_id(tool_use_id)
+                                .content(converse::ToolResultContentBlock::Text(output))
+                                .status(status)
+                                .build()?,
+                        ));
+                    }
+                    ContentBlock::Image { media_type, data } => {
+                        let bytes = base64::engine::general_purpose::STANDARD.decode(data)?;
+                        let format = media_type.trim_start_matches("image/");
+                        content.push(converse::ContentBlock::Image(
+                            converse::ImageBlock::builder()
+                                .format(converse::ImageFormat::from(format))
+                                .source(converse::ImageSource::Bytes(Blob::new(bytes)))
+                                .build()?,
+                        ));
+                    }
+                    // Earlier reasoning is not replayed through Converse
+                    ContentBlock::Thinking { .. } => {}
+                }
+            }
+
+            if content.is_empty() {
+                continue;
+            }
+
+            converted.push(
+                converse::Message::builder()
+                    .role(role)
+                    .set_content(Some(content))
+                    .build()?,
+            );
+        }
+
+        Ok(converted)
+    }
+
+    fn convert_converse_tools(
+        tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<converse::ToolConfiguration>> {
+        let tools = tools.unwrap_or_default();
+        // An empty tool list is rejected, so omit the config entirely
+        if tools.is_empty() {
+            return Ok(None);
+        }
+
+        let mut builder = converse::ToolConfiguration::builder();
+        for t in tools {
+            builder = builder.tools(converse::Tool::ToolSpec(
+                converse::ToolSpecification::builder()
+                    .name(t.name)
+                    .description(t.description)
+                    .input_schema(converse::ToolInputSchema::Json(json_to_document(
+                        t.input_schema,
+                    )))
+                    .build()?,
+            ));
+        }
+
+        Ok(Some(builder.build()?))
+    }
+
+    /// Converse has no common field for top_k or seed, so those are not sent
+    fn converse_inference_config(options: &ChatOptions) -> converse::InferenceConfiguration {
+        let sampling = &options.sampling;
+        converse::InferenceConfiguration::builder()
+            .max_tokens(options.max_tokens as i32)
+            .set_temperature(sampling.temperature.map(|t| t as f32))
+            .set_top_p(sampling.top_p.map(|p| p as f32))
+            .set_stop_sequences((!sampling.stop.is_empty()).then(|| sampling.stop.clone()))
+            .build()
     }
 
     fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<Message> {
-        messages
-            .into_iter()
-            .filter(|m| m.role != "system") // Bedrock Claude doesn't support system in messages
-            .map(|m| Message {
-                role: m.role,
-                content: m.content,
-            })
-            .collect()
+        // Bedrock Claude models take the Anthropic Messages body as-is
+        to_anthropic_messages(messages)
     }
 
     fn convert_tools(tools: Option<Vec<ToolDef>>) -> Vec<Tool> {
@@ -50,77 +312,158 @@ impl BedrockProvider {
             .collect()
     }
 
-    fn chat_impl(
+    /// Tag an SDK error with its broad cause, so the app can decide
+    /// whether to fall back to another provider
+    fn classify<E, R>(error: SdkError<E, R>) -> anyhow::Error
+    where
+        E: ProvideErrorMetadata + std::error::Error + 'static,
+        R: std::fmt::Debug,
+    {
+        let kind = match &error {
+            SdkError::DispatchFailure(e) if e.is_io() || e.is_timeout() => ErrorKind::Connection,
+            SdkError::TimeoutError(_) => ErrorKind::Connection,
+            // Usually credentials that couldn't be loaded
+            SdkError::DispatchFailure(_) => ErrorKind::Auth,
+            SdkError::ServiceError(e) => match e.err().code() {
+                Some(
+                    "ThrottlingException"
+                    | "ServiceUnavailableException"
+                    | "ModelNotReadyException"
+                    | "InternalServerException",
+                ) => ErrorKind::Overloaded,
+                Some(
+                    "AccessDeniedException"
+                    | "UnrecognizedClientException"
+                    | "ExpiredTokenException",
+                ) => ErrorKind::Auth,
+                _ => ErrorKind::Other,
+            },
+            _ => ErrorKind::Other,
+        };
+        ProviderError::new(kind, DisplayErrorContext(&error).to_string()).into()
+    }
+
+    async fn chat_impl(
+        clients: Arc<Clients>,
+        endpoint_url: Option<String>,
         model_id: String,
-        messages: Vec<Message>,
-        tools: Vec<Tool>,
-        max_tokens: u32,
-        tx: Sender<LlmEvent>,
+        request_body: serde_json::Value,
+        tx: EventSender,
     ) -> Result<()> {
-        let rt = tokio::runtime::Runtime::new()?;
+        let client = clients.runtime(endpoint_url.as_deref()).await;
 
-        rt.block_on(async {
-            let config = aws_config::load_from_env().await;
-            let client = aws_sdk_bedrockruntime::Client::new(&config);
+        let mut response = client
+            .invoke_model_with_response_stream()
+            .model_id(&model_id)
+            .content_type("application/json")
+            .body(aws_sdk_bedrockruntime::primitives::Blob::new(
+                serde_json::to_vec(&request_body)?,
+            ))
+            .send()
+            .await
+            .map_err(Self::classify)?;
 
-            let request_body = json!({
-                "anthropic_version": "bedrock-2023-05-31",
-                "max_tokens": max_tokens,
-                "messages": messages,
-                "tools": tools,
-            });
-
-            let response = client
-                .invoke_model()
-                .model_id(&model_id)
-                .content_type("application/json")
-                .body(aws_sdk_bedrockruntime::primitives::Blob::new(
-                    serde_json::to_vec(&request_body)?,
-                ))
-                .send()
-                .await?;
-
-            let response_body: serde_json::Value =
-                serde_json::from_slice(response.body().as_ref())?;
-
-            if let Some(content) = response_body["content"].as_array() {
-                for block in content {
-                    let block_type = block["type"].as_str().unwrap_or("");
-
-                    match block_type {
-                        "text" => {
-                            if let Some(text) = block["text"].as_str() {
-                                tx.send(LlmEvent::Text(text.to_string()))?;
-                            }
-                        }
-                        "tool_use" => {
-                            let id = block["id"].as_str().unwrap_or("").to_string();
-                            let name = block["name"].as_str().unwrap_or("").to_string();
-                            if let Some(input) = block.get("input") {
-                                tx.send(LlmEvent::ToolUse {
-                                    id,
-                                    name,
-                                    input: input.clone(),
-                                })?;
-                            }
-                        }
-                        _ => {}
-                    }
+        // Each chunk carries one Anthropic streaming event as JSON
+        let mut decoder = AnthropicStreamDecoder::default();
+        while let Some(event) = response.body.recv().await.map_err(Self::classify)? {
+            if let ResponseStream::Chunk(part) = event {
+                let Some(bytes) = part.bytes() else {
+                    continue;
+                };
+                let event: serde_json::Value = serde_json::from_slice(bytes.as_ref())?;
+                if decoder.handle_event(&event, &tx)? {
+                    break;
                 }
             }
+        }
 
-            let input_tokens = response_body["usage"]["input_tokens"].as_u64().unwrap_or(0) as u32;
-            let output_tokens = response_body["usage"]["output_tokens"]
-                .as_u64()
-                .unwrap_or(0) as u32;
+        Ok(())
+    }
 
-            tx.send(LlmEvent::Done {
-                input_tokens: Some(input_tokens),
-                output_tokens: Some(output_tokens),
-            })?;
+    #[allow(clippy::too_many_arguments)]
+    async fn converse_impl(
+        clients: Arc<Clients>,
+        endpoint_url: Option<String>,
+        model_id: String,
+        system: Option<String>,
+        messages: Vec<converse::Message>,
+        tool_config: Option<converse::ToolConfiguration>,
+        inference_config: converse::InferenceConfiguration,
+        tx: EventSender,
+    ) -> Result<()> {
+        let client = clients.runtime(endpoint_url.as_deref()).await;
 
-            Ok::<(), anyhow::Error>(())
-        })?;
+        let mut response = client
+            .converse_stream()
+            .model_id(&model_id)
+            .set_system(system.map(|s| vec![converse::SystemContentBlock::Text(s)]))
+            .set_messages(Some(messages))
+            .set_tool_config(tool_config)
+            .inference_config(inference_config)
+            .send()
+            .await
+            .map_err(Self::classify)?;
+
+        let mut tool_id = String::new();
+        let mut tool_name = String::new();
+        let mut tool_input = String::new();
+        let mut usage = Usage::default();
+
+        while let Some(event) = response.stream.recv().await.map_err(Self::classify)? {
+            match event {
+                ConverseStreamOutput::ContentBlockStart(start) => {
+                    if let Some(converse::ContentBlockStart::ToolUse(tool)) = start.start() {
+                        tool_id = tool.tool_use_id().to_string();
+                        tool_name = tool.name().to_string();
+                        tool_input.clear();
+                    }
+                }
+                ConverseStreamOutput::ContentBlockDelta(delta) => match delta.delta() {
+                    Some(converse::ContentBlockDelta::Text(text)) => {
+                        tx.send(LlmEvent::Text(text.clone()))?;
+                    }
+                    Some(converse::ContentBlockDelta::ToolUse(tool)) => {
+                        tool_input.push_str(tool.input());
+                    }
+                    Some(converse::ContentBlockDelta::ReasoningContent(
+                        converse::ReasoningContentBlockDelta::Text(text),
+                    )) => {
+                        tx.send(LlmEvent::Thinking(text.clone()))?;
+                    }
+                    _ => {}
+                },
+                ConverseStreamOutput::ContentBlockStop(_) if !tool_name.is_empty() => {
+                    // Tools called without arguments stream no input deltas
+                    let input = if tool_input.is_empty() {
+                        Ok(json!({}))
+                    } else {
+                        serde_json::from_str(&tool_input)
+                    };
+                    if let Ok(input) = input {
+                        tx.send(LlmEvent::ToolUse {
+                            id: tool_id.clone(),
+                            name: tool_name.clone(),
+                            input,
+                        })?;
+                    }
+                    tool_id.clear();
+                    tool_name.clear();
+                    tool_input.clear();
+                }
+                // Usage arrives in the metadata event after message_stop
+                ConverseStreamOutput::Metadata(metadata) => {
+                    if let Some(reported) = metadata.usage() {
+                        usage = Usage::new(
+                            reported.input_tokens() as u32,
+                            reported.output_tokens() as u32,
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        tx.send(LlmEvent::Done(usage))?;
 
         Ok(())
     }
@@ -128,7 +471,7 @@ impl BedrockProvider {
 
 impl Default for BedrockProvider {
     fn default() -> Self {
-        Self::new()
+        Self::new(None, None)
     }
 }
 
@@ -147,51 +490,162 @@ impl LlmProvider for BedrockProvider {
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let (tx, rx) = channel();
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
+        let clients = self.clients.clone();
+        let endpoint_url = self.endpoint_url.clone();
         let model_id = model.to_string();
-        let messages = Self::convert_messages(messages);
-        let tools = Self::convert_tools(tools);
+        let system = collect_system_prompt(&messages);
 
-        thread::spawn(move || {
-            if let Err(e) = Self::chat_impl(model_id, messages, tools, max_tokens, tx.clone()) {
-                let _ = tx.send(LlmEvent::Error(format!("Bedrock error: {:?}", e)));
-            }
+        if self.uses_converse(&model_id) {
+            let messages = Self::convert_converse_messages(messages)?;
+            let tool_config = Self::convert_converse_tools(tools)?;
+            let inference_config = Self::converse_inference_config(options);
+
+            return Ok(ChatStream::spawn("Bedrock error", |tx| {
+                Self::converse_impl(
+                    clients,
+                    endpoint_url,
+                    model_id,
+                    system,
+                    messages,
+                    tool_config,
+                    inference_config,
+                    tx,
+                )
+            }));
+        }
+
+        let mut request_body = json!({
+            "anthropic_version": "bedrock-2023-05-31",
+            "max_tokens": options.max_tokens,
+            "messages": Self::convert_messages(messages),
+            "tools": Self::convert_tools(tools),
         });
+        if let Some(system) = system {
+            request_body["system"] = json!(system);
+        }
+        apply_options(&mut request_body, options);
+        apply_cache_control(&mut request_body);
 
-        Ok(rx)
+        Ok(ChatStream::spawn("Bedrock error", |tx| {
+            Self::chat_impl(clients, endpoint_url, model_id, request_body, tx)
+        }))
     }
 
     fn list_models(&self) -> Result<Vec<ModelInfo>> {
-        let rt = tokio::runtime::Runtime::new()?;
+        self.models
+            .get_or_fetch(|| self.fetch_models(), Self::fallback_models)
+    }
 
-        rt.block_on(async {
-            let bedrock_config = aws_config::load_from_env().await;
-            let bedrock_client = aws_sdk_bedrock::Client::new(&bedrock_config);
+    /// Titan text embedding models (`amazon.titan-embed-text-v2:0`) take
+    /// one input per request, so the requests are sent concurrently
+    fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        block_on(async {
+            let client = self.clients.runtime(self.endpoint_url.as_deref()).await;
+            let requests = inputs.into_iter().map(|text| {
+                let client = client.clone();
+                async move {
+                    let response = client
+                        .invoke_model()
+                        .model_id(model)
+                        .content_type("application/json")
+                        .body(aws_sdk_bedrockruntime::primitives::Blob::new(
+                            serde_json::to_vec(&json!({ "inputText": text }))?,
+                        ))
+                        .send()
+                        .await
+                        .map_err(Self::classify)?;
+                    let body: serde_json::Value = serde_json::from_slice(response.body().as_ref())?;
+                    Ok(serde_json::from_value(body["embedding"].clone())?)
+                }
+            });
+            futures::future::try_join_all(requests).await
+        })
+    }
+}
 
-            let response = bedrock_client.list_inference_profiles().send().await?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            let models: Vec<ModelInfo> = response
-                .inference_profile_summaries()
-                .iter()
-                .filter_map(|profile| {
-                    let profile_id = profile.inference_profile_id();
-                    if profile_id.contains("anthropic.claude")
-                        || profile_id.contains("us.anthropic.claude")
-                    {
-                        Some(ModelInfo {
-                            id: profile_id.to_string(),
-                            name: profile_id.to_string(),
-                            provider: "bedrock".to_string(),
-                        })
-                    } else {
-                        None
-                    }
-                })
-                .collect();
+    #[test]
+    fn converse_messages_skip_system_and_blank_text() {
+        let messages = vec![
+            ProviderMessage::text("system", "Be brief".to_string()),
+            ProviderMessage::text("user", "Hi".to_string()),
+            ProviderMessage::text("assistant", "  ".to_string()),
+            ProviderMessage::text("assistant", "Hello".to_string()),
+        ];
 
-            Ok(models)
-        })
+        let converted = BedrockProvider::convert_converse_messages(messages).unwrap();
+        assert_eq!(converted.len(), 2);
+        assert_eq!(converted[0].role(), &converse::ConversationRole::User);
+        assert_eq!(
+            converted[1].content(),
+            &[converse::ContentBlock::Text("Hello".to_string())]
+        );
+    }
+
+    #[test]
+    fn converse_messages_carry_tool_calls_and_images() {
+        let messages = vec![
+            ProviderMessage {
+                role: "assistant".to_string(),
+                content: vec![ContentBlock::ToolUse {
+                    id: "call-1".to_string(),
+                    name: "read".to_string(),
+                    input: json!({"file_path": "a.txt", "limit": 2}),
+                }],
+            },
+            ProviderMessage {
+                role: "user".to_string(),
+                content: vec![
+                    ContentBlock::ToolResult {
+                        tool_use_id: "call-1".to_string(),
+                        content: "no such file".to_string(),
+                        is_error: true,
+                    },
+                    ContentBlock::Image {
+                        media_type: "image/png".to_string(),
+                        data: "AQID".to_string(),
+                    },
+                ],
+            },
+        ];
+
+        let converted = BedrockProvider::convert_converse_messages(messages).unwrap();
+        let converse::ContentBlock::ToolUse(tool_use) = &converted[0].content()[0] else {
+            panic!("expected a tool use, got {:?}", converted[0].content());
+        };
+        assert_eq!(tool_use.tool_use_id(), "call-1");
+        assert_eq!(
+            tool_use.input(),
+            &json_to_document(json!({"file_path": "a.txt", "limit": 2}))
+        );
+
+        let [converse::ContentBlock::ToolResult(result), converse::ContentBlock::Image(image)] =
+            converted[1].content()
+        else {
+            panic!(
+                "expected a tool result and an image, got {:?}",
+                converted[1].content()
+            );
+        };
+        assert_eq!(result.status(), Some(&converse::ToolResultStatus::Error));
+        assert_eq!(image.format(), &converse::ImageFormat::Png);
+        assert_eq!(
+            image.source(),
+            Some(&converse::ImageSource::Bytes(Blob::new(vec![1, 2, 3])))
+        );
+    }
+
+    #[test]
+    fn anthropic_models_skip_converse_unless_forced() {
+        let provider = BedrockProvider::new(None, None);
+        assert!(!provider.uses_converse("us.anthropic.claude-sonnet-4-5-20250929-v1:0"));
+        assert!(provider.uses_converse("us.amazon.nova-pro-v1:0"));
+        let forced = BedrockProvider::new(None, Some(true));
+        assert!(forced.uses_converse("us.anthropic.claude-sonnet-4-5-20250929-v1:0"));
     }
 }
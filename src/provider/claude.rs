@@ -1,17 +1,20 @@
 //! Claude provider implementation
 
-use super::{LlmEvent, LlmProvider, ModelInfo, ProviderMessage, ToolDef};
+use super::retry::send_with_retry;
+use super::{
+    block_on, collect_system_prompt, http_client, ChatOptions, ChatStream, ContentBlock, ErrorKind,
+    EventSender, LineStream, LlmEvent, LlmProvider, ModelCache, ModelInfo, ProviderError,
+    ProviderMessage, ToolDef, Usage,
+};
 use anyhow::Result;
+use reqwest::header::HeaderMap;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::io::BufRead;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-struct Message {
+pub(super) struct Message {
     role: String,
-    content: String,
+    content: Vec<serde_json::Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,28 +24,251 @@ struct Tool {
     input_schema: serde_json::Value,
 }
 
+/// Convert a content block to the Anthropic Messages API format.
+fn to_anthropic_block(block: ContentBlock) -> Option<serde_json::Value> {
+    match block {
+        // The API rejects empty text blocks
+        ContentBlock::Text { text } if text.trim().is_empty() => None,
+        ContentBlock::Text { text } => Some(json!({"type": "text", "text": text})),
+        ContentBlock::ToolUse { id, name, input } => Some(json!({
+            "type": "tool_use",
+            "id": id,
+            "name": name,
+            "input": input,
+        })),
+        ContentBlock::ToolResult {
+            tool_use_id,
+            content,
+            is_error,
+        } => Some(json!({
+            "type": "tool_result",
+            "tool_use_id": tool_use_id,
+            "content": content,
+            "is_error": is_error,
+        })),
+        ContentBlock::Image { media_type, data } => Some(json!({
+            "type": "image",
+            "source": {
+                "type": "base64",
+                "media_type": media_type,
+                "data": data,
+            },
+        })),
+        // Unsigned thinking came from another provider and would be rejected
+        // Gemini signatures come without reasoning text; Claude's never do
+        ContentBlock::Thinking {
+            thinking,
+            signature: Some(signature),
+        } if !thinking.is_empty() => Some(json!({
+            "type": "thinking",
+            "thinking": thinking,
+            "signature": signature,
+        })),
+        ContentBlock::Thinking { .. } => None,
+    }
+}
+
+/// Tool the model is made to call when a response schema is set; its input
+/// is the structured reply
+pub(super) const STRUCTURED_OUTPUT_TOOL: &str = "structured_output";
+
+/// Apply thinking, sampling and response schema options to a Messages API body
+/// (Anthropic or Bedrock). `max_tokens` must exceed the thinking budget, so it
+/// grows to fit. Extended thinking rejects a custom temperature or top_k, so
+/// those are dropped. Newer models reject temperature and top_p together, so
+/// top_p is only sent when temperature isn't. The API has no seed.
+///
+/// There is no JSON mode: a schema becomes a tool the model is forced to call,
+/// which rules out thinking and calls to other tools for that request.
+pub(super) fn apply_options(body: &mut serde_json::Value, options: &ChatOptions) {
+    if let Some(schema) = &options.response_schema {
+        let tool = json!({
+            "name": STRUCTURED_OUTPUT_TOOL,
+            "description": "Give your reply as input to this tool.",
+            "input_schema": schema,
+        });
+        match body["tools"].as_array_mut() {
+            Some(tools) => tools.push(tool),
+            None => body["tools"] = json!([tool]),
+        }
+        body["tool_choice"] = json!({"type": "tool", "name": STRUCTURED_OUTPUT_TOOL});
+    }
+
+    let thinking_budget = options
+        .thinking_budget
+        .filter(|_| options.response_schema.is_none());
+    let thinking = thinking_budget.is_some();
+    if let Some(budget) = thinking_budget {
+        body["thinking"] = json!({"type": "enabled", "budget_tokens": budget});
+        if options.max_tokens <= budget {
+            body["max_tokens"] = json!(budget + options.max_tokens);
+        }
+    }
+
+    let sampling = &options.sampling;
+    let temperature = sampling.temperature.filter(|_| !thinking);
+    if let Some(temperature) = temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = sampling.top_p.filter(|_| temperature.is_none()) {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(top_k) = sampling.top_k.filter(|_| !thinking) {
+        body["top_k"] = json!(top_k);
+    }
+    if !sampling.stop.is_empty() {
+        body["stop_sequences"] = json!(sampling.stop);
+    }
+}
+
+/// Mark prompt cache breakpoints on a Messages API body (Anthropic or Bedrock):
+/// the system prompt, the tool definitions, and the last message before the
+/// new turn. Everything up to a breakpoint is read from the cache on the next
+/// request instead of being billed in full. Prompts below the model's minimum
+/// cacheable size are simply not cached.
+pub(super) fn apply_cache_control(body: &mut serde_json::Value) {
+    let ephemeral = json!({"type": "ephemeral"});
+
+    if let Some(system) = body["system"].as_str() {
+        body["system"] = json!([{
+            "type": "text",
+            "text": system,
+            "cache_control": ephemeral,
+        }]);
+    }
+
+    if let Some(last_tool) = body["tools"].as_array_mut().and_then(|t| t.last_mut()) {
+        last_tool["cache_control"] = ephemeral.clone();
+    }
+
+    // The final message is the new turn; the one before it is already known
+    if let Some(messages) = body["messages"].as_array_mut() {
+        let stable = messages
+            .len()
+            .checked_sub(2)
+            .and_then(|i| messages.get_mut(i));
+        // Thinking blocks can't carry a breakpoint
+        let block = stable
+            .and_then(|m| m["content"].as_array_mut())
+            .and_then(|c| {
+                c.iter_mut()
+                    .rev()
+                    .find(|b| !matches!(b["type"].as_str(), Some("thinking")))
+            });
+        if let Some(block) = block {
+            block["cache_control"] = ephemeral;
+        }
+    }
+}
+
+/// Convert provider messages to the Anthropic Messages API format.
+/// Shared with the Bedrock provider, which uses the same body for Claude models.
+pub(super) fn to_anthropic_messages(messages: Vec<ProviderMessage>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .filter(|m| m.role != "system") // Sent as the top-level `system` field instead
+        .filter_map(|m| {
+            let content: Vec<serde_json::Value> = m
+                .content
+                .into_iter()
+                .filter_map(to_anthropic_block)
+                .collect();
+            if content.is_empty() {
+                return None;
+            }
+            Some(Message {
+                role: m.role,
+                content,
+            })
+        })
+        .collect()
+}
+
 pub struct ClaudeProvider {
     api_key: String,
-    api_url: String,
+    base_url: String,
+    /// Extra headers sent with every request
+    headers: HeaderMap,
+    models: ModelCache,
 }
 
 impl ClaudeProvider {
+    #[allow(dead_code)]
     pub fn new(api_key: String) -> Self {
+        Self::with_base_url(api_key, "https://api.anthropic.com/v1".to_string())
+    }
+
+    pub fn with_base_url(api_key: String, base_url: String) -> Self {
         Self {
             api_key,
-            api_url: "https://api.anthropic.com/v1/messages".to_string(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: HeaderMap::new(),
+            models: ModelCache::default(),
         }
     }
 
-    fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<Message> {
-        messages
-            .into_iter()
-            .filter(|m| m.role != "system") // Claude doesn't support system in messages array
-            .map(|m| Message {
-                role: m.role,
-                content: m.content,
+    /// Send these headers with every request, replacing any set by default
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Query `GET /v1/models` for the models this key can use
+    fn fetch_models(&self) -> Result<Vec<ModelInfo>> {
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .get(format!("{}/models?limit=1000", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .headers(self.headers.clone())
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to list models: {}",
+                    response.status()
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+        let models = body["data"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .filter_map(|m| {
+                let id = m["id"].as_str()?.to_string();
+                Some(ModelInfo {
+                    name: m["display_name"].as_str().unwrap_or(&id).to_string(),
+                    id,
+                    provider: "claude".to_string(),
+                })
             })
-            .collect()
+            .collect();
+
+        Ok(models)
+    }
+
+    /// Offline fallback when the models API can't be reached
+    fn fallback_models() -> Vec<ModelInfo> {
+        [
+            ("claude-sonnet-4-5-20250929", "Claude Sonnet 4.5"),
+            ("claude-haiku-4-5-20251001", "Claude Haiku 4.5"),
+            ("claude-opus-4-1-20250805", "Claude Opus 4.1"),
+            ("claude-sonnet-4-20250514", "Claude Sonnet 4"),
+        ]
+        .into_iter()
+        .map(|(id, name)| ModelInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            provider: "claude".to_string(),
+        })
+        .collect()
+    }
+
+    fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<Message> {
+        to_anthropic_messages(messages)
     }
 
     fn convert_tools(tools: Option<Vec<ToolDef>>) -> Vec<Tool> {
@@ -57,135 +283,181 @@ impl ClaudeProvider {
             .collect()
     }
 
-    fn stream_chat(
+    async fn stream_chat(
         api_key: String,
         api_url: String,
-        model: String,
-        messages: Vec<Message>,
-        tools: Vec<Tool>,
-        max_tokens: u32,
-        tx: Sender<LlmEvent>,
+        headers: HeaderMap,
+        body: serde_json::Value,
+        tx: EventSender,
     ) -> Result<()> {
-        let client = reqwest::blocking::Client::new();
+        let send = || {
+            http_client()
+                .post(&api_url)
+                .header("x-api-key", &api_key)
+                .header("anthropic-version", "2023-06-01")
+                .header("content-type", "application/json")
+                .headers(headers.clone())
+                .json(&body)
+                .send()
+        };
+        let response = send_with_retry(send, &tx).await?;
 
-        let body = json!({
-            "model": model,
-            "max_tokens": max_tokens,
-            "messages": messages,
-            "tools": tools,
-            "stream": true,
-        });
-
-        let response = client
-            .post(&api_url)
-            .header("x-api-key", api_key)
-            .header("anthropic-version", "2023-06-01")
-            .header("content-type", "application/json")
-            .json(&body)
-            .send()?;
-
-        if !response.status().is_success() {
-            let error_text = response.text()?;
-            tx.send(LlmEvent::Error(format!(
-                "API request failed: {}",
-                error_text
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            tx.send(LlmEvent::Error(ProviderError::new(
+                ErrorKind::from_status(status.as_u16()),
+                format!("API request failed: {}", error_text),
             )))?;
             return Ok(());
         }
 
-        let reader = std::io::BufReader::new(response);
-
-        let mut current_tool_id = String::new();
-        let mut current_tool_name = String::new();
-        let mut current_tool_input = String::new();
-        let mut input_tokens: u32 = 0;
-        let mut output_tokens: u32 = 0;
-
-        for line in reader.lines() {
-            let line = line?;
+        let mut lines = LineStream::new(response);
+        let mut decoder = AnthropicStreamDecoder::default();
 
+        while let Some(line) = lines.next_line().await? {
             if let Some(data) = line.strip_prefix("data: ") {
                 if data == "[DONE]" {
                     break;
                 }
 
                 if let Ok(event) = serde_json::from_str::<serde_json::Value>(data) {
-                    let event_type = event["type"].as_str().unwrap_or("");
-
-                    match event_type {
-                        "message_start" => {
-                            if let Some(message) = event.get("message") {
-                                if let Some(usage) = message.get("usage") {
-                                    input_tokens =
-                                        usage["input_tokens"].as_u64().unwrap_or(0) as u32;
-                                    output_tokens =
-                                        usage["output_tokens"].as_u64().unwrap_or(0) as u32;
-                                }
-                            }
-                        }
-                        "message_delta" => {
-                            if let Some(usage) = event["usage"].as_object() {
-                                output_tokens = usage
-                                    .get("output_tokens")
-                                    .and_then(|v| v.as_u64())
-                                    .unwrap_or(output_tokens as u64)
-                                    as u32;
-                            }
+                    if decoder.handle_event(&event, &tx)? {
+                        break;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Turns Anthropic Messages streaming events into `LlmEvent`s.
+/// Shared with Bedrock, which streams the same event JSON in binary frames.
+#[derive(Default)]
+pub(super) struct AnthropicStreamDecoder {
+    tool_id: String,
+    tool_name: String,
+    tool_input: String,
+    usage: Usage,
+}
+
+impl AnthropicStreamDecoder {
+    /// Take the counts present in a `usage` object. `message_start` carries
+    /// the prompt and cache counts; `message_delta` updates the output count.
+    fn read_usage(&mut self, usage: &serde_json::Value) {
+        let count = |key: &str| usage[key].as_u64().map(|n| n as u32);
+        let u = &mut self.usage;
+        u.input_tokens = count("input_tokens").or(u.input_tokens);
+        u.output_tokens = count("output_tokens").or(u.output_tokens);
+        u.cache_read_tokens = count("cache_read_input_tokens").or(u.cache_read_tokens);
+        u.cache_creation_tokens = count("cache_creation_input_tokens").or(u.cache_creation_tokens);
+    }
+
+    /// Handle one stream event. Returns true once the message is complete.
+    pub(super) fn handle_event(
+        &mut self,
+        event: &serde_json::Value,
+        tx: &EventSender,
+    ) -> Result<bool> {
+        let event_type = event["type"].as_str().unwrap_or("");
+
+        match event_type {
+            "message_start" => {
+                if let Some(usage) = event["message"].get("usage") {
+                    self.read_usage(usage);
+                }
+            }
+            "message_delta" => {
+                if let Some(usage) = event.get("usage") {
+                    self.read_usage(usage);
+                }
+            }
+            "content_block_start" => {
+                if let Some(content_block) = event.get("content_block") {
+                    if content_block["type"] == "tool_use" {
+                        self.tool_id = content_block["id"].as_str().unwrap_or("").to_string();
+                        self.tool_name = content_block["name"].as_str().unwrap_or("").to_string();
+                        self.tool_input.clear();
+                    }
+                }
+            }
+            "content_block_delta" => {
+                if let Some(delta) = event.get("delta") {
+                    let delta_type = delta["type"].as_str().unwrap_or("");
+
+                    if delta_type == "text_delta" {
+                        if let Some(text) = delta["text"].as_str() {
+                            tx.send(LlmEvent::Text(text.to_string()))?;
                         }
-                        "content_block_start" => {
-                            if let Some(content_block) = event.get("content_block") {
-                                if content_block["type"] == "tool_use" {
-                                    current_tool_id =
-                                        content_block["id"].as_str().unwrap_or("").to_string();
-                                    current_tool_name =
-                                        content_block["name"].as_str().unwrap_or("").to_string();
-                                    current_tool_input.clear();
-                                }
-                            }
+                    } else if delta_type == "thinking_delta" {
+                        if let Some(thinking) = delta["thinking"].as_str() {
+                            tx.send(LlmEvent::Thinking(thinking.to_string()))?;
                         }
-                        "content_block_delta" => {
-                            if let Some(delta) = event.get("delta") {
-                                let delta_type = delta["type"].as_str().unwrap_or("");
-
-                                if delta_type == "text_delta" {
-                                    if let Some(text) = delta["text"].as_str() {
-                                        tx.send(LlmEvent::Text(text.to_string()))?;
-                                    }
-                                } else if delta_type == "input_json_delta" {
-                                    if let Some(partial_json) = delta["partial_json"].as_str() {
-                                        current_tool_input.push_str(partial_json);
-                                    }
-                                }
-                            }
+                    } else if delta_type == "signature_delta" {
+                        if let Some(signature) = delta["signature"].as_str() {
+                            tx.send(LlmEvent::ThinkingSignature(signature.to_string()))?;
                         }
-                        "content_block_stop" => {
-                            if !current_tool_name.is_empty() && !current_tool_input.is_empty() {
-                                if let Ok(input) = serde_json::from_str(&current_tool_input) {
-                                    tx.send(LlmEvent::ToolUse {
-                                        id: current_tool_id.clone(),
-                                        name: current_tool_name.clone(),
-                                        input,
-                                    })?;
-                                }
-                                current_tool_name.clear();
-                                current_tool_input.clear();
-                                current_tool_id.clear();
+                    } else if delta_type == "input_json_delta" {
+                        if let Some(partial_json) = delta["partial_json"].as_str() {
+                            // A structured reply is the answer, not a tool call
+                            if self.tool_name == STRUCTURED_OUTPUT_TOOL {
+                                tx.send(LlmEvent::Text(partial_json.to_string()))?;
+                            } else {
+                                self.tool_input.push_str(partial_json);
                             }
                         }
-                        "message_stop" => {
-                            tx.send(LlmEvent::Done {
-                                input_tokens: Some(input_tokens),
-                                output_tokens: Some(output_tokens),
-                            })?;
-                            break;
-                        }
-                        _ => {}
                     }
                 }
             }
+            "content_block_stop" if self.tool_name == STRUCTURED_OUTPUT_TOOL => {
+                self.tool_name.clear();
+                self.tool_id.clear();
+            }
+            "content_block_stop" if !self.tool_name.is_empty() => {
+                // Tools called without arguments stream no input deltas
+                let input = if self.tool_input.is_empty() {
+                    Ok(json!({}))
+                } else {
+                    serde_json::from_str(&self.tool_input)
+                };
+                if let Ok(input) = input {
+                    tx.send(LlmEvent::ToolUse {
+                        id: self.tool_id.clone(),
+                        name: self.tool_name.clone(),
+                        input,
+                    })?;
+                }
+                self.tool_name.clear();
+                self.tool_input.clear();
+                self.tool_id.clear();
+            }
+            "message_stop" => {
+                tx.send(LlmEvent::Done(self.usage))?;
+                return Ok(true);
+            }
+            "error" => {
+                let message = event["error"]["message"]
+                    .as_str()
+                    .unwrap_or("unknown error");
+                let kind = match event["error"]["type"].as_str() {
+                    Some("overloaded_error" | "rate_limit_error" | "api_error") => {
+                        ErrorKind::Overloaded
+                    }
+                    Some("authentication_error" | "permission_error") => ErrorKind::Auth,
+                    _ => ErrorKind::Other,
+                };
+                tx.send(LlmEvent::Error(ProviderError::new(
+                    kind,
+                    format!("API stream error: {}", message),
+                )))?;
+                return Ok(true);
+            }
+            _ => {}
         }
 
-        Ok(())
+        Ok(false)
     }
 }
 
@@ -203,49 +475,160 @@ impl LlmProvider for ClaudeProvider {
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let (tx, rx) = channel();
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
         let api_key = self.api_key.clone();
-        let api_url = self.api_url.clone();
-        let model = model.to_string();
-        let messages = Self::convert_messages(messages);
-        let tools = Self::convert_tools(tools);
-
-        thread::spawn(move || {
-            if let Err(e) =
-                Self::stream_chat(api_key, api_url, model, messages, tools, max_tokens, tx)
-            {
-                eprintln!("Claude chat error: {}", e);
-            }
+        let api_url = format!("{}/messages", self.base_url);
+        let headers = self.headers.clone();
+        let system = collect_system_prompt(&messages);
+
+        let mut body = json!({
+            "model": model,
+            "max_tokens": options.max_tokens,
+            "messages": Self::convert_messages(messages),
+            "tools": Self::convert_tools(tools),
+            "stream": true,
         });
+        if let Some(system) = system {
+            body["system"] = json!(system);
+        }
+        apply_options(&mut body, options);
+        apply_cache_control(&mut body);
 
-        Ok(rx)
+        Ok(ChatStream::spawn("Claude error", |tx| {
+            Self::stream_chat(api_key, api_url, headers, body, tx)
+        }))
     }
 
     fn list_models(&self) -> Result<Vec<ModelInfo>> {
-        // Claude doesn't have a list models API, return static list
-        Ok(vec![
-            ModelInfo {
-                id: "claude-sonnet-4-20250514".to_string(),
-                name: "Claude Sonnet 4".to_string(),
-                provider: "claude".to_string(),
-            },
-            ModelInfo {
-                id: "claude-3-5-sonnet-20241022".to_string(),
-                name: "Claude 3.5 Sonnet".to_string(),
-                provider: "claude".to_string(),
-            },
-            ModelInfo {
-                id: "claude-3-opus-20240229".to_string(),
-                name: "Claude 3 Opus".to_string(),
-                provider: "claude".to_string(),
-            },
-            ModelInfo {
-                id: "claude-3-haiku-20240307".to_string(),
-                name: "Claude 3 Haiku".to_string(),
-                provider: "claude".to_string(),
-            },
-        ])
+        self.models
+            .get_or_fetch(|| self.fetch_models(), Self::fallback_models)
+    }
+
+    fn count_tokens(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<u32>> {
+        let system = collect_system_prompt(&messages);
+        let mut body = json!({
+            "model": model,
+            "messages": Self::convert_messages(messages),
+            "tools": Self::convert_tools(tools),
+        });
+        if let Some(system) = system {
+            body["system"] = json!(system);
+        }
+
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .post(format!("{}/messages/count_tokens", self.base_url))
+                .header("x-api-key", &self.api_key)
+                .header("anthropic-version", "2023-06-01")
+                .headers(self.headers.clone())
+                .json(&body)
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to count tokens: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+        Ok(body["input_tokens"].as_u64().map(|n| n as u32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema_options() -> ChatOptions {
+        ChatOptions {
+            response_schema: Some(json!({"type": "object"})),
+            ..ChatOptions::new(1024)
+        }
+    }
+
+    #[test]
+    fn response_schema_adds_a_forced_tool() {
+        let mut body = json!({"tools": [{"name": "read"}]});
+        apply_options(&mut body, &schema_options());
+        assert_eq!(body["tools"][1]["name"], STRUCTURED_OUTPUT_TOOL);
+        assert_eq!(body["tool_choice"]["name"], STRUCTURED_OUTPUT_TOOL);
+
+        // Without tools the array is created, so tool_choice names a real tool
+        let mut body = json!({"model": "claude"});
+        apply_options(&mut body, &schema_options());
+        assert_eq!(body["tools"][0]["name"], STRUCTURED_OUTPUT_TOOL);
+        assert_eq!(body["tools"][0]["input_schema"], json!({"type": "object"}));
+    }
+
+    #[test]
+    fn cache_breakpoints_mark_the_stable_prefix() {
+        let mut body = json!({
+            "system": "Be brief",
+            "tools": [{"name": "read"}, {"name": "write"}],
+            "messages": [
+                {"role": "user", "content": [{"type": "text", "text": "Hi"}]},
+                {"role": "assistant", "content": [
+                    {"type": "text", "text": "Hello"},
+                    {"type": "thinking", "thinking": "hmm", "signature": "sig"}
+                ]},
+                {"role": "user", "content": [{"type": "text", "text": "Again"}]}
+            ]
+        });
+        apply_cache_control(&mut body);
+
+        let ephemeral = json!({"type": "ephemeral"});
+        assert_eq!(body["system"][0]["text"], "Be brief");
+        assert_eq!(body["system"][0]["cache_control"], ephemeral);
+        assert!(body["tools"][0].get("cache_control").is_none());
+        assert_eq!(body["tools"][1]["cache_control"], ephemeral);
+        // The breakpoint skips the thinking block, and the new turn has none
+        assert_eq!(
+            body["messages"][1]["content"][0]["cache_control"],
+            ephemeral
+        );
+        assert!(body["messages"][1]["content"][1]
+            .get("cache_control")
+            .is_none());
+        assert!(body["messages"][2]["content"][0]
+            .get("cache_control")
+            .is_none());
+    }
+
+    #[test]
+    fn cache_breakpoints_need_an_earlier_message() {
+        let mut body = json!({
+            "tools": [],
+            "messages": [{"role": "user", "content": [{"type": "text", "text": "Hi"}]}]
+        });
+        let before = body.clone();
+        apply_cache_control(&mut body);
+        assert_eq!(body, before);
+    }
+
+    #[test]
+    fn temperature_wins_over_top_p() {
+        let mut options = ChatOptions::new(1024);
+        options.sampling.temperature = Some(0.2);
+        options.sampling.top_p = Some(0.9);
+        let mut body = json!({});
+        apply_options(&mut body, &options);
+        assert_eq!(body["temperature"], json!(0.2));
+        assert!(body.get("top_p").is_none());
+
+        // Thinking drops temperature, so top_p goes instead
+        options.thinking_budget = Some(2048);
+        let mut body = json!({});
+        apply_options(&mut body, &options);
+        assert!(body.get("temperature").is_none());
+        assert_eq!(body["top_p"], json!(0.9));
     }
 }
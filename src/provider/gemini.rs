@@ -1,12 +1,16 @@
 //! Google Gemini provider implementation with tool support
 
-use super::{LlmEvent, LlmProvider, ModelInfo, ProviderMessage, ToolDef};
+use super::retry::send_with_retry;
+use super::{
+    block_on, collect_system_prompt, http_client, local_tool_id, ChatOptions, ChatStream,
+    ContentBlock, ErrorKind, EventSender, LineStream, LlmEvent, LlmProvider, ModelCache, ModelInfo,
+    ProviderError, ProviderMessage, ToolDef, Usage,
+};
 use anyhow::Result;
+use reqwest::header::HeaderMap;
 use serde::Serialize;
 use serde_json::json;
-use std::io::BufRead;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
+use std::collections::HashMap;
 
 #[derive(Debug, Serialize)]
 struct GeminiContent {
@@ -17,7 +21,62 @@ struct GeminiContent {
 #[derive(Debug, Serialize)]
 #[serde(untagged)]
 enum GeminiPart {
-    Text { text: String },
+    Text {
+        text: String,
+        #[serde(rename = "thoughtSignature", skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
+    },
+    FunctionCall {
+        #[serde(rename = "functionCall")]
+        function_call: GeminiFunctionCall,
+        /// Returned with the model's first function call when thinking;
+        /// Gemini 3 rejects a tool turn without it
+        #[serde(rename = "thoughtSignature", skip_serializing_if = "Option::is_none")]
+        thought_signature: Option<String>,
+    },
+    FunctionResponse {
+        #[serde(rename = "functionResponse")]
+        function_response: GeminiFunctionResponse,
+    },
+    InlineData {
+        inline_data: GeminiInlineData,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiFunctionCall {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    name: String,
+    args: serde_json::Value,
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiFunctionResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<String>,
+    name: String,
+    response: serde_json::Value,
+}
+
+/// Prefix for call ids made up locally, when Gemini doesn't send one
+const LOCAL_ID_PREFIX: &str = "gemini-tool-";
+
+/// The call id to send back, if it came from Gemini
+fn api_call_id(id: &str) -> Option<String> {
+    (!id.starts_with(LOCAL_ID_PREFIX)).then(|| id.to_string())
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiSafetySetting {
+    category: String,
+    threshold: String,
+}
+
+#[derive(Debug, Serialize)]
+struct GeminiInlineData {
+    mime_type: String,
+    data: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -32,40 +91,382 @@ struct GeminiFunctionDeclaration {
     parameters: serde_json::Value,
 }
 
+#[derive(Debug, Serialize)]
+struct GeminiSystemInstruction {
+    parts: Vec<GeminiPart>,
+}
+
 #[derive(Debug, Serialize)]
 struct GeminiRequest {
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    system_instruction: Option<GeminiSystemInstruction>,
     contents: Vec<GeminiContent>,
     #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<GeminiToolDeclaration>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     generation_config: Option<GeminiGenerationConfig>,
+    #[serde(rename = "safetySettings", skip_serializing_if = "Vec::is_empty")]
+    safety_settings: Vec<GeminiSafetySetting>,
 }
 
 #[derive(Debug, Serialize)]
 struct GeminiGenerationConfig {
     #[serde(rename = "maxOutputTokens")]
     max_output_tokens: u32,
+    #[serde(rename = "thinkingConfig", skip_serializing_if = "Option::is_none")]
+    thinking_config: Option<GeminiThinkingConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    #[serde(rename = "topK", skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(rename = "stopSequences", skip_serializing_if = "Vec::is_empty")]
+    stop_sequences: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(rename = "responseMimeType", skip_serializing_if = "Option::is_none")]
+    response_mime_type: Option<String>,
+    #[serde(rename = "responseSchema", skip_serializing_if = "Option::is_none")]
+    response_schema: Option<serde_json::Value>,
+}
+
+/// Gemini's `responseSchema` is a subset of OpenAPI's schema object and
+/// rejects JSON Schema keywords it doesn't know, so `$schema`, `$defs` and
+/// `additionalProperties` are removed and local `$ref`s are inlined.
+/// Recursive schemas can't be inlined and are rejected.
+fn gemini_response_schema(schema: &serde_json::Value) -> Result<serde_json::Value> {
+    let defs = schema
+        .get("$defs")
+        .or_else(|| schema.get("definitions"))
+        .and_then(serde_json::Value::as_object);
+    inline_schema(schema, defs, &mut Vec::new())
+}
+
+/// Convert one schema, following `$ref`s into `defs`. `seen` holds the
+/// refs being expanded, to catch recursion.
+fn inline_schema(
+    schema: &serde_json::Value,
+    defs: Option<&serde_json::Map<String, serde_json::Value>>,
+    seen: &mut Vec<String>,
+) -> Result<serde_json::Value> {
+    let serde_json::Value::Object(object) = schema else {
+        return Ok(schema.clone());
+    };
+
+    let mut converted = match object.get("$ref").and_then(serde_json::Value::as_str) {
+        Some(reference) => {
+            let name = reference
+                .strip_prefix("#/$defs/")
+                .or_else(|| reference.strip_prefix("#/definitions/"))
+                .ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "Gemini response schemas can only use local $refs, not {}",
+                        reference
+                    )
+                })?;
+            if seen.iter().any(|s| s == name) {
+                return Err(anyhow::anyhow!(
+                    "Gemini response schemas can't be recursive ({} refers to itself)",
+                    reference
+                ));
+            }
+            let target = defs.and_then(|defs| defs.get(name)).ok_or_else(|| {
+                anyhow::anyhow!("Response schema has no definition for {}", reference)
+            })?;
+            seen.push(name.to_string());
+            let inlined = inline_schema(target, defs, seen)?;
+            seen.pop();
+            match inlined {
+                serde_json::Value::Object(inlined) => inlined,
+                _ => serde_json::Map::new(),
+            }
+        }
+        None => serde_json::Map::new(),
+    };
+
+    for (key, value) in object {
+        if key.starts_with('$') || key == "definitions" || key == "additionalProperties" {
+            continue;
+        }
+        let value = match (key.as_str(), value) {
+            // Property names are data, only their schemas are converted
+            ("properties", serde_json::Value::Object(properties)) => serde_json::Value::Object(
+                properties
+                    .iter()
+                    .map(|(name, property)| {
+                        Ok((name.clone(), inline_schema(property, defs, seen)?))
+                    })
+                    .collect::<Result<_>>()?,
+            ),
+            ("items" | "not", _) => inline_schema(value, defs, seen)?,
+            ("anyOf" | "oneOf" | "allOf" | "prefixItems", serde_json::Value::Array(schemas)) => {
+                serde_json::Value::Array(
+                    schemas
+                        .iter()
+                        .map(|s| inline_schema(s, defs, seen))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            _ => value.clone(),
+        };
+        converted.insert(key.clone(), value);
+    }
+    Ok(serde_json::Value::Object(converted))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GeminiThinkingConfig {
+    include_thoughts: bool,
+    thinking_budget: u32,
 }
 
 pub struct GeminiProvider {
     api_key: String,
+    base_url: String,
+    /// Extra headers sent with every request
+    headers: HeaderMap,
+    /// Harm category -> block threshold
+    safety_settings: HashMap<String, String>,
+    models: ModelCache,
 }
 
 impl GeminiProvider {
+    #[allow(dead_code)]
     pub fn new(api_key: String) -> Self {
-        Self { api_key }
+        Self::with_base_url(
+            api_key,
+            "https://generativelanguage.googleapis.com/v1beta".to_string(),
+        )
+    }
+
+    pub fn with_base_url(api_key: String, base_url: String) -> Self {
+        Self {
+            api_key,
+            base_url: base_url.trim_end_matches('/').to_string(),
+            headers: HeaderMap::new(),
+            safety_settings: HashMap::new(),
+            models: ModelCache::default(),
+        }
+    }
+
+    /// Send these headers with every request, replacing any set by default
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Block thresholds by harm category, e.g.
+    /// `HARM_CATEGORY_HARASSMENT = "BLOCK_ONLY_HIGH"`
+    pub fn with_safety_settings(mut self, safety_settings: HashMap<String, String>) -> Self {
+        self.safety_settings = safety_settings;
+        self
+    }
+
+    fn safety_settings(&self) -> Vec<GeminiSafetySetting> {
+        let mut settings: Vec<GeminiSafetySetting> = self
+            .safety_settings
+            .iter()
+            .map(|(category, threshold)| GeminiSafetySetting {
+                category: category.clone(),
+                threshold: threshold.clone(),
+            })
+            .collect();
+        settings.sort_by(|a, b| a.category.cmp(&b.category));
+        settings
+    }
+
+    /// The request body shared by chat and token counting
+    fn build_request(
+        &self,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+        generation_config: Option<GeminiGenerationConfig>,
+    ) -> GeminiRequest {
+        let system = collect_system_prompt(&messages);
+        GeminiRequest {
+            system_instruction: system.map(|text| GeminiSystemInstruction {
+                parts: vec![GeminiPart::Text {
+                    text,
+                    thought_signature: None,
+                }],
+            }),
+            contents: Self::convert_messages(messages),
+            tools: Self::convert_tools(tools),
+            generation_config,
+            safety_settings: self.safety_settings(),
+        }
+    }
+
+    /// Query `models.list` for models that support generateContent
+    fn fetch_models(&self) -> Result<Vec<ModelInfo>> {
+        block_on(self.fetch_models_async())
+    }
+
+    async fn fetch_models_async(&self) -> Result<Vec<ModelInfo>> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut url = format!("{}/models?pageSize=1000", self.base_url);
+            if let Some(ref token) = page_token {
+                url.push_str(&format!("&pageToken={}", token));
+            }
+
+            let response = http_client()
+                .get(&url)
+                .header("x-goog-api-key", &self.api_key)
+                .headers(self.headers.clone())
+                .send()
+                .await?;
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to list models: {}",
+                    response.status()
+                ));
+            }
+
+            let body: serde_json::Value = response.json().await?;
+            for m in body["models"].as_array().unwrap_or(&vec![]) {
+                let supports_chat = m["supportedGenerationMethods"]
+                    .as_array()
+                    .is_some_and(|methods| methods.iter().any(|v| v == "generateContent"));
+                let Some(name) = m["name"].as_str() else {
+                    continue;
+                };
+                if !supports_chat {
+                    continue;
+                }
+
+                let id = name.trim_start_matches("models/").to_string();
+                models.push(ModelInfo {
+                    name: m["displayName"].as_str().unwrap_or(&id).to_string(),
+                    id,
+                    provider: "gemini".to_string(),
+                });
+            }
+
+            match body["nextPageToken"].as_str() {
+                Some(token) if !token.is_empty() => page_token = Some(token.to_string()),
+                _ => break,
+            }
+        }
+
+        Ok(models)
+    }
+
+    /// Offline fallback when the models API can't be reached
+    fn fallback_models() -> Vec<ModelInfo> {
+        [
+            ("gemini-2.5-pro", "Gemini 2.5 Pro"),
+            ("gemini-2.5-flash", "Gemini 2.5 Flash"),
+            ("gemini-2.0-flash", "Gemini 2.0 Flash"),
+        ]
+        .into_iter()
+        .map(|(id, name)| ModelInfo {
+            id: id.to_string(),
+            name: name.to_string(),
+            provider: "gemini".to_string(),
+        })
+        .collect()
     }
 
     fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<GeminiContent> {
+        // functionResponse parts are matched by function name, not call id
+        let mut tool_names: HashMap<String, String> = HashMap::new();
+
         messages
             .into_iter()
-            .filter(|m| m.role != "system")
-            .map(|m| GeminiContent {
-                role: match m.role.as_str() {
-                    "assistant" => "model".to_string(),
-                    _ => "user".to_string(),
-                },
-                parts: vec![GeminiPart::Text { text: m.content }],
+            .filter(|m| m.role != "system") // Sent as systemInstruction instead
+            .filter_map(|m| {
+                let mut signature = None;
+                let mut parts: Vec<GeminiPart> = m
+                    .content
+                    .into_iter()
+                    .filter_map(|block| match block {
+                        ContentBlock::Text { text } if text.is_empty() => None,
+                        ContentBlock::Text { text } => Some(GeminiPart::Text {
+                            text,
+                            thought_signature: None,
+                        }),
+                        ContentBlock::ToolUse { id, name, input } => {
+                            tool_names.insert(id.clone(), name.clone());
+                            Some(GeminiPart::FunctionCall {
+                                function_call: GeminiFunctionCall {
+                                    id: api_call_id(&id),
+                                    name,
+                                    args: input,
+                                },
+                                thought_signature: None,
+                            })
+                        }
+                        ContentBlock::ToolResult {
+                            tool_use_id,
+                            content,
+                            is_error,
+                        } => {
+                            let id = api_call_id(&tool_use_id);
+                            let name = tool_names.get(&tool_use_id).cloned().unwrap_or(tool_use_id);
+                            let response = if is_error {
+                                json!({ "error": content })
+                            } else {
+                                json!({ "content": content })
+                            };
+                            Some(GeminiPart::FunctionResponse {
+                                function_response: GeminiFunctionResponse { id, name, response },
+                            })
+                        }
+                        ContentBlock::Image { media_type, data } => Some(GeminiPart::InlineData {
+                            inline_data: GeminiInlineData {
+                                mime_type: media_type,
+                                data,
+                            },
+                        }),
+                        // Thought summaries are not sent back, only their signature
+                        ContentBlock::Thinking {
+                            signature: Some(s), ..
+                        } => {
+                            signature = Some(s);
+                            None
+                        }
+                        ContentBlock::Thinking { .. } => None,
+                    })
+                    .collect();
+
+                // The signature goes back on the first function call, or on
+                // the first text part if the turn made no calls
+                if signature.is_some() {
+                    let signed = parts
+                        .iter()
+                        .position(|p| matches!(p, GeminiPart::FunctionCall { .. }))
+                        .or_else(|| {
+                            parts
+                                .iter()
+                                .position(|p| matches!(p, GeminiPart::Text { .. }))
+                        });
+                    match signed.map(|i| &mut parts[i]) {
+                        Some(GeminiPart::FunctionCall {
+                            thought_signature, ..
+                        })
+                        | Some(GeminiPart::Text {
+                            thought_signature, ..
+                        }) => *thought_signature = signature,
+                        _ => {}
+                    }
+                }
+
+                if parts.is_empty() {
+                    return None;
+                }
+
+                Some(GeminiContent {
+                    role: match m.role.as_str() {
+                        "assistant" => "model".to_string(),
+                        _ => "user".to_string(),
+                    },
+                    parts,
+                })
             })
             .collect()
     }
@@ -85,88 +486,128 @@ impl GeminiProvider {
         })
     }
 
-    fn stream_chat(
+    async fn stream_chat(
+        url: String,
         api_key: String,
-        model: String,
-        messages: Vec<GeminiContent>,
-        tools: Option<Vec<GeminiToolDeclaration>>,
-        max_tokens: u32,
-        tx: Sender<LlmEvent>,
+        headers: HeaderMap,
+        request: GeminiRequest,
+        tx: EventSender,
     ) -> Result<()> {
-        let client = reqwest::blocking::Client::new();
-
-        let request = GeminiRequest {
-            contents: messages,
-            tools,
-            generation_config: Some(GeminiGenerationConfig {
-                max_output_tokens: max_tokens,
-            }),
+        let send = || {
+            http_client()
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("x-goog-api-key", &api_key)
+                .headers(headers.clone())
+                .json(&request)
+                .send()
         };
+        let response = send_with_retry(send, &tx).await?;
 
-        let url = format!(
-            "https://generativelanguage.googleapis.com/v1beta/models/{}:streamGenerateContent?key={}&alt=sse",
-            model, api_key
-        );
-
-        let response = client
-            .post(&url)
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()?;
-
-        if !response.status().is_success() {
-            let error_text = response.text()?;
-            tx.send(LlmEvent::Error(format!("API error: {}", error_text)))?;
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            tx.send(LlmEvent::Error(ProviderError::new(
+                ErrorKind::from_status(status.as_u16()),
+                format!("API error: {}", error_text),
+            )))?;
             return Ok(());
         }
 
-        let reader = std::io::BufReader::new(response);
+        let mut lines = LineStream::new(response);
+
+        let mut usage = Usage::default();
+        let mut signed = false;
+        let mut streamed = false;
 
-        let mut input_tokens: u32 = 0;
-        let mut output_tokens: u32 = 0;
+        while let Some(line) = lines.next_line().await? {
+            let Some(data) = line.strip_prefix("data: ") else {
+                continue;
+            };
+            let Ok(json_val) = serde_json::from_str::<serde_json::Value>(data) else {
+                continue;
+            };
 
-        for line in reader.lines() {
-            let line = line?;
+            // Extract usage metadata. The prompt count includes cached tokens,
+            // and thinking is billed as output but counted separately.
+            if let Some(metadata) = json_val.get("usageMetadata") {
+                let count = |field: &str| metadata[field].as_u64().unwrap_or(0) as u32;
+                let cached = count("cachedContentTokenCount");
+                usage = Usage::new(
+                    count("promptTokenCount").saturating_sub(cached),
+                    count("candidatesTokenCount") + count("thoughtsTokenCount"),
+                );
+                usage.cache_read_tokens = (cached > 0).then_some(cached);
+            }
+
+            if let Some(reason) = json_val["promptFeedback"]["blockReason"].as_str() {
+                tx.send(LlmEvent::Error(ProviderError::other(format!(
+                    "Prompt blocked by Gemini ({})",
+                    reason
+                ))))?;
+                return Ok(());
+            }
 
-            if let Some(data) = line.strip_prefix("data: ") {
-                if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(data) {
-                    // Extract usage metadata
-                    if let Some(usage) = json_val.get("usageMetadata") {
-                        input_tokens = usage["promptTokenCount"].as_u64().unwrap_or(0) as u32;
-                        output_tokens = usage["candidatesTokenCount"].as_u64().unwrap_or(0) as u32;
+            for candidate in json_val["candidates"].as_array().into_iter().flatten() {
+                for part in candidate["content"]["parts"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                {
+                    // Only the first signature of a turn is needed to replay it
+                    if let Some(signature) = part["thoughtSignature"].as_str() {
+                        if !signed {
+                            signed = true;
+                            tx.send(LlmEvent::ThinkingSignature(signature.to_string()))?;
+                        }
                     }
 
-                    if let Some(candidates) = json_val["candidates"].as_array() {
-                        for candidate in candidates {
-                            if let Some(parts) = candidate["content"]["parts"].as_array() {
-                                for part in parts {
-                                    // Text response
-                                    if let Some(text) = part["text"].as_str() {
-                                        tx.send(LlmEvent::Text(text.to_string()))?;
-                                    }
-
-                                    // Function call response
-                                    if let Some(fc) = part.get("functionCall") {
-                                        let name = fc["name"].as_str().unwrap_or("").to_string();
-                                        let args = fc.get("args").cloned().unwrap_or(json!({}));
-                                        tx.send(LlmEvent::ToolUse {
-                                            id: format!("gemini-tool-{}", name),
-                                            name,
-                                            input: args,
-                                        })?;
-                                    }
-                                }
-                            }
+                    // Text response; thought summaries are flagged with `thought`
+                    if let Some(text) = part["text"].as_str() {
+                        if part["thought"].as_bool().unwrap_or(false) {
+                            tx.send(LlmEvent::Thinking(text.to_string()))?;
+                        } else {
+                            streamed |= !text.is_empty();
+                            tx.send(LlmEvent::Text(text.to_string()))?;
                         }
                     }
+
+                    // Function call response
+                    if let Some(fc) = part.get("functionCall") {
+                        streamed = true;
+                        let name = fc["name"].as_str().unwrap_or("").to_string();
+                        let args = fc.get("args").cloned().unwrap_or(json!({}));
+                        // Older models send no call ids
+                        let id = match fc["id"].as_str() {
+                            Some(id) => id.to_string(),
+                            None => local_tool_id(&format!("{}{}-", LOCAL_ID_PREFIX, name)),
+                        };
+                        tx.send(LlmEvent::ToolUse {
+                            id,
+                            name,
+                            input: args,
+                        })?;
+                    }
+                }
+
+                // A reply cut off by the safety filters before anything was shown
+                let reason = candidate["finishReason"].as_str().unwrap_or("");
+                if !streamed
+                    && matches!(
+                        reason,
+                        "SAFETY" | "RECITATION" | "BLOCKLIST" | "PROHIBITED_CONTENT" | "SPII"
+                    )
+                {
+                    tx.send(LlmEvent::Error(ProviderError::other(format!(
+                        "Response blocked by Gemini ({})",
+                        reason
+                    ))))?;
+                    return Ok(());
                 }
             }
         }
 
-        tx.send(LlmEvent::Done {
-            input_tokens: Some(input_tokens),
-            output_tokens: Some(output_tokens),
-        })?;
+        tx.send(LlmEvent::Done(usage))?;
 
         Ok(())
     }
@@ -186,40 +627,200 @@ impl LlmProvider for GeminiProvider {
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let (tx, rx) = channel();
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
+        let url = format!(
+            "{}/models/{}:streamGenerateContent?alt=sse",
+            self.base_url, model
+        );
         let api_key = self.api_key.clone();
-        let model = model.to_string();
-        let messages = Self::convert_messages(messages);
-        let tools = Self::convert_tools(tools);
+        let headers = self.headers.clone();
 
-        thread::spawn(move || {
-            if let Err(e) = Self::stream_chat(api_key, model, messages, tools, max_tokens, tx) {
-                eprintln!("Gemini chat error: {}", e);
-            }
-        });
+        let generation_config = GeminiGenerationConfig {
+            max_output_tokens: options.max_tokens,
+            thinking_config: options.thinking_budget.map(|budget| GeminiThinkingConfig {
+                include_thoughts: true,
+                thinking_budget: budget,
+            }),
+            temperature: options.sampling.temperature,
+            top_p: options.sampling.top_p,
+            top_k: options.sampling.top_k,
+            stop_sequences: options.sampling.stop.clone(),
+            seed: options.sampling.seed,
+            response_mime_type: options
+                .response_schema
+                .as_ref()
+                .map(|_| "application/json".to_string()),
+            response_schema: options
+                .response_schema
+                .as_ref()
+                .map(gemini_response_schema)
+                .transpose()?,
+        };
+        // Function calling can't be combined with a JSON response
+        let tools = tools.filter(|_| options.response_schema.is_none());
+        let request = self.build_request(messages, tools, Some(generation_config));
 
-        Ok(rx)
+        Ok(ChatStream::spawn("Gemini error", |tx| {
+            Self::stream_chat(url, api_key, headers, request, tx)
+        }))
     }
 
     fn list_models(&self) -> Result<Vec<ModelInfo>> {
-        Ok(vec![
-            ModelInfo {
-                id: "gemini-2.5-flash".to_string(),
-                name: "Gemini 2.5 Flash".to_string(),
-                provider: "gemini".to_string(),
-            },
-            ModelInfo {
-                id: "gemini-2.0-flash".to_string(),
-                name: "Gemini 2.0 Flash".to_string(),
-                provider: "gemini".to_string(),
-            },
-            ModelInfo {
-                id: "gemini-1.5-pro".to_string(),
-                name: "Gemini 1.5 Pro".to_string(),
-                provider: "gemini".to_string(),
+        self.models
+            .get_or_fetch(|| self.fetch_models(), Self::fallback_models)
+    }
+
+    fn count_tokens(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<u32>> {
+        let mut request = serde_json::to_value(self.build_request(messages, tools, None))?;
+        request["model"] = json!(format!("models/{}", model));
+
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .post(format!("{}/models/{}:countTokens", self.base_url, model))
+                .header("x-goog-api-key", &self.api_key)
+                .headers(self.headers.clone())
+                .json(&json!({ "generateContentRequest": request }))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to count tokens: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+        Ok(body["totalTokens"].as_u64().map(|n| n as u32))
+    }
+
+    /// Sends one `embedContent` request per input, batched into a single call
+    fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let requests: Vec<serde_json::Value> = inputs
+            .into_iter()
+            .map(|text| {
+                json!({
+                    "model": format!("models/{}", model),
+                    "content": {"parts": [{"text": text}]},
+                })
+            })
+            .collect();
+
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .post(format!(
+                    "{}/models/{}:batchEmbedContents",
+                    self.base_url, model
+                ))
+                .header("x-goog-api-key", &self.api_key)
+                .headers(self.headers.clone())
+                .json(&json!({ "requests": requests }))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to embed: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+
+        body["embeddings"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected embeddings response: {}", body))?
+            .iter()
+            .map(|e| Ok(serde_json::from_value(e["values"].clone())?))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response_schema_keeps_property_names() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "type": "object",
+            "properties": {
+                "$id": {"type": "string"},
+                "additionalProperties": {
+                    "type": "object",
+                    "additionalProperties": false
+                }
             },
-        ])
+            "required": ["$id"],
+            "additionalProperties": false
+        });
+
+        assert_eq!(
+            gemini_response_schema(&schema).unwrap(),
+            json!({
+                "type": "object",
+                "properties": {
+                    "$id": {"type": "string"},
+                    "additionalProperties": {"type": "object"}
+                },
+                "required": ["$id"]
+            })
+        );
+    }
+
+    #[test]
+    fn response_schema_inlines_refs() {
+        let schema = json!({
+            "type": "array",
+            "items": {"$ref": "#/$defs/point", "description": "A point"},
+            "$defs": {
+                "point": {
+                    "type": "object",
+                    "properties": {"x": {"$ref": "#/$defs/coordinate"}}
+                },
+                "coordinate": {"type": "number"}
+            }
+        });
+
+        assert_eq!(
+            gemini_response_schema(&schema).unwrap(),
+            json!({
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {"x": {"type": "number"}},
+                    "description": "A point"
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn response_schema_rejects_refs_it_cannot_inline() {
+        let recursive = json!({
+            "$ref": "#/$defs/node",
+            "$defs": {
+                "node": {
+                    "type": "object",
+                    "properties": {"next": {"$ref": "#/$defs/node"}}
+                }
+            }
+        });
+        let error = gemini_response_schema(&recursive).unwrap_err();
+        assert!(error.to_string().contains("recursive"));
+
+        let remote = json!({"$ref": "https://example.com/schema.json"});
+        assert!(gemini_response_schema(&remote).is_err());
+        let missing = json!({"$ref": "#/$defs/nothing"});
+        assert!(gemini_response_schema(&missing).is_err());
     }
 }
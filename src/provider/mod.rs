@@ -3,6 +3,10 @@
 //! This module provides a common interface for all LLM providers (Ollama, Claude, Bedrock).
 //! Each provider implements the `LlmProvider` trait, allowing the application to work with
 //! any provider through a single, unified API.
+//!
+//! Requests run as tasks on one shared tokio runtime and use one pooled HTTP
+//! client, so connections are reused across messages and several streams can
+//! run at once. Each stream's events reach the UI through a channel.
 
 pub mod bedrock;
 pub mod claude;
@@ -10,11 +14,19 @@ pub mod gemini;
 pub mod ollama;
 pub mod openai;
 pub mod registry;
+pub mod replay;
+pub mod retry;
 
 use anyhow::Result;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::mpsc::Receiver;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 
 // Re-export provider implementations
 pub use bedrock::BedrockProvider;
@@ -23,34 +35,589 @@ pub use gemini::GeminiProvider;
 pub use ollama::OllamaProvider;
 pub use openai::OpenAIProvider;
 pub use registry::ProviderRegistry;
+pub use replay::ReplayProvider;
 
 /// Unified event type for all providers
 #[derive(Debug, Clone)]
 pub enum LlmEvent {
     /// Streaming text content
     Text(String),
+    /// Streaming reasoning output, kept apart from the answer
+    Thinking(String),
+    /// Signature for the thinking block just streamed (Anthropic only).
+    /// Must be sent back with the thinking text during tool use.
+    ThinkingSignature(String),
     /// Tool use request from the model
     ToolUse {
-        #[allow(dead_code)]
         id: String,
         name: String,
         input: serde_json::Value,
     },
     /// Response complete
-    Done {
-        #[allow(dead_code)]
-        input_tokens: Option<u32>,
-        output_tokens: Option<u32>,
-    },
+    Done(Usage),
+    /// A transient failure; the request will be sent again after `delay`
+    Retrying { attempt: u32, delay: Duration },
     /// Error occurred
-    Error(String),
+    Error(ProviderError),
+}
+
+/// Token usage reported for one response
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// Prompt tokens billed at the normal rate. With Anthropic this
+    /// excludes the cached part, counted below.
+    #[serde(default)]
+    pub input_tokens: Option<u32>,
+    #[serde(default)]
+    pub output_tokens: Option<u32>,
+    /// Prompt tokens read from the prompt cache
+    #[serde(default)]
+    pub cache_read_tokens: Option<u32>,
+    /// Prompt tokens written to the prompt cache
+    #[serde(default)]
+    pub cache_creation_tokens: Option<u32>,
+}
+
+impl Usage {
+    pub fn new(input_tokens: u32, output_tokens: u32) -> Self {
+        Self {
+            input_tokens: Some(input_tokens),
+            output_tokens: Some(output_tokens),
+            ..Default::default()
+        }
+    }
+
+    /// Full size of the prompt, cached or not
+    pub fn prompt_tokens(&self) -> Option<u32> {
+        Some(
+            self.input_tokens?
+                + self.cache_read_tokens.unwrap_or(0)
+                + self.cache_creation_tokens.unwrap_or(0),
+        )
+    }
+}
+
+/// Broad cause of a failed request, used to decide whether another
+/// provider is worth trying
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// The provider couldn't be reached or timed out
+    Connection,
+    /// Missing, invalid or unauthorised credentials
+    Auth,
+    /// Rate limited, overloaded or a server-side failure
+    Overloaded,
+    /// Anything else, e.g. a rejected request; other providers would fail too
+    Other,
+}
+
+impl ErrorKind {
+    pub fn from_status(status: u16) -> Self {
+        match status {
+            401 | 403 => ErrorKind::Auth,
+            408 => ErrorKind::Connection,
+            429 | 500..=599 => ErrorKind::Overloaded,
+            _ => ErrorKind::Other,
+        }
+    }
+
+    /// Whether the request should be re-sent to the next fallback provider
+    pub fn should_fall_back(self) -> bool {
+        self != ErrorKind::Other
+    }
+}
+
+/// A failed chat request, as reported through `LlmEvent::Error`
+#[derive(Debug, Clone)]
+pub struct ProviderError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl ProviderError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    pub fn other(message: impl Into<String>) -> Self {
+        Self::new(ErrorKind::Other, message)
+    }
+
+    /// Classify an error that ended a provider's worker thread
+    pub fn from_error(context: &str, error: &anyhow::Error) -> Self {
+        if let Some(e) = error.downcast_ref::<ProviderError>() {
+            return Self::new(e.kind, format!("{}: {}", context, e.message));
+        }
+        let kind = match error.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_connect() || e.is_timeout() => ErrorKind::Connection,
+            Some(e) => e
+                .status()
+                .map_or(ErrorKind::Other, |s| ErrorKind::from_status(s.as_u16())),
+            None => ErrorKind::Other,
+        };
+        Self::new(kind, format!("{}: {:#}", context, error))
+    }
+}
+
+impl std::fmt::Display for ProviderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+/// Shared flag used to abort an in-flight request.
+/// Setting it drops the request's task, which closes the connection.
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    flag: Arc<tokio::sync::watch::Sender<bool>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self {
+            flag: Arc::new(tokio::sync::watch::Sender::new(false)),
+        }
+    }
+}
+
+impl CancelToken {
+    pub fn cancel(&self) {
+        self.flag.send_replace(true);
+    }
+
+    /// Run `fut` unless the token is cancelled first.
+    /// Returns None when cancelled; `fut` is dropped, which aborts the request.
+    pub async fn run<T>(&self, fut: impl Future<Output = T>) -> Option<T> {
+        let mut rx = self.flag.subscribe();
+        tokio::select! {
+            out = fut => Some(out),
+            _ = rx.wait_for(|cancelled| *cancelled) => None,
+        }
+    }
+}
+
+/// Sending half of a chat stream, held by the provider's task
+pub type EventSender = UnboundedSender<LlmEvent>;
+
+/// Runtime shared by every provider for the life of the process
+pub fn runtime() -> &'static tokio::runtime::Runtime {
+    static RUNTIME: OnceLock<tokio::runtime::Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("llm-provider")
+            .enable_all()
+            .build()
+            .expect("failed to start the provider runtime")
+    })
+}
+
+/// Run a future to completion on the shared runtime, for the synchronous
+/// parts of the provider API. Must not be called from a provider task.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    runtime().block_on(future)
+}
+
+/// HTTP client shared by every provider, so connections are pooled
+pub fn http_client() -> &'static reqwest::Client {
+    static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .build()
+            .unwrap_or_default()
+    })
+}
+
+/// A streaming response: events from the provider's task plus a handle to
+/// abort it. Dropping the stream cancels the request.
+pub struct ChatStream {
+    pub events: UnboundedReceiver<LlmEvent>,
+    cancel: CancelToken,
+}
+
+impl ChatStream {
+    pub fn new(events: UnboundedReceiver<LlmEvent>, cancel: CancelToken) -> Self {
+        Self { events, cancel }
+    }
+
+    /// Run `work` on the shared runtime, streaming the events it sends.
+    /// An error it returns is sent as `LlmEvent::Error`, prefixed with
+    /// `context`. Cancelling drops `work`, which closes its connection.
+    pub fn spawn<F, Fut>(context: &'static str, work: F) -> Self
+    where
+        F: FnOnce(EventSender) -> Fut,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (tx, rx) = unbounded_channel();
+        let cancel = CancelToken::default();
+        let task = work(tx.clone());
+        let task_cancel = cancel.clone();
+        runtime().spawn(async move {
+            if let Some(Err(e)) = task_cancel.run(task).await {
+                let _ = tx.send(LlmEvent::Error(ProviderError::from_error(context, &e)));
+            }
+        });
+        Self::new(rx, cancel)
+    }
+
+    /// Wait for the next event; None once the stream has ended
+    pub async fn next(&mut self) -> Option<LlmEvent> {
+        self.events.recv().await
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Reads a streamed response body line by line (SSE and NDJSON)
+pub struct LineStream {
+    response: reqwest::Response,
+    buffer: Vec<u8>,
+}
+
+impl LineStream {
+    pub fn new(response: reqwest::Response) -> Self {
+        Self {
+            response,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// The next line without its line ending, or None at the end of the body
+    pub async fn next_line(&mut self) -> Result<Option<String>> {
+        loop {
+            if let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                let line = String::from_utf8_lossy(&line[..end]);
+                return Ok(Some(line.strip_suffix('\r').unwrap_or(&line).to_string()));
+            }
+            match self.response.chunk().await? {
+                Some(chunk) => self.buffer.extend_from_slice(&chunk),
+                None if self.buffer.is_empty() => return Ok(None),
+                None => {
+                    let line = String::from_utf8_lossy(&self.buffer).into_owned();
+                    self.buffer.clear();
+                    return Ok(Some(line));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for ChatStream {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+/// A typed piece of message content.
+/// Providers map these blocks to their native wire format.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(default)]
+        is_error: bool,
+    },
+    /// Base64-encoded image data
+    Image {
+        media_type: String,
+        data: String,
+    },
+    /// Model reasoning from an earlier assistant turn
+    Thinking {
+        thinking: String,
+        #[serde(default)]
+        signature: Option<String>,
+    },
 }
 
 /// Common message format for providers
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ProviderMessage {
     pub role: String,
-    pub content: String,
+    pub content: Vec<ContentBlock>,
+}
+
+impl ProviderMessage {
+    /// Build a message with a single text block
+    pub fn text(role: &str, text: String) -> Self {
+        Self {
+            role: role.to_string(),
+            content: vec![ContentBlock::Text { text }],
+        }
+    }
+}
+
+/// Convert configured extra headers for reqwest, rejecting invalid names or values
+pub fn header_map(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| anyhow::anyhow!("Invalid header name {:?}: {}", name, e))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| anyhow::anyhow!("Invalid value for header {}: {}", name, e))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// A tool call id for providers that don't send one. The start time and a
+/// process-wide counter keep it unique across responses and restarts.
+fn local_tool_id(prefix: &str) -> String {
+    static STARTED: OnceLock<i64> = OnceLock::new();
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    let started = STARTED.get_or_init(|| chrono::Utc::now().timestamp_millis());
+    format!(
+        "{}{}-{}",
+        prefix,
+        started,
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+/// Join the text of all system messages, for APIs that take the system
+/// prompt as a separate field rather than a message
+fn collect_system_prompt(messages: &[ProviderMessage]) -> Option<String> {
+    let parts: Vec<&str> = messages
+        .iter()
+        .filter(|m| m.role == "system")
+        .flat_map(|m| &m.content)
+        .filter_map(|block| match block {
+            ContentBlock::Text { text } if !text.trim().is_empty() => Some(text.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join("\n\n"))
+    }
+}
+
+/// Sampling settings. Unset fields are left to the provider's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stop: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+    pub const KEYS: [&'static str; 5] = ["temperature", "top_p", "top_k", "stop", "seed"];
+
+    /// Combine with `overrides`, whose set fields win
+    pub fn merged(&self, overrides: &SamplingParams) -> Self {
+        Self {
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            top_k: overrides.top_k.or(self.top_k),
+            stop: if overrides.stop.is_empty() {
+                self.stop.clone()
+            } else {
+                overrides.stop.clone()
+            },
+            seed: overrides.seed.or(self.seed),
+        }
+    }
+
+    /// Set one field by name. An empty value or "default" clears it;
+    /// `stop` takes a comma-separated list.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        let clear = value.is_empty() || value == "default";
+
+        match key {
+            "temperature" => {
+                self.temperature = if clear {
+                    None
+                } else {
+                    let t: f64 = value.parse()?;
+                    if !(0.0..=2.0).contains(&t) {
+                        anyhow::bail!("temperature must be between 0 and 2");
+                    }
+                    Some(t)
+                };
+            }
+            "top_p" => {
+                self.top_p = if clear {
+                    None
+                } else {
+                    let p: f64 = value.parse()?;
+                    if !(0.0..=1.0).contains(&p) {
+                        anyhow::bail!("top_p must be between 0 and 1");
+                    }
+                    Some(p)
+                };
+            }
+            "top_k" => self.top_k = if clear { None } else { Some(value.parse()?) },
+            "seed" => self.seed = if clear { None } else { Some(value.parse()?) },
+            "stop" => {
+                self.stop = if clear {
+                    Vec::new()
+                } else {
+                    value
+                        .split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string)
+                        .collect()
+                };
+            }
+            _ => anyhow::bail!(
+                "unknown setting '{}' (expected one of: {})",
+                key,
+                Self::KEYS.join(", ")
+            ),
+        }
+        Ok(())
+    }
+
+    /// Short "key=value" summary of the fields that are set
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(t) = self.temperature {
+            parts.push(format!("temperature={}", t));
+        }
+        if let Some(p) = self.top_p {
+            if self.temperature.is_some() {
+                parts.push(format!("top_p={} (not sent to Anthropic models)", p));
+            } else {
+                parts.push(format!("top_p={}", p));
+            }
+        }
+        if let Some(k) = self.top_k {
+            parts.push(format!("top_k={}", k));
+        }
+        if !self.stop.is_empty() {
+            parts.push(format!("stop={:?}", self.stop));
+        }
+        if let Some(seed) = self.seed {
+            parts.push(format!("seed={}", seed));
+        }
+        if parts.is_empty() {
+            "provider defaults".to_string()
+        } else {
+            parts.join(", ")
+        }
+    }
+}
+
+/// Per-request generation settings
+#[derive(Debug, Clone, Default)]
+pub struct ChatOptions {
+    pub max_tokens: u32,
+    /// Reasoning budget in tokens; None leaves thinking off
+    pub thinking_budget: Option<u32>,
+    pub sampling: SamplingParams,
+    /// JSON Schema the reply must match; None for free-form text
+    pub response_schema: Option<serde_json::Value>,
+}
+
+impl ChatOptions {
+    pub fn new(max_tokens: u32) -> Self {
+        Self {
+            max_tokens,
+            ..Default::default()
+        }
+    }
+}
+
+/// Splits `<think>...</think>` sections out of streamed text, for models
+/// that inline their reasoning in the answer (qwen3, deepseek-r1, ...)
+#[derive(Debug, Default)]
+pub struct ThinkTagSplitter {
+    in_think: bool,
+    /// Drop whitespace between a closing tag and the answer
+    trim_next: bool,
+    pending: String,
+}
+
+impl ThinkTagSplitter {
+    const OPEN: &'static str = "<think>";
+    const CLOSE: &'static str = "</think>";
+
+    /// Feed a streamed chunk, returning the Text/Thinking events it completes.
+    /// A tag split across chunks is held back until the next call.
+    pub fn push(&mut self, chunk: &str) -> Vec<LlmEvent> {
+        self.pending.push_str(chunk);
+        let mut events = Vec::new();
+
+        loop {
+            let tag = if self.in_think {
+                Self::CLOSE
+            } else {
+                Self::OPEN
+            };
+            match self.pending.find(tag) {
+                Some(pos) => {
+                    let before: String = self.pending.drain(..pos).collect();
+                    self.pending.drain(..tag.len());
+                    self.emit(before, &mut events);
+                    self.in_think = !self.in_think;
+                    self.trim_next = !self.in_think;
+                }
+                None => {
+                    // Keep a trailing partial tag for the next chunk
+                    let keep = (1..tag.len())
+                        .rev()
+                        .find(|&k| self.pending.ends_with(&tag[..k]))
+                        .unwrap_or(0);
+                    let ready: String = self.pending.drain(..self.pending.len() - keep).collect();
+                    self.emit(ready, &mut events);
+                    return events;
+                }
+            }
+        }
+    }
+
+    /// Flush anything held back at the end of the stream
+    pub fn finish(&mut self) -> Vec<LlmEvent> {
+        let rest = std::mem::take(&mut self.pending);
+        let mut events = Vec::new();
+        self.emit(rest, &mut events);
+        events
+    }
+
+    fn emit(&mut self, mut text: String, events: &mut Vec<LlmEvent>) {
+        if self.trim_next {
+            text = text.trim_start().to_string();
+            self.trim_next = text.is_empty();
+        }
+        if text.is_empty() {
+            return;
+        }
+        events.push(if self.in_think {
+            LlmEvent::Thinking(text)
+        } else {
+            LlmEvent::Text(text)
+        });
+    }
 }
 
 /// Tool definition in unified format
@@ -66,6 +633,8 @@ pub struct ToolDef {
 pub struct ToolResult {
     pub tool_use_id: String,
     pub content: String,
+    #[serde(default)]
+    pub is_error: bool,
 }
 
 /// Model information
@@ -78,6 +647,53 @@ pub struct ModelInfo {
     pub provider: String,
 }
 
+/// How long a fetched model list is reused before asking the API again
+const MODEL_CACHE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// Per-provider cache of the models an account can call
+#[derive(Default)]
+pub struct ModelCache {
+    entry: Mutex<Option<(Instant, Vec<ModelInfo>)>>,
+}
+
+impl ModelCache {
+    /// Return the cached list while it is fresh, otherwise call `fetch`.
+    /// If the provider can't be reached, fall back to the last fetched list,
+    /// then to `fallback`; other failures (bad key, rejected request) are
+    /// returned.
+    pub fn get_or_fetch(
+        &self,
+        fetch: impl FnOnce() -> Result<Vec<ModelInfo>>,
+        fallback: impl FnOnce() -> Vec<ModelInfo>,
+    ) -> Result<Vec<ModelInfo>> {
+        let mut entry = match self.entry.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+
+        if let Some((fetched_at, ref models)) = *entry {
+            if fetched_at.elapsed() < MODEL_CACHE_TTL {
+                return Ok(models.clone());
+            }
+        }
+
+        match fetch() {
+            Ok(models) => {
+                *entry = Some((Instant::now(), models.clone()));
+                Ok(models)
+            }
+            Err(e) if ProviderError::from_error("", &e).kind == ErrorKind::Connection => {
+                crate::debug_log!("DEBUG: Model listing failed: {}", e);
+                Ok(match *entry {
+                    Some((_, ref models)) => models.clone(),
+                    None => fallback(),
+                })
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
 /// Trait that all LLM providers must implement
 #[allow(dead_code)]
 pub trait LlmProvider: Send + Sync {
@@ -87,16 +703,17 @@ pub trait LlmProvider: Send + Sync {
     /// Check if the provider is available and configured
     fn is_available(&self) -> bool;
 
-    /// Start a chat, returns a receiver for streaming events
+    /// Start a chat, returns a stream of events that can be cancelled
     fn chat(
         &self,
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>>;
+        options: &ChatOptions,
+    ) -> Result<ChatStream>;
 
     /// Continue conversation after tool execution.
+    /// `messages` must end with the assistant message holding the tool_use blocks.
     /// Default: appends tool results as a user message and re-calls chat().
     fn continue_with_tools(
         &self,
@@ -104,23 +721,45 @@ pub trait LlmProvider: Send + Sync {
         mut messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
         tool_results: Vec<ToolResult>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let results_text: Vec<String> = tool_results
-            .into_iter()
-            .map(|r| format!("[Tool result for {}]:\n{}", r.tool_use_id, r.content))
-            .collect();
-
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
         messages.push(ProviderMessage {
             role: "user".to_string(),
-            content: results_text.join("\n\n"),
+            content: tool_results
+                .into_iter()
+                .map(|r| ContentBlock::ToolResult {
+                    tool_use_id: r.tool_use_id,
+                    content: r.content,
+                    is_error: r.is_error,
+                })
+                .collect(),
         });
 
-        self.chat(model, messages, tools, max_tokens)
+        self.chat(model, messages, tools, options)
     }
 
     /// List available models for this provider
     fn list_models(&self) -> Result<Vec<ModelInfo>>;
+
+    /// Count the prompt tokens a chat request would use, without sending it.
+    /// Returns None if the provider can't count ahead of time.
+    fn count_tokens(
+        &self,
+        _model: &str,
+        _messages: Vec<ProviderMessage>,
+        _tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<u32>> {
+        Ok(None)
+    }
+
+    /// Embed each input with an embedding model, returning one vector per
+    /// input in the same order
+    fn embed(&self, _model: &str, _inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        Err(anyhow::anyhow!(
+            "{} doesn't support embeddings",
+            self.name()
+        ))
+    }
 }
 
 /// Get the standard tool definitions used by all providers
@@ -248,3 +887,131 @@ pub fn get_tool_definitions() -> Vec<ToolDef> {
         },
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Events as (is_thinking, text) pairs
+    fn parts(events: Vec<LlmEvent>) -> Vec<(bool, String)> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                LlmEvent::Thinking(text) => (true, text),
+                LlmEvent::Text(text) => (false, text),
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect()
+    }
+
+    fn split(chunks: &[&str]) -> Vec<(bool, String)> {
+        let mut splitter = ThinkTagSplitter::default();
+        let mut events = Vec::new();
+        for chunk in chunks {
+            events.extend(splitter.push(chunk));
+        }
+        events.extend(splitter.finish());
+        parts(events)
+    }
+
+    #[test]
+    fn think_tags_split_reasoning_from_answer() {
+        assert_eq!(
+            split(&["<think>hmm</think>\n\nHello"]),
+            vec![(true, "hmm".to_string()), (false, "Hello".to_string())]
+        );
+        assert_eq!(
+            split(&["plain answer"]),
+            vec![(false, "plain answer".to_string())]
+        );
+    }
+
+    #[test]
+    fn think_tags_split_across_chunks() {
+        assert_eq!(
+            split(&["<thi", "nk>a", "b</th", "ink>", "\n", " Hi"]),
+            vec![
+                (true, "a".to_string()),
+                (true, "b".to_string()),
+                (false, "Hi".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn partial_tag_is_flushed_as_text() {
+        assert_eq!(
+            split(&["1 <", "2 <thi"]),
+            vec![
+                (false, "1 ".to_string()),
+                (false, "<2 ".to_string()),
+                (false, "<thi".to_string())
+            ]
+        );
+    }
+
+    fn models(ids: &[&str]) -> Vec<ModelInfo> {
+        ids.iter()
+            .map(|id| ModelInfo {
+                id: id.to_string(),
+                name: id.to_string(),
+                provider: "test".to_string(),
+            })
+            .collect()
+    }
+
+    fn ids(models: &[ModelInfo]) -> Vec<&str> {
+        models.iter().map(|m| m.id.as_str()).collect()
+    }
+
+    fn unreachable() -> Result<Vec<ModelInfo>> {
+        Err(ProviderError::new(ErrorKind::Connection, "connection refused").into())
+    }
+
+    #[test]
+    fn model_cache_falls_back_only_when_unreachable() {
+        let cache = ModelCache::default();
+        let listed = cache
+            .get_or_fetch(unreachable, || models(&["static"]))
+            .unwrap();
+        assert_eq!(ids(&listed), vec!["static"]);
+
+        let rejected = cache.get_or_fetch(
+            || Err(ProviderError::new(ErrorKind::Auth, "invalid key").into()),
+            || models(&["static"]),
+        );
+        assert!(rejected.is_err());
+    }
+
+    #[test]
+    fn model_cache_prefers_the_last_fetched_list() {
+        let cache = ModelCache::default();
+        let fetched = cache
+            .get_or_fetch(|| Ok(models(&["fetched"])), Vec::new)
+            .unwrap();
+        assert_eq!(ids(&fetched), vec!["fetched"]);
+        // Fresh, so there's no second request
+        let cached = cache
+            .get_or_fetch(|| panic!("fetched again"), Vec::new)
+            .unwrap();
+        assert_eq!(ids(&cached), vec!["fetched"]);
+
+        // Instants can't predate boot, so a just-booted machine skips the rest
+        let Some(stale) = Instant::now().checked_sub(MODEL_CACHE_TTL) else {
+            return;
+        };
+        cache.entry.lock().unwrap().as_mut().unwrap().0 = stale;
+        let listed = cache
+            .get_or_fetch(unreachable, || models(&["static"]))
+            .unwrap();
+        assert_eq!(ids(&listed), vec!["fetched"]);
+    }
+
+    #[test]
+    fn local_tool_ids_are_unique() {
+        let first = local_tool_id("test-");
+        let second = local_tool_id("test-");
+        assert!(first.starts_with("test-"));
+        assert_ne!(first, second);
+    }
+}
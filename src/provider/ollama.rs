@@ -1,12 +1,14 @@
 //! Ollama provider implementation
 
-use super::{LlmEvent, LlmProvider, ModelInfo, ProviderMessage, ToolDef};
+use super::{
+    block_on, http_client, local_tool_id, runtime, ChatOptions, ChatStream, ContentBlock,
+    ErrorKind, EventSender, LineStream, LlmEvent, LlmProvider, ModelInfo, ProviderError,
+    ProviderMessage, SamplingParams, ThinkTagSplitter, ToolDef, Usage,
+};
 use anyhow::Result;
-use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
-use std::io::{BufRead, BufReader};
 use std::process::{Child, Command, Stdio};
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 use std::time::Duration;
 
@@ -21,6 +23,14 @@ pub struct OllamaModel {
 struct ChatMessage {
     role: String,
     content: String,
+    /// Base64-encoded images for multimodal models
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    images: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    tool_calls: Vec<ToolCall>,
+    /// Name of the tool a "tool" role message answers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tool_name: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -37,12 +47,12 @@ struct OllamaFunction {
     parameters: serde_json::Value,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ToolCall {
     function: FunctionCall,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct FunctionCall {
     name: String,
     arguments: serde_json::Value,
@@ -55,12 +65,31 @@ struct ChatRequest {
     stream: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     tools: Option<Vec<OllamaTool>>,
+    /// Separate reasoning into `message.thinking` (Ollama 0.9+)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    think: Option<bool>,
+    /// Sampling settings, using Ollama's option names
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<SamplingParams>,
+    /// JSON Schema the reply is constrained to (Ollama 0.5+)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    format: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct EmbedResponse {
+    embeddings: Vec<Vec<f32>>,
 }
 
 #[derive(Debug, Deserialize)]
 struct ChatResponse {
     message: Option<MessageWithTools>,
     done: bool,
+    /// Prompt size, reported on the final response
+    #[serde(default)]
+    prompt_eval_count: Option<u32>,
+    #[serde(default)]
+    eval_count: Option<u32>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,6 +97,8 @@ struct MessageWithTools {
     #[allow(dead_code)]
     role: String,
     content: String,
+    #[serde(default)]
+    thinking: Option<String>,
     tool_calls: Option<Vec<ToolCall>>,
 }
 
@@ -93,7 +124,6 @@ struct PullResponse {
 
 pub struct OllamaProvider {
     base_url: String,
-    client: Client,
     process: Option<Child>,
 }
 
@@ -101,17 +131,16 @@ impl OllamaProvider {
     pub fn new(base_url: &str) -> Self {
         Self {
             base_url: base_url.to_string(),
-            client: Client::new(),
             process: None,
         }
     }
 
     pub fn is_running(&self) -> bool {
-        self.client
+        let request = http_client()
             .get(format!("{}/api/tags", self.base_url))
             .timeout(Duration::from_secs(2))
-            .send()
-            .is_ok()
+            .send();
+        block_on(request).is_ok()
     }
 
     pub fn start_server(&mut self) -> Result<()> {
@@ -138,24 +167,26 @@ impl OllamaProvider {
     }
 
     pub fn list_ollama_models(&self) -> Result<Vec<OllamaModel>> {
-        let response: ModelsResponse = self
-            .client
-            .get(format!("{}/api/tags", self.base_url))
-            .send()?
-            .json()?;
+        let response: ModelsResponse = block_on(async {
+            http_client()
+                .get(format!("{}/api/tags", self.base_url))
+                .send()
+                .await?
+                .json()
+                .await
+        })?;
         Ok(response.models)
     }
 
     pub fn pull_model(&self, name: &str) -> Result<Receiver<String>> {
         let (tx, rx) = channel();
-        let client = self.client.clone();
         let url = format!("{}/api/pull", self.base_url);
         let name = name.to_string();
 
-        thread::spawn(move || {
+        runtime().spawn(async move {
             let request = PullRequest { name, stream: true };
 
-            let response = match client.post(&url).json(&request).send() {
+            let response = match http_client().post(&url).json(&request).send().await {
                 Ok(r) => r,
                 Err(e) => {
                     let _ = tx.send(format!("Error: {}", e));
@@ -167,13 +198,14 @@ impl OllamaProvider {
                 let status = response.status();
                 let error_text = response
                     .text()
+                    .await
                     .unwrap_or_else(|_| "Unknown error".to_string());
                 let _ = tx.send(format!("Error {}: {}", status, error_text));
                 return;
             }
 
-            let reader = BufReader::new(response);
-            for line in reader.lines().map_while(Result::ok) {
+            let mut lines = LineStream::new(response);
+            while let Ok(Some(line)) = lines.next_line().await {
                 if let Ok(response) = serde_json::from_str::<PullResponse>(&line) {
                     let status = if let (Some(completed), Some(total)) =
                         (response.completed, response.total)
@@ -202,12 +234,13 @@ impl OllamaProvider {
             name: String,
         }
 
-        self.client
+        let request = http_client()
             .delete(format!("{}/api/delete", self.base_url))
             .json(&DeleteRequest {
                 name: name.to_string(),
             })
-            .send()?;
+            .send();
+        block_on(request)?;
         Ok(())
     }
 
@@ -217,16 +250,39 @@ impl OllamaProvider {
             models: Vec<OllamaModel>,
         }
 
-        let response: LibraryResponse = self
-            .client
-            .get("https://ollama.com/api/tags")
-            .timeout(Duration::from_secs(10))
-            .send()?
-            .json()?;
+        let response: LibraryResponse = block_on(async {
+            http_client()
+                .get("https://ollama.com/api/tags")
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await?
+                .json()
+                .await
+        })?;
 
         Ok(response.models)
     }
 
+    /// Whether `model` is in memory (a bare name means its `latest` tag)
+    fn is_loaded(&self, model: &str) -> Result<bool> {
+        let response: serde_json::Value = block_on(async {
+            http_client()
+                .get(format!("{}/api/ps", self.base_url))
+                .timeout(Duration::from_secs(2))
+                .send()
+                .await?
+                .json()
+                .await
+        })?;
+        let tagged = format!("{}:latest", model);
+        Ok(response["models"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|m| m["name"].as_str())
+            .any(|name| name == model || name == tagged))
+    }
+
     pub fn unload_model(&self, model: &str) -> Result<()> {
         #[derive(Serialize)]
         struct GenerateRequest {
@@ -234,26 +290,78 @@ impl OllamaProvider {
             keep_alive: i32,
         }
 
-        let _ = self
-            .client
+        let request = http_client()
             .post(format!("{}/api/generate", self.base_url))
             .json(&GenerateRequest {
                 model: model.to_string(),
                 keep_alive: 0,
             })
             .send();
+        let _ = block_on(request);
 
         Ok(())
     }
 
     fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<ChatMessage> {
-        messages
-            .into_iter()
-            .map(|m| ChatMessage {
+        // Tool results are matched by tool name, not call id
+        let mut tool_names: std::collections::HashMap<String, String> =
+            std::collections::HashMap::new();
+        let mut converted = Vec::new();
+
+        for m in messages {
+            let mut message = ChatMessage {
                 role: m.role,
-                content: m.content,
-            })
-            .collect()
+                content: String::new(),
+                images: Vec::new(),
+                tool_calls: Vec::new(),
+                tool_name: None,
+            };
+
+            for block in m.content {
+                match block {
+                    ContentBlock::Text { text } => {
+                        if !message.content.is_empty() {
+                            message.content.push('\n');
+                        }
+                        message.content.push_str(&text);
+                    }
+                    ContentBlock::Image { data, .. } => message.images.push(data),
+                    ContentBlock::ToolUse { id, name, input } => {
+                        tool_names.insert(id, name.clone());
+                        message.tool_calls.push(ToolCall {
+                            function: FunctionCall {
+                                name,
+                                arguments: input,
+                            },
+                        });
+                    }
+                    ContentBlock::ToolResult {
+                        tool_use_id,
+                        content,
+                        ..
+                    } => {
+                        converted.push(ChatMessage {
+                            role: "tool".to_string(),
+                            content,
+                            images: Vec::new(),
+                            tool_calls: Vec::new(),
+                            tool_name: tool_names.get(&tool_use_id).cloned(),
+                        });
+                    }
+                    // Reasoning from earlier turns is not sent back
+                    ContentBlock::Thinking { .. } => {}
+                }
+            }
+
+            if !message.content.is_empty()
+                || !message.images.is_empty()
+                || !message.tool_calls.is_empty()
+            {
+                converted.push(message);
+            }
+        }
+
+        converted
     }
 
     fn convert_tools(tools: Option<Vec<ToolDef>>) -> Option<Vec<OllamaTool>> {
@@ -271,63 +379,86 @@ impl OllamaProvider {
         })
     }
 
-    fn stream_chat(client: Client, url: String, request: ChatRequest, tx: Sender<LlmEvent>) {
-        let response = match client
+    async fn stream_chat(url: String, request: ChatRequest, tx: EventSender) -> Result<()> {
+        let response = match http_client()
             .post(&url)
             .json(&request)
             .timeout(Duration::from_secs(300))
             .send()
+            .await
         {
             Ok(r) => r,
             Err(e) => {
-                let _ = tx.send(LlmEvent::Error(format!("Request failed: {}", e)));
-                return;
+                let kind = if e.is_connect() || e.is_timeout() {
+                    ErrorKind::Connection
+                } else {
+                    ErrorKind::Other
+                };
+                let _ = tx.send(LlmEvent::Error(ProviderError::new(
+                    kind,
+                    format!("Request failed: {}", e),
+                )));
+                return Ok(());
             }
         };
 
-        let reader = BufReader::new(response);
-        let mut tool_id_counter = 0;
+        let mut lines = LineStream::new(response);
+        let mut think_tags = ThinkTagSplitter::default();
 
-        for line in reader.lines().map_while(Result::ok) {
+        while let Ok(Some(line)) = lines.next_line().await {
             match serde_json::from_str::<ChatResponse>(&line) {
                 Ok(response) => {
                     if let Some(message) = response.message {
+                        if let Some(thinking) = message.thinking.filter(|t| !t.is_empty()) {
+                            if tx.send(LlmEvent::Thinking(thinking)).is_err() {
+                                return Ok(());
+                            }
+                        }
                         if let Some(tool_calls) = message.tool_calls {
                             for tool_call in tool_calls {
-                                tool_id_counter += 1;
                                 if tx
                                     .send(LlmEvent::ToolUse {
-                                        id: format!("ollama-tool-{}", tool_id_counter),
+                                        id: local_tool_id("ollama-tool-"),
                                         name: tool_call.function.name,
                                         input: tool_call.function.arguments,
                                     })
                                     .is_err()
                                 {
-                                    return;
+                                    return Ok(());
                                 }
                             }
                         }
-                        if !message.content.is_empty()
-                            && tx.send(LlmEvent::Text(message.content)).is_err()
-                        {
-                            break;
+                        // Without `think`, reasoning models inline <think> tags
+                        for event in think_tags.push(&message.content) {
+                            if tx.send(event).is_err() {
+                                return Ok(());
+                            }
                         }
                     }
 
                     if response.done {
-                        let _ = tx.send(LlmEvent::Done {
-                            input_tokens: None,
-                            output_tokens: None,
-                        });
+                        for event in think_tags.finish() {
+                            let _ = tx.send(event);
+                        }
+                        let _ = tx.send(LlmEvent::Done(Usage {
+                            input_tokens: response.prompt_eval_count,
+                            output_tokens: response.eval_count,
+                            ..Default::default()
+                        }));
                         break;
                     }
                 }
                 Err(e) => {
-                    let _ = tx.send(LlmEvent::Error(format!("Parse error: {}", e)));
+                    let _ = tx.send(LlmEvent::Error(ProviderError::other(format!(
+                        "Parse error: {}",
+                        e
+                    ))));
                     break;
                 }
             }
         }
+
+        Ok(())
     }
 }
 
@@ -345,10 +476,8 @@ impl LlmProvider for OllamaProvider {
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        _max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let (tx, rx) = channel();
-        let client = self.client.clone();
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
         let url = format!("{}/api/chat", self.base_url);
 
         let request = ChatRequest {
@@ -356,13 +485,16 @@ impl LlmProvider for OllamaProvider {
             messages: Self::convert_messages(messages),
             stream: true,
             tools: Self::convert_tools(tools),
+            // Ollama has no thinking budget, only on/off
+            think: options.thinking_budget.map(|_| true),
+            options: (options.sampling != SamplingParams::default())
+                .then(|| options.sampling.clone()),
+            format: options.response_schema.clone(),
         };
 
-        thread::spawn(move || {
-            Self::stream_chat(client, url, request, tx);
-        });
-
-        Ok(rx)
+        Ok(ChatStream::spawn("Ollama error", |tx| {
+            Self::stream_chat(url, request, tx)
+        }))
     }
 
     fn list_models(&self) -> Result<Vec<ModelInfo>> {
@@ -376,6 +508,74 @@ impl LlmProvider for OllamaProvider {
             })
             .collect())
     }
+
+    /// Ollama has no counting endpoint; evaluating the prompt without
+    /// generating anything reports its size as `prompt_eval_count`
+    fn count_tokens(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<u32>> {
+        // Counting runs a zero-token chat, which would load the model first
+        // and hold up the caller for as long as that takes
+        if !self.is_loaded(model)? {
+            return Err(anyhow::anyhow!(
+                "Ollama can only count tokens for a loaded model; {} isn't loaded yet",
+                model
+            ));
+        }
+
+        let request = serde_json::json!({
+            "model": model,
+            "messages": Self::convert_messages(messages),
+            "tools": Self::convert_tools(tools),
+            "stream": false,
+            "options": {"num_predict": 0},
+        });
+
+        let response: ChatResponse = block_on(async {
+            let response = http_client()
+                .post(format!("{}/api/chat", self.base_url))
+                .json(&request)
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to count tokens: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+        Ok(response.prompt_eval_count)
+    }
+
+    fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let request = serde_json::json!({"model": model, "input": inputs});
+
+        let response: EmbedResponse = block_on(async {
+            let response = http_client()
+                .post(format!("{}/api/embed", self.base_url))
+                .json(&request)
+                .timeout(Duration::from_secs(300))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to embed: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+        Ok(response.embeddings)
+    }
 }
 
 impl Drop for OllamaProvider {
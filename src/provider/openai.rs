@@ -1,20 +1,44 @@
 //! OpenAI-compatible provider implementation with tool support
 //!
-//! Works with OpenAI's API and any OpenAI-compatible endpoint
-//! (hosted LLMs, OpenRouter, vLLM, llama.cpp, etc.)
-
-use super::{LlmEvent, LlmProvider, ModelInfo, ProviderMessage, ToolDef};
+//! Works with OpenAI's API, Azure OpenAI deployments and any
+//! OpenAI-compatible endpoint (hosted LLMs, OpenRouter, vLLM, llama.cpp, etc.)
+
+use super::retry::send_with_retry;
+use super::{
+    block_on, http_client, ChatOptions, ChatStream, ContentBlock, ErrorKind, EventSender,
+    LineStream, LlmEvent, LlmProvider, ModelInfo, ProviderError, ProviderMessage, ThinkTagSplitter,
+    ToolDef, Usage,
+};
 use anyhow::Result;
+use reqwest::header::HeaderMap;
 use serde::Serialize;
 use serde_json::json;
-use std::io::BufRead;
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::thread;
 
 #[derive(Debug, Serialize)]
 struct OpenAIMessage {
     role: String,
-    content: String,
+    /// Plain string, or an array of parts when images are attached
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<OpenAIToolCall>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAIToolCall {
+    id: String,
+    #[serde(rename = "type")]
+    call_type: String,
+    function: OpenAIFunctionCall,
+}
+
+#[derive(Debug, Serialize)]
+struct OpenAIFunctionCall {
+    name: String,
+    /// JSON-encoded arguments, as the API expects a string here
+    arguments: String,
 }
 
 #[derive(Debug, Serialize)]
@@ -41,12 +65,38 @@ struct OpenAIRequest {
     tools: Option<Vec<OpenAITool>>,
     #[serde(skip_serializing_if = "Option::is_none")]
     stream_options: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f64>,
+    /// Not part of OpenAI's API, but accepted by vLLM, llama.cpp and others
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_k: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    stop: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<serde_json::Value>,
+}
+
+/// An Azure OpenAI deployment, which has its own URL scheme and auth header
+struct AzureDeployment {
+    deployment: String,
+    api_version: String,
+    /// Model served by the deployment, listed on the Models screen
+    model: String,
 }
 
 pub struct OpenAIProvider {
     api_key: String,
     base_url: String,
     provider_name: String,
+    /// Extra headers sent with every request (e.g. OpenRouter's HTTP-Referer)
+    headers: HeaderMap,
+    azure: Option<AzureDeployment>,
+    /// Whether the server accepts `top_k`; OpenAI and Azure reject it
+    send_top_k: bool,
 }
 
 impl OpenAIProvider {
@@ -56,25 +106,158 @@ impl OpenAIProvider {
             api_key,
             base_url: "https://api.openai.com/v1".to_string(),
             provider_name: "openai".to_string(),
+            headers: HeaderMap::new(),
+            azure: None,
+            send_top_k: false,
         }
     }
 
     pub fn with_base_url(api_key: String, base_url: String, provider_name: String) -> Self {
         Self {
             api_key,
-            base_url,
+            base_url: base_url.trim_end_matches('/').to_string(),
             provider_name,
+            headers: HeaderMap::new(),
+            azure: None,
+            send_top_k: false,
+        }
+    }
+
+    /// Azure OpenAI: requests go to `{endpoint}/openai/deployments/{deployment}`
+    /// with the key in the `api-key` header
+    pub fn azure(
+        api_key: String,
+        endpoint: String,
+        deployment: String,
+        api_version: String,
+        model: String,
+        provider_name: String,
+    ) -> Self {
+        Self {
+            azure: Some(AzureDeployment {
+                deployment,
+                api_version,
+                model,
+            }),
+            ..Self::with_base_url(api_key, endpoint, provider_name)
+        }
+    }
+
+    /// Send these headers with every request, replacing any set by default
+    pub fn with_headers(mut self, headers: HeaderMap) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Pass the `top_k` sampling setting on, for servers that take it
+    pub fn with_top_k(mut self) -> Self {
+        self.send_top_k = true;
+        self
+    }
+
+    fn chat_url(&self) -> String {
+        match &self.azure {
+            Some(azure) => format!(
+                "{}/openai/deployments/{}/chat/completions?api-version={}",
+                self.base_url, azure.deployment, azure.api_version
+            ),
+            None => format!("{}/chat/completions", self.base_url),
+        }
+    }
+
+    /// On Azure, embedding models have their own deployment, named by `model`
+    fn embeddings_url(&self, model: &str) -> String {
+        match &self.azure {
+            Some(azure) => format!(
+                "{}/openai/deployments/{}/embeddings?api-version={}",
+                self.base_url, model, azure.api_version
+            ),
+            None => format!("{}/embeddings", self.base_url),
+        }
+    }
+
+    /// Header carrying the key: `api-key` on Azure, a bearer token elsewhere
+    fn auth_header(&self) -> (&'static str, String) {
+        match self.azure {
+            Some(_) => ("api-key", self.api_key.clone()),
+            None => ("Authorization", format!("Bearer {}", self.api_key)),
         }
     }
 
     fn convert_messages(messages: Vec<ProviderMessage>) -> Vec<OpenAIMessage> {
-        messages
-            .into_iter()
-            .map(|m| OpenAIMessage {
+        let mut converted = Vec::new();
+
+        for m in messages {
+            let mut parts = Vec::new();
+            let mut has_image = false;
+            let mut tool_calls = Vec::new();
+
+            for block in m.content {
+                match block {
+                    ContentBlock::Text { text } => {
+                        parts.push(json!({"type": "text", "text": text}));
+                    }
+                    ContentBlock::Image { media_type, data } => {
+                        has_image = true;
+                        parts.push(json!({
+                            "type": "image_url",
+                            "image_url": {"url": format!("data:{};base64,{}", media_type, data)},
+                        }));
+                    }
+                    ContentBlock::ToolUse { id, name, input } => {
+                        tool_calls.push(OpenAIToolCall {
+                            id,
+                            call_type: "function".to_string(),
+                            function: OpenAIFunctionCall {
+                                name,
+                                arguments: input.to_string(),
+                            },
+                        });
+                    }
+                    // Each tool result is its own "tool" role message
+                    ContentBlock::ToolResult {
+                        tool_use_id,
+                        content,
+                        ..
+                    } => {
+                        converted.push(OpenAIMessage {
+                            role: "tool".to_string(),
+                            content: Some(json!(content)),
+                            tool_calls: None,
+                            tool_call_id: Some(tool_use_id),
+                        });
+                    }
+                    // Reasoning from earlier turns is not sent back
+                    ContentBlock::Thinking { .. } => {}
+                }
+            }
+
+            if parts.is_empty() && tool_calls.is_empty() {
+                continue;
+            }
+
+            let content = if parts.is_empty() {
+                None
+            } else if has_image {
+                Some(serde_json::Value::Array(parts))
+            } else {
+                let text: Vec<&str> = parts.iter().filter_map(|p| p["text"].as_str()).collect();
+                Some(json!(text.join("\n")))
+            };
+
+            converted.push(OpenAIMessage {
                 role: m.role,
-                content: m.content,
-            })
-            .collect()
+                content,
+                tool_calls: if tool_calls.is_empty() {
+                    None
+                } else {
+                    Some(tool_calls)
+                },
+                tool_call_id: None,
+            });
+        }
+
+        converted
     }
 
     fn convert_tools(tools: Option<Vec<ToolDef>>) -> Option<Vec<OpenAITool>> {
@@ -92,71 +275,82 @@ impl OpenAIProvider {
         })
     }
 
-    fn stream_chat(
-        api_key: String,
-        base_url: String,
-        model: String,
-        messages: Vec<OpenAIMessage>,
-        tools: Option<Vec<OpenAITool>>,
-        max_tokens: u32,
-        tx: Sender<LlmEvent>,
+    async fn stream_chat(
+        url: String,
+        auth: (&'static str, String),
+        headers: HeaderMap,
+        request: OpenAIRequest,
+        tx: EventSender,
     ) -> Result<()> {
-        let client = reqwest::blocking::Client::new();
-
-        let request = OpenAIRequest {
-            model,
-            messages,
-            max_tokens,
-            stream: true,
-            tools,
-            stream_options: Some(json!({"include_usage": true})),
+        let send = || {
+            http_client()
+                .post(&url)
+                .header(auth.0, &auth.1)
+                .header("Content-Type", "application/json")
+                .headers(headers.clone())
+                .json(&request)
+                .send()
         };
-
-        let url = format!("{}/chat/completions", base_url);
-
-        let response = client
-            .post(&url)
-            .header("Authorization", format!("Bearer {}", api_key))
-            .header("Content-Type", "application/json")
-            .json(&request)
-            .send()?;
-
-        if !response.status().is_success() {
-            let error_text = response.text()?;
-            tx.send(LlmEvent::Error(format!("API error: {}", error_text)))?;
+        let response = send_with_retry(send, &tx).await?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            tx.send(LlmEvent::Error(ProviderError::new(
+                ErrorKind::from_status(status.as_u16()),
+                format!("API error: {}", error_text),
+            )))?;
             return Ok(());
         }
 
-        let reader = std::io::BufReader::new(response);
+        let mut lines = LineStream::new(response);
 
         let mut current_tool_id = String::new();
         let mut current_tool_name = String::new();
         let mut current_tool_args = String::new();
-        let mut input_tokens: u32 = 0;
-        let mut output_tokens: u32 = 0;
-
-        for line in reader.lines() {
-            let line = line?;
+        // Stays empty for servers that don't report usage
+        let mut usage = Usage::default();
+        let mut think_tags = ThinkTagSplitter::default();
 
+        while let Some(line) = lines.next_line().await? {
             if let Some(data) = line.strip_prefix("data: ") {
                 if data == "[DONE]" {
                     break;
                 }
 
                 if let Ok(json_val) = serde_json::from_str::<serde_json::Value>(data) {
-                    // Extract usage from the final chunk
-                    if let Some(usage) = json_val.get("usage") {
-                        input_tokens = usage["prompt_tokens"].as_u64().unwrap_or(0) as u32;
-                        output_tokens = usage["completion_tokens"].as_u64().unwrap_or(0) as u32;
+                    // Extract usage from the final chunk; earlier chunks carry null
+                    if let Some(reported) = json_val.get("usage") {
+                        let count = |field: &str| reported[field].as_u64().map(|n| n as u32);
+                        if let Some(prompt) = count("prompt_tokens") {
+                            // Cached prompt tokens are included in prompt_tokens
+                            let cached = reported["prompt_tokens_details"]["cached_tokens"]
+                                .as_u64()
+                                .unwrap_or(0) as u32;
+                            usage.input_tokens = Some(prompt.saturating_sub(cached));
+                            usage.cache_read_tokens = (cached > 0).then_some(cached);
+                        }
+                        usage.output_tokens = count("completion_tokens").or(usage.output_tokens);
                     }
 
                     if let Some(choices) = json_val["choices"].as_array() {
                         for choice in choices {
                             let delta = &choice["delta"];
 
-                            // Stream text content
+                            // Reasoning field: `reasoning_content` (DeepSeek, vLLM)
+                            // or `reasoning` (OpenRouter)
+                            let reasoning = delta["reasoning_content"]
+                                .as_str()
+                                .or_else(|| delta["reasoning"].as_str());
+                            if let Some(reasoning) = reasoning.filter(|r| !r.is_empty()) {
+                                tx.send(LlmEvent::Thinking(reasoning.to_string()))?;
+                            }
+
+                            // Stream text content, splitting out inline <think> tags
                             if let Some(content) = delta["content"].as_str() {
-                                tx.send(LlmEvent::Text(content.to_string()))?;
+                                for event in think_tags.push(content) {
+                                    tx.send(event)?;
+                                }
                             }
 
                             // Stream tool calls (accumulated across chunks)
@@ -209,10 +403,10 @@ impl OpenAIProvider {
             }
         }
 
-        tx.send(LlmEvent::Done {
-            input_tokens: Some(input_tokens),
-            output_tokens: Some(output_tokens),
-        })?;
+        for event in think_tags.finish() {
+            tx.send(event)?;
+        }
+        tx.send(LlmEvent::Done(usage))?;
 
         Ok(())
     }
@@ -232,42 +426,68 @@ impl LlmProvider for OpenAIProvider {
         model: &str,
         messages: Vec<ProviderMessage>,
         tools: Option<Vec<ToolDef>>,
-        max_tokens: u32,
-    ) -> Result<Receiver<LlmEvent>> {
-        let (tx, rx) = channel();
-        let api_key = self.api_key.clone();
-        let base_url = self.base_url.clone();
-        let model = model.to_string();
-        let messages = Self::convert_messages(messages);
-        let tools = Self::convert_tools(tools);
-
-        thread::spawn(move || {
-            if let Err(e) =
-                Self::stream_chat(api_key, base_url, model, messages, tools, max_tokens, tx)
-            {
-                eprintln!("OpenAI chat error: {}", e);
-            }
-        });
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
+        let url = self.chat_url();
+        let auth = self.auth_header();
+        let headers = self.headers.clone();
 
-        Ok(rx)
+        let request = OpenAIRequest {
+            model: model.to_string(),
+            messages: Self::convert_messages(messages),
+            max_tokens: options.max_tokens,
+            stream: true,
+            tools: Self::convert_tools(tools),
+            stream_options: Some(json!({"include_usage": true})),
+            temperature: options.sampling.temperature,
+            top_p: options.sampling.top_p,
+            top_k: options.sampling.top_k.filter(|_| self.send_top_k),
+            stop: options.sampling.stop.clone(),
+            seed: options.sampling.seed,
+            // Not strict: strict mode only accepts schemas where every
+            // property is required, and replies are validated anyway
+            response_format: options.response_schema.as_ref().map(|schema| {
+                json!({
+                    "type": "json_schema",
+                    "json_schema": {"name": "response", "schema": schema},
+                })
+            }),
+        };
+
+        Ok(ChatStream::spawn("OpenAI error", |tx| {
+            Self::stream_chat(url, auth, headers, request, tx)
+        }))
     }
 
     fn list_models(&self) -> Result<Vec<ModelInfo>> {
-        let url = format!("{}/models", self.base_url);
-        let client = reqwest::blocking::Client::new();
-        let response = client
-            .get(&url)
-            .header("Authorization", format!("Bearer {}", self.api_key))
-            .send()?;
-
-        if !response.status().is_success() {
-            return Err(anyhow::anyhow!(
-                "Failed to list models: {}",
-                response.status()
-            ));
+        // A deployment serves a single model
+        if let Some(azure) = &self.azure {
+            return Ok(vec![ModelInfo {
+                id: azure.model.clone(),
+                name: format!("{} ({})", azure.model, azure.deployment),
+                provider: self.provider_name.clone(),
+            }]);
         }
 
-        let body: serde_json::Value = response.json()?;
+        let url = format!("{}/models", self.base_url);
+        let (auth_name, auth_value) = self.auth_header();
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .get(&url)
+                .header(auth_name, auth_value)
+                .headers(self.headers.clone())
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to list models: {}",
+                    response.status()
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
         let mut models: Vec<ModelInfo> = body["data"]
             .as_array()
             .unwrap_or(&vec![])
@@ -285,4 +505,96 @@ impl LlmProvider for OpenAIProvider {
         models.sort_by(|a, b| a.id.cmp(&b.id));
         Ok(models)
     }
+
+    fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let (auth_name, auth_value) = self.auth_header();
+        let body: serde_json::Value = block_on(async {
+            let response = http_client()
+                .post(self.embeddings_url(model))
+                .header(auth_name, auth_value)
+                .headers(self.headers.clone())
+                .json(&json!({"model": model, "input": inputs}))
+                .send()
+                .await?;
+
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Failed to embed: {}",
+                    response.text().await?
+                ));
+            }
+
+            Ok(response.json().await?)
+        })?;
+
+        // Each embedding carries the index of its input
+        let mut data: Vec<(u64, Vec<f32>)> = body["data"]
+            .as_array()
+            .ok_or_else(|| anyhow::anyhow!("Unexpected embeddings response: {}", body))?
+            .iter()
+            .map(|item| {
+                let embedding = serde_json::from_value(item["embedding"].clone())?;
+                Ok((item["index"].as_u64().unwrap_or(0), embedding))
+            })
+            .collect::<Result<_>>()?;
+        data.sort_by_key(|(index, _)| *index);
+        Ok(data.into_iter().map(|(_, embedding)| embedding).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn azure() -> OpenAIProvider {
+        OpenAIProvider::azure(
+            "secret".to_string(),
+            "https://my-resource.openai.azure.com/".to_string(),
+            "chat-prod".to_string(),
+            "2024-10-21".to_string(),
+            "gpt-4o".to_string(),
+            "azure".to_string(),
+        )
+    }
+
+    #[test]
+    fn azure_requests_go_to_the_deployment() {
+        let provider = azure();
+        assert_eq!(
+            provider.chat_url(),
+            "https://my-resource.openai.azure.com/openai/deployments/chat-prod/chat/completions?api-version=2024-10-21"
+        );
+        // Embedding models are deployed separately, under their own name
+        assert_eq!(
+            provider.embeddings_url("embed-prod"),
+            "https://my-resource.openai.azure.com/openai/deployments/embed-prod/embeddings?api-version=2024-10-21"
+        );
+        assert_eq!(provider.auth_header(), ("api-key", "secret".to_string()));
+
+        let models = provider.list_models().unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].id, "gpt-4o");
+        assert_eq!(models[0].name, "gpt-4o (chat-prod)");
+    }
+
+    #[test]
+    fn other_servers_use_plain_paths_and_bearer_auth() {
+        let provider = OpenAIProvider::with_base_url(
+            "secret".to_string(),
+            "http://localhost:8000/v1/".to_string(),
+            "vllm".to_string(),
+        );
+        assert_eq!(
+            provider.chat_url(),
+            "http://localhost:8000/v1/chat/completions"
+        );
+        assert_eq!(
+            provider.embeddings_url("bge"),
+            "http://localhost:8000/v1/embeddings"
+        );
+        assert_eq!(
+            provider.auth_header(),
+            ("Authorization", "Bearer secret".to_string())
+        );
+    }
 }
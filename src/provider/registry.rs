@@ -1,14 +1,16 @@
 //! Provider registry for dynamic provider management
 
 use super::{
-    BedrockProvider, ClaudeProvider, GeminiProvider, LlmProvider, OllamaProvider, OpenAIProvider,
+    header_map, BedrockProvider, ClaudeProvider, GeminiProvider, LlmProvider, OllamaProvider,
+    OpenAIProvider, ReplayProvider,
 };
 use crate::config::ProviderConfig;
 use std::collections::HashMap;
+use std::sync::Arc;
 
 /// Provider registry that holds all available providers
 pub struct ProviderRegistry {
-    providers: HashMap<String, Box<dyn LlmProvider>>,
+    providers: HashMap<String, Arc<dyn LlmProvider>>,
 }
 
 impl ProviderRegistry {
@@ -22,7 +24,7 @@ impl ProviderRegistry {
 
     /// Register a provider
     pub fn register(&mut self, name: String, provider: Box<dyn LlmProvider>) {
-        self.providers.insert(name, provider);
+        self.providers.insert(name, provider.into());
     }
 
     /// Get a provider by name
@@ -30,6 +32,11 @@ impl ProviderRegistry {
         self.providers.get(name).map(|p| &**p)
     }
 
+    /// Get a handle to a provider that can be moved to another thread
+    pub fn get_shared(&self, name: &str) -> Option<Arc<dyn LlmProvider>> {
+        self.providers.get(name).cloned()
+    }
+
     /// Check if a provider is available
     pub fn is_available(&self, name: &str) -> bool {
         self.providers.contains_key(name)
@@ -50,7 +57,7 @@ impl ProviderRegistry {
         let mut registry = Self::new();
 
         for (name, provider_config) in &config.providers {
-            if let Some(provider) = Self::build_provider(name, provider_config) {
+            if let Some(provider) = Self::build_provider(name, provider_config, &config.providers) {
                 registry.register(name.clone(), provider);
             }
         }
@@ -58,44 +65,120 @@ impl ProviderRegistry {
         registry
     }
 
-    fn build_provider(name: &str, config: &ProviderConfig) -> Option<Box<dyn LlmProvider>> {
+    fn build_provider(
+        name: &str,
+        config: &ProviderConfig,
+        providers: &HashMap<String, ProviderConfig>,
+    ) -> Option<Box<dyn LlmProvider>> {
+        let headers = match config.headers().map(header_map).transpose() {
+            Ok(headers) => headers.unwrap_or_default(),
+            Err(e) => {
+                crate::debug_log!("DEBUG: Provider {} not loaded: {}", name, e);
+                return None;
+            }
+        };
+
         match config {
-            ProviderConfig::Anthropic { .. } => {
+            ProviderConfig::Anthropic { base_url, .. } => {
                 let api_key = config.resolve_api_key()?;
-                Some(Box::new(ClaudeProvider::new(api_key)))
+                let base_url = base_url
+                    .clone()
+                    .unwrap_or_else(|| "https://api.anthropic.com/v1".to_string());
+                Some(Box::new(
+                    ClaudeProvider::with_base_url(api_key, base_url).with_headers(headers),
+                ))
             }
             ProviderConfig::Openai { base_url, .. } => {
                 let api_key = config.resolve_api_key()?;
-                if let Some(url) = base_url {
-                    Some(Box::new(OpenAIProvider::with_base_url(
-                        api_key,
-                        url.clone(),
-                        name.to_string(),
-                    )))
-                } else {
-                    Some(Box::new(OpenAIProvider::with_base_url(
-                        api_key,
-                        "https://api.openai.com/v1".to_string(),
-                        name.to_string(),
-                    )))
-                }
+                let base_url = base_url
+                    .clone()
+                    .unwrap_or_else(|| "https://api.openai.com/v1".to_string());
+                Some(Box::new(
+                    OpenAIProvider::with_base_url(api_key, base_url, name.to_string())
+                        .with_headers(headers),
+                ))
             }
             ProviderConfig::OpenaiCompatible { base_url, .. } => {
                 let api_key = config.resolve_api_key()?;
-                Some(Box::new(OpenAIProvider::with_base_url(
-                    api_key,
-                    base_url.clone(),
-                    name.to_string(),
-                )))
+                Some(Box::new(
+                    OpenAIProvider::with_base_url(api_key, base_url.clone(), name.to_string())
+                        .with_headers(headers)
+                        .with_top_k(),
+                ))
             }
-            ProviderConfig::Gemini { .. } => {
+            ProviderConfig::AzureOpenai {
+                common,
+                endpoint,
+                deployment,
+                api_version,
+                ..
+            } => {
                 let api_key = config.resolve_api_key()?;
-                Some(Box::new(GeminiProvider::new(api_key)))
+                Some(Box::new(
+                    OpenAIProvider::azure(
+                        api_key,
+                        endpoint.clone(),
+                        deployment.clone(),
+                        api_version.clone(),
+                        common.model.clone(),
+                        name.to_string(),
+                    )
+                    .with_headers(headers),
+                ))
             }
-            ProviderConfig::Bedrock { .. } => Some(Box::new(BedrockProvider::new())),
+            ProviderConfig::Gemini {
+                base_url,
+                safety_settings,
+                ..
+            } => {
+                let api_key = config.resolve_api_key()?;
+                let base_url = base_url.clone().unwrap_or_else(|| {
+                    "https://generativelanguage.googleapis.com/v1beta".to_string()
+                });
+                Some(Box::new(
+                    GeminiProvider::with_base_url(api_key, base_url)
+                        .with_headers(headers)
+                        .with_safety_settings(safety_settings.clone()),
+                ))
+            }
+            ProviderConfig::Bedrock {
+                endpoint_url,
+                use_converse,
+                ..
+            } => Some(Box::new(BedrockProvider::new(
+                endpoint_url.clone(),
+                *use_converse,
+            ))),
             ProviderConfig::Ollama { base_url, .. } => {
                 Some(Box::new(OllamaProvider::new(base_url)))
             }
+            ProviderConfig::Replay {
+                common,
+                cassette,
+                record,
+            } => {
+                let replay =
+                    ReplayProvider::new(name.to_string(), common.model.clone(), cassette.clone());
+                let Some(recorded) = record else {
+                    return Some(Box::new(replay));
+                };
+                // Record from a real provider only; replays can't record each other
+                let inner = match providers.get(recorded) {
+                    Some(ProviderConfig::Replay { .. }) | None => None,
+                    Some(inner) => Self::build_provider(recorded, inner, providers),
+                };
+                match inner {
+                    Some(inner) => Some(Box::new(replay.recording(inner))),
+                    None => {
+                        crate::debug_log!(
+                            "DEBUG: Provider {} not loaded: can't record from {}",
+                            name,
+                            recorded
+                        );
+                        None
+                    }
+                }
+            }
         }
     }
 }
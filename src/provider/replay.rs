@@ -0,0 +1,456 @@
+//! Replay provider: answers from a cassette file instead of a model
+//!
+//! A cassette is a JSON file holding one turn per chat request, each a list
+//! of events (text, thinking, tool uses, usage, errors). Replay serves the
+//! turns in order, so a conversation or tool loop plays out the same way
+//! every time without a network. In record mode requests go to a real
+//! provider and its streams are appended to the cassette as they finish.
+
+use super::{
+    ChatOptions, ChatStream, ErrorKind, LlmEvent, LlmProvider, ModelInfo, ProviderError,
+    ProviderMessage, ToolDef, ToolResult, Usage,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// One stream event as stored in a cassette
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CassetteEvent {
+    Text {
+        text: String,
+    },
+    Thinking {
+        text: String,
+    },
+    ThinkingSignature {
+        signature: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    Done {
+        #[serde(default)]
+        usage: Usage,
+    },
+    /// Replayed without waiting
+    Retrying {
+        attempt: u32,
+        delay_ms: u64,
+    },
+    Error {
+        #[serde(default = "default_error_kind")]
+        kind: ErrorKind,
+        message: String,
+    },
+}
+
+fn default_error_kind() -> ErrorKind {
+    ErrorKind::Other
+}
+
+impl From<&LlmEvent> for CassetteEvent {
+    fn from(event: &LlmEvent) -> Self {
+        match event.clone() {
+            LlmEvent::Text(text) => Self::Text { text },
+            LlmEvent::Thinking(text) => Self::Thinking { text },
+            LlmEvent::ThinkingSignature(signature) => Self::ThinkingSignature { signature },
+            LlmEvent::ToolUse { id, name, input } => Self::ToolUse { id, name, input },
+            LlmEvent::Done(usage) => Self::Done { usage },
+            LlmEvent::Retrying { attempt, delay } => Self::Retrying {
+                attempt,
+                delay_ms: delay.as_millis() as u64,
+            },
+            LlmEvent::Error(e) => Self::Error {
+                kind: e.kind,
+                message: e.message,
+            },
+        }
+    }
+}
+
+impl From<CassetteEvent> for LlmEvent {
+    fn from(event: CassetteEvent) -> Self {
+        match event {
+            CassetteEvent::Text { text } => Self::Text(text),
+            CassetteEvent::Thinking { text } => Self::Thinking(text),
+            CassetteEvent::ThinkingSignature { signature } => Self::ThinkingSignature(signature),
+            CassetteEvent::ToolUse { id, name, input } => Self::ToolUse { id, name, input },
+            CassetteEvent::Done { usage } => Self::Done(usage),
+            CassetteEvent::Retrying { attempt, delay_ms } => Self::Retrying {
+                attempt,
+                delay: Duration::from_millis(delay_ms),
+            },
+            CassetteEvent::Error { kind, message } => {
+                Self::Error(ProviderError::new(kind, message))
+            }
+        }
+    }
+}
+
+/// The events streamed in answer to one chat request
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CassetteTurn {
+    /// Model the request was sent to, for reference
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub model: String,
+    pub events: Vec<CassetteEvent>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cassette {
+    pub turns: Vec<CassetteTurn>,
+}
+
+impl Cassette {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read cassette {}", path.display()))?;
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid cassette {}", path.display()))
+    }
+
+    /// Append a turn, creating the file if needed
+    fn append(path: &Path, turn: CassetteTurn) -> Result<()> {
+        let mut cassette = if path.exists() {
+            Self::load(path)?
+        } else {
+            Self::default()
+        };
+        cassette.turns.push(turn);
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(&cassette)?)?;
+        Ok(())
+    }
+}
+
+pub struct ReplayProvider {
+    provider_name: String,
+    model: String,
+    cassette: PathBuf,
+    /// Index of the next turn to replay
+    next_turn: Mutex<usize>,
+    /// Provider whose streams are recorded, in record mode
+    recording: Option<Box<dyn LlmProvider>>,
+}
+
+impl ReplayProvider {
+    pub fn new(provider_name: String, model: String, cassette: PathBuf) -> Self {
+        Self {
+            provider_name,
+            model,
+            cassette,
+            next_turn: Mutex::new(0),
+            recording: None,
+        }
+    }
+
+    /// Send requests to `provider` and append its streams to the cassette
+    pub fn recording(mut self, provider: Box<dyn LlmProvider>) -> Self {
+        self.recording = Some(provider);
+        self
+    }
+
+    fn replay(&self) -> ChatStream {
+        let cassette = self.cassette.clone();
+
+        let index = {
+            let mut next = self.next_turn.lock().unwrap();
+            *next += 1;
+            *next - 1
+        };
+
+        ChatStream::spawn("Replay", |tx| async move {
+            let mut recorded = Cassette::load(&cassette)?;
+            if index >= recorded.turns.len() {
+                return Err(anyhow::anyhow!(
+                    "Cassette {} has no turn {} ({} recorded)",
+                    cassette.display(),
+                    index + 1,
+                    recorded.turns.len()
+                ));
+            }
+            let mut finished = false;
+            for event in recorded.turns.swap_remove(index).events {
+                finished = matches!(
+                    event,
+                    CassetteEvent::Done { .. } | CassetteEvent::Error { .. }
+                );
+                tx.send(event.into())?;
+                if finished {
+                    break;
+                }
+            }
+            // Without a last event the app would wait for the turn forever
+            if !finished {
+                return Err(anyhow::anyhow!(
+                    "Cassette {} turn {} ends without a done or error event",
+                    cassette.display(),
+                    index + 1
+                ));
+            }
+            Ok(())
+        })
+    }
+
+    /// Forward `inner`'s events and save them as a turn once the stream
+    /// finishes. Cancelled streams are not saved; a failed save ends the
+    /// stream with an error instead of its last event.
+    fn record(&self, model: &str, mut inner: ChatStream) -> ChatStream {
+        let cassette = self.cassette.clone();
+        let mut turn = CassetteTurn {
+            model: model.to_string(),
+            events: Vec::new(),
+        };
+
+        // Cancelling drops `inner`, which cancels the recorded request
+        ChatStream::spawn("Recording failed", |tx| async move {
+            while let Some(event) = inner.next().await {
+                let finished = matches!(event, LlmEvent::Done(_) | LlmEvent::Error(_));
+                turn.events.push(CassetteEvent::from(&event));
+                if finished {
+                    Cassette::append(&cassette, turn)?;
+                    tx.send(event)?;
+                    break;
+                }
+                tx.send(event)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+impl LlmProvider for ReplayProvider {
+    fn name(&self) -> &str {
+        &self.provider_name
+    }
+
+    fn is_available(&self) -> bool {
+        self.recording.is_some() || self.cassette.exists()
+    }
+
+    fn chat(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
+        match &self.recording {
+            Some(provider) => {
+                let inner = provider.chat(model, messages, tools, options)?;
+                Ok(self.record(model, inner))
+            }
+            None => Ok(self.replay()),
+        }
+    }
+
+    fn continue_with_tools(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+        tool_results: Vec<ToolResult>,
+        options: &ChatOptions,
+    ) -> Result<ChatStream> {
+        match &self.recording {
+            Some(provider) => {
+                let inner =
+                    provider.continue_with_tools(model, messages, tools, tool_results, options)?;
+                Ok(self.record(model, inner))
+            }
+            None => Ok(self.replay()),
+        }
+    }
+
+    fn list_models(&self) -> Result<Vec<ModelInfo>> {
+        match &self.recording {
+            Some(provider) => provider.list_models(),
+            None => Ok(vec![ModelInfo {
+                id: self.model.clone(),
+                name: self.model.clone(),
+                provider: self.provider_name.clone(),
+            }]),
+        }
+    }
+
+    fn count_tokens(
+        &self,
+        model: &str,
+        messages: Vec<ProviderMessage>,
+        tools: Option<Vec<ToolDef>>,
+    ) -> Result<Option<u32>> {
+        match &self.recording {
+            Some(provider) => provider.count_tokens(model, messages, tools),
+            None => Ok(None),
+        }
+    }
+
+    /// Embeddings aren't recorded; in record mode they come from the
+    /// recorded provider
+    fn embed(&self, model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        match &self.recording {
+            Some(provider) => provider.embed(model, inputs),
+            None => Err(anyhow::anyhow!("Replay providers don't support embeddings")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::block_on;
+    use serde_json::json;
+
+    fn temp_cassette(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("llm-tui-test-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn collect(mut stream: ChatStream) -> Vec<LlmEvent> {
+        block_on(async {
+            let mut events = Vec::new();
+            while let Some(event) = stream.next().await {
+                events.push(event);
+            }
+            events
+        })
+    }
+
+    fn turn(events: Vec<CassetteEvent>) -> CassetteTurn {
+        CassetteTurn {
+            model: "test-model".to_string(),
+            events,
+        }
+    }
+
+    #[test]
+    fn event_conversions_round_trip() {
+        let events = vec![
+            LlmEvent::Text("hello".to_string()),
+            LlmEvent::Thinking("hmm".to_string()),
+            LlmEvent::ThinkingSignature("sig".to_string()),
+            LlmEvent::ToolUse {
+                id: "call-1".to_string(),
+                name: "read_file".to_string(),
+                input: json!({"path": "src/main.rs"}),
+            },
+            LlmEvent::Retrying {
+                attempt: 2,
+                delay: Duration::from_millis(1500),
+            },
+            LlmEvent::Error(ProviderError::new(ErrorKind::Overloaded, "slow down")),
+            LlmEvent::Done(Usage::new(10, 20)),
+        ];
+
+        for event in events {
+            let back = LlmEvent::from(CassetteEvent::from(&event));
+            assert_eq!(format!("{:?}", back), format!("{:?}", event));
+        }
+    }
+
+    #[test]
+    fn missing_error_kind_defaults_to_other() {
+        let event: CassetteEvent =
+            serde_json::from_value(json!({"type": "error", "message": "boom"})).unwrap();
+        match LlmEvent::from(event) {
+            LlmEvent::Error(e) => {
+                assert_eq!(e.kind, ErrorKind::Other);
+                assert_eq!(e.message, "boom");
+            }
+            other => panic!("expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn append_creates_and_extends_cassette() {
+        let path = temp_cassette("append");
+        Cassette::append(
+            &path,
+            turn(vec![
+                CassetteEvent::Text {
+                    text: "one".to_string(),
+                },
+                CassetteEvent::Done {
+                    usage: Usage::new(1, 2),
+                },
+            ]),
+        )
+        .unwrap();
+        Cassette::append(
+            &path,
+            turn(vec![CassetteEvent::Done {
+                usage: Usage::default(),
+            }]),
+        )
+        .unwrap();
+
+        let cassette = Cassette::load(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(cassette.turns.len(), 2);
+        assert_eq!(cassette.turns[0].model, "test-model");
+        assert_eq!(cassette.turns[0].events.len(), 2);
+        assert!(matches!(
+            cassette.turns[0].events[1],
+            CassetteEvent::Done { usage } if usage == Usage::new(1, 2)
+        ));
+    }
+
+    #[test]
+    fn replays_turns_in_order() {
+        let path = temp_cassette("order");
+        for text in ["first", "second"] {
+            Cassette::append(
+                &path,
+                turn(vec![
+                    CassetteEvent::Text {
+                        text: text.to_string(),
+                    },
+                    CassetteEvent::Done {
+                        usage: Usage::default(),
+                    },
+                ]),
+            )
+            .unwrap();
+        }
+
+        let provider = ReplayProvider::new("replay".to_string(), "m".to_string(), path.clone());
+        let first = collect(provider.replay());
+        let second = collect(provider.replay());
+        let third = collect(provider.replay());
+        let _ = std::fs::remove_file(&path);
+
+        assert!(matches!(&first[0], LlmEvent::Text(t) if t == "first"));
+        assert!(matches!(first[1], LlmEvent::Done(_)));
+        assert!(matches!(&second[0], LlmEvent::Text(t) if t == "second"));
+        assert!(matches!(&third[..], [LlmEvent::Error(e)] if e.message.contains("no turn 3")));
+    }
+
+    #[test]
+    fn unfinished_turn_ends_with_error() {
+        let path = temp_cassette("unfinished");
+        Cassette::append(
+            &path,
+            turn(vec![CassetteEvent::Text {
+                text: "partial".to_string(),
+            }]),
+        )
+        .unwrap();
+
+        let provider = ReplayProvider::new("replay".to_string(), "m".to_string(), path.clone());
+        let events = collect(provider.replay());
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[1], LlmEvent::Error(e) if e.message.contains("ends without")));
+    }
+}
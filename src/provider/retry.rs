@@ -0,0 +1,154 @@
+//! Retry with exponential backoff for transient provider errors
+//!
+//! Only the initial request is retried: once a response has started
+//! streaming, tokens may already be on screen and a retry would repeat them.
+
+use super::{EventSender, LlmEvent};
+use anyhow::Result;
+use reqwest::header::HeaderMap;
+use reqwest::{Response, StatusCode};
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+/// Attempts in total, including the first one
+const MAX_ATTEMPTS: u32 = 4;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+/// Cap on server-provided retry-after values
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Statuses that mean "try again later" rather than "this request is wrong".
+/// 529 is Anthropic's "overloaded".
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504 | 529)
+}
+
+/// Connection failures and timeouts, where the request never got an answer
+fn is_retryable_error(error: &reqwest::Error) -> bool {
+    error.is_connect() || error.is_timeout()
+}
+
+/// Read `retry-after-ms` (OpenAI) or `retry-after` in seconds
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| {
+        let value = headers
+            .get(name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f64>()
+            .ok()?;
+        // Duration panics on negative or infinite seconds
+        (value.is_finite() && value >= 0.0).then_some(value)
+    };
+
+    let delay = header("retry-after-ms")
+        .map(|ms| Duration::from_secs_f64(ms / 1000.0))
+        .or_else(|| header("retry-after").map(Duration::from_secs_f64))?;
+    Some(delay.min(MAX_RETRY_AFTER))
+}
+
+/// Exponential backoff with jitter: a random delay between half and all of
+/// `BASE_DELAY * 2^(attempt - 1)`, capped at `MAX_DELAY`
+fn backoff(attempt: u32) -> Duration {
+    let ceiling = BASE_DELAY
+        .saturating_mul(1 << (attempt - 1).min(16))
+        .min(MAX_DELAY);
+    // RandomState is seeded randomly per instance, which is enough for jitter
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+    ceiling.mul_f64(fraction)
+}
+
+/// Send a request, retrying transient failures with backoff.
+///
+/// `send` must build a fresh request on every call. Each retry is announced
+/// with `LlmEvent::Retrying`. Returns the last response, which may still be
+/// an error status for the caller to report.
+pub async fn send_with_retry<Fut>(
+    mut send: impl FnMut() -> Fut,
+    tx: &EventSender,
+) -> Result<Response>
+where
+    Fut: Future<Output = reqwest::Result<Response>>,
+{
+    let mut attempt = 1;
+
+    loop {
+        let delay = match send().await {
+            Ok(response) if response.status().is_success() => return Ok(response),
+            Ok(response) if is_retryable_status(response.status()) && attempt < MAX_ATTEMPTS => {
+                crate::debug_log!(
+                    "DEBUG: Retryable status {} on attempt {}",
+                    response.status(),
+                    attempt
+                );
+                retry_after(response.headers()).unwrap_or_else(|| backoff(attempt))
+            }
+            Ok(response) => return Ok(response),
+            Err(e) if is_retryable_error(&e) && attempt < MAX_ATTEMPTS => {
+                crate::debug_log!("DEBUG: Request failed on attempt {}: {}", attempt, e);
+                backoff(attempt)
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        attempt += 1;
+        tx.send(LlmEvent::Retrying { attempt, delay })?;
+        tokio::time::sleep(delay).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn retry_after_prefers_milliseconds() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after-ms", "250"), ("retry-after", "3")])),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", " 1.5 ")])),
+            Some(Duration::from_millis(1500))
+        );
+    }
+
+    #[test]
+    fn retry_after_ignores_what_it_cannot_use() {
+        assert_eq!(
+            retry_after(&headers(&[("retry-after", "3600")])),
+            Some(MAX_RETRY_AFTER)
+        );
+        // HTTP dates aren't parsed, so backoff takes over
+        assert_eq!(
+            retry_after(&headers(&[(
+                "retry-after",
+                "Wed, 21 Oct 2026 07:28:00 GMT"
+            )])),
+            None
+        );
+        assert_eq!(retry_after(&headers(&[("retry-after", "-1")])), None);
+        assert_eq!(retry_after(&headers(&[("retry-after-ms", "inf")])), None);
+        assert_eq!(retry_after(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn backoff_doubles_within_bounds() {
+        for attempt in 1..=8 {
+            let ceiling = (BASE_DELAY * 2u32.pow(attempt - 1)).min(MAX_DELAY);
+            let delay = backoff(attempt);
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+//! JSON Schema support for structured output
+//!
+//! A session can carry a schema (set with `:schema <file>`). Providers ask
+//! the model to answer in that shape, and the reply is checked here. The
+//! validator covers the keywords structured output schemas use in practice:
+//! type, enum, const, properties, required, additionalProperties, items,
+//! length and range limits, anyOf/oneOf/allOf and local `$ref`s.
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+use std::path::Path;
+
+/// Read a schema file. The schema must be a JSON object.
+pub fn load(path: &Path) -> Result<Value> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+    let schema: Value = serde_json::from_str(&contents)
+        .map_err(|e| anyhow!("'{}' is not valid JSON: {}", path.display(), e))?;
+    if !schema.is_object() {
+        bail!("'{}' is not a JSON Schema object", path.display());
+    }
+    Ok(schema)
+}
+
+/// The JSON in a reply, without a surrounding ```json fence
+fn reply_json(text: &str) -> &str {
+    let text = text.trim();
+    let Some(fenced) = text.strip_prefix("```") else {
+        return text;
+    };
+    let body = fenced.split_once('\n').map_or("", |(_, body)| body);
+    body.trim_end().strip_suffix("```").unwrap_or(body).trim()
+}
+
+/// Check a reply against `schema`. Returns the problems found, empty if
+/// the reply is valid JSON matching the schema.
+pub fn check_reply(schema: &Value, reply: &str) -> Vec<String> {
+    match serde_json::from_str::<Value>(reply_json(reply)) {
+        Ok(value) => validate(schema, &value),
+        Err(e) => vec![format!("reply is not valid JSON: {}", e)],
+    }
+}
+
+/// Validate `value` against `schema`
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    check(schema, schema, value, "", &mut errors);
+    errors
+}
+
+fn type_matches(name: &str, value: &Value) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Resolve a local reference like "#/$defs/item"
+fn resolve<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    root.pointer(reference.strip_prefix('#')?)
+}
+
+fn check(root: &Value, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    // `true` accepts anything, `false` nothing
+    let schema = match schema {
+        Value::Bool(true) => return,
+        Value::Bool(false) => {
+            errors.push(format!("{}: not allowed", location(path)));
+            return;
+        }
+        Value::Object(schema) => schema,
+        _ => return,
+    };
+
+    if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+        match resolve(root, reference) {
+            Some(target) => check(root, target, value, path, errors),
+            None => errors.push(format!("{}: unknown $ref {}", location(path), reference)),
+        }
+    }
+
+    if let Some(expected) = schema.get("type") {
+        let names: Vec<&str> = match expected {
+            Value::String(name) => vec![name.as_str()],
+            Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        if !names.is_empty() && !names.iter().any(|name| type_matches(name, value)) {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                location(path),
+                names.join(" or "),
+                type_name(value)
+            ));
+            // Other keywords would only repeat the mismatch
+            return;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!(
+                "{}: {} is not one of {}",
+                location(path),
+                value,
+                Value::Array(allowed.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            errors.push(format!("{}: expected {}", location(path), expected));
+        }
+    }
+
+    match value {
+        Value::Object(object) => check_object(root, schema, object, path, errors),
+        Value::Array(items) => check_array(root, schema, items, path, errors),
+        Value::String(s) => {
+            let length = s.chars().count() as u64;
+            if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+                if length < min {
+                    errors.push(format!(
+                        "{}: shorter than {} characters",
+                        location(path),
+                        min
+                    ));
+                }
+            }
+            if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+                if length > max {
+                    errors.push(format!(
+                        "{}: longer than {} characters",
+                        location(path),
+                        max
+                    ));
+                }
+            }
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(0.0);
+            let limit = |key: &str| schema.get(key).and_then(Value::as_f64);
+            if limit("minimum").is_some_and(|min| n < min)
+                || limit("exclusiveMinimum").is_some_and(|min| n <= min)
+                || limit("maximum").is_some_and(|max| n > max)
+                || limit("exclusiveMaximum").is_some_and(|max| n >= max)
+            {
+                errors.push(format!("{}: {} is out of range", location(path), n));
+            }
+        }
+        _ => {}
+    }
+
+    if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+        for sub in all {
+            check(root, sub, value, path, errors);
+        }
+    }
+    for (keyword, wanted) in [("anyOf", None), ("oneOf", Some(1))] {
+        let Some(options) = schema.get(keyword).and_then(Value::as_array) else {
+            continue;
+        };
+        let matching = options
+            .iter()
+            .filter(|sub| {
+                let mut sub_errors = Vec::new();
+                check(root, sub, value, path, &mut sub_errors);
+                sub_errors.is_empty()
+            })
+            .count();
+        let ok = match wanted {
+            Some(count) => matching == count,
+            None => matching > 0,
+        };
+        if !ok {
+            errors.push(format!(
+                "{}: matches {} of the {} alternatives ({})",
+                location(path),
+                matching,
+                options.len(),
+                keyword
+            ));
+        }
+    }
+}
+
+fn check_object(
+    root: &Value,
+    schema: &Map<String, Value>,
+    object: &Map<String, Value>,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    let properties = schema.get("properties").and_then(Value::as_object);
+
+    for name in schema
+        .get("required")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+    {
+        if !object.contains_key(name) {
+            errors.push(format!(
+                "{}: missing required property \"{}\"",
+                location(path),
+                name
+            ));
+        }
+    }
+
+    for (name, property) in object {
+        let property_path = format!("{}/{}", path, name);
+        match properties.and_then(|p| p.get(name)) {
+            Some(property_schema) => check(root, property_schema, property, &property_path, errors),
+            None => match schema.get("additionalProperties") {
+                Some(Value::Bool(false)) => errors.push(format!(
+                    "{}: unexpected property \"{}\"",
+                    location(path),
+                    name
+                )),
+                Some(extra) => check(root, extra, property, &property_path, errors),
+                None => {}
+            },
+        }
+    }
+}
+
+fn check_array(
+    root: &Value,
+    schema: &Map<String, Value>,
+    items: &[Value],
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    let count = items.len() as u64;
+    if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+        if count < min {
+            errors.push(format!("{}: fewer than {} items", location(path), min));
+        }
+    }
+    if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+        if count > max {
+            errors.push(format!("{}: more than {} items", location(path), max));
+        }
+    }
+    if let Some(item_schema) = schema.get("items") {
+        for (i, item) in items.iter().enumerate() {
+            check(root, item_schema, item, &format!("{}/{}", path, i), errors);
+        }
+    }
+}
+
+/// Where a problem is, as a JSON pointer ("/" for the whole reply)
+fn location(path: &str) -> &str {
+    if path.is_empty() {
+        "/"
+    } else {
+        path
+    }
+}
+
+/// A follow-up message asking the model to fix a reply
+pub fn retry_prompt(errors: &[String]) -> String {
+    format!(
+        "Your reply did not match the required JSON schema:\n{}\n\nReply again with only JSON that matches the schema.",
+        errors
+            .iter()
+            .map(|e| format!("- {}", e))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn person() -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "name": {"type": "string", "minLength": 1},
+                "age": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}, "maxItems": 2}
+            },
+            "required": ["name"],
+            "additionalProperties": false,
+            "$defs": {"tag": {"enum": ["a", "b"]}}
+        })
+    }
+
+    #[test]
+    fn accepts_matching_reply() {
+        let reply = r#"{"name": "Ada", "age": 36, "tags": ["a"]}"#;
+        assert!(check_reply(&person(), reply).is_empty());
+    }
+
+    #[test]
+    fn strips_json_fence() {
+        let reply = "```json\n{\"name\": \"Ada\"}\n```";
+        assert!(check_reply(&person(), reply).is_empty());
+        assert!(check_reply(&person(), "```\n{\"name\": \"Ada\"}\n```").is_empty());
+    }
+
+    #[test]
+    fn reports_invalid_json() {
+        let errors = check_reply(&person(), "Sure! Here it is: {");
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("reply is not valid JSON"));
+    }
+
+    #[test]
+    fn reports_each_problem_with_its_location() {
+        let reply = r#"{"age": -1.5, "tags": ["a", "c", "b"], "extra": true}"#;
+        let errors = check_reply(&person(), reply);
+        assert_eq!(
+            errors,
+            vec![
+                "/: missing required property \"name\"",
+                "/age: expected integer, got number",
+                "/: unexpected property \"extra\"",
+                "/tags: more than 2 items",
+                "/tags/1: \"c\" is not one of [\"a\",\"b\"]",
+            ]
+        );
+    }
+
+    #[test]
+    fn wrong_root_type_stops_early() {
+        let errors = check_reply(&person(), "[1, 2]");
+        assert_eq!(errors, vec!["/: expected object, got array"]);
+    }
+
+    #[test]
+    fn one_of_needs_exactly_one_match() {
+        let schema = json!({"oneOf": [{"type": "integer"}, {"type": "number"}]});
+        assert!(check_reply(&schema, "1.5").is_empty());
+        assert_eq!(
+            check_reply(&schema, "2"),
+            vec!["/: matches 2 of the 2 alternatives (oneOf)"]
+        );
+        let any = json!({"anyOf": [{"type": "integer"}, {"type": "number"}]});
+        assert!(check_reply(&any, "2").is_empty());
+        assert_eq!(check_reply(&any, "\"x\"").len(), 1);
+    }
+}
@@ -0,0 +1,456 @@
+//! Semantic search over saved messages
+//!
+//! Messages are split into chunks and embedded with the configured
+//! embedding model by a background indexer, which picks up whatever hasn't
+//! been embedded yet each time messages are saved. Searches embed the query
+//! and rank sessions by the cosine similarity of their closest chunk.
+
+use crate::db::{self, SearchResult, UnembeddedMessage};
+use crate::provider::{ErrorKind, LlmProvider, ProviderError};
+use anyhow::{anyhow, Result};
+use rusqlite::Connection;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Longest chunk embedded, in characters
+const CHUNK_CHARS: usize = 1500;
+/// Messages embedded per request while indexing
+const BATCH_MESSAGES: usize = 16;
+const MAX_RESULTS: usize = 50;
+/// Rank offset for reciprocal rank fusion; higher flattens the ranking
+const FUSION_K: f32 = 60.0;
+
+/// Split a message into chunks of at most `CHUNK_CHARS`, packing whole
+/// paragraphs together and breaking long ones between words
+fn chunk_text(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        let mut rest = paragraph;
+        while rest.chars().count() > CHUNK_CHARS {
+            let limit = rest
+                .char_indices()
+                .nth(CHUNK_CHARS)
+                .map_or(rest.len(), |(i, _)| i);
+            let cut = rest[..limit]
+                .rfind(char::is_whitespace)
+                .filter(|&i| i > 0)
+                .unwrap_or(limit);
+            if !current.is_empty() {
+                chunks.push(std::mem::take(&mut current));
+            }
+            chunks.push(rest[..cut].trim_end().to_string());
+            rest = rest[cut..].trim_start();
+        }
+
+        if !current.is_empty() && current.chars().count() + rest.chars().count() + 2 > CHUNK_CHARS {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(rest);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+    // Every message needs a chunk, or it would be picked up again forever
+    if chunks.is_empty() {
+        chunks.push(text.to_string());
+    }
+    chunks
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norms = norm(a) * norm(b);
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// A message's chunks, each with its embedding
+type EmbeddedChunks = Vec<(String, Vec<f32>)>;
+
+/// Embed each message's chunks in one request, returning them per message
+fn embed_messages(
+    provider: &dyn LlmProvider,
+    model: &str,
+    messages: &[UnembeddedMessage],
+) -> Result<Vec<EmbeddedChunks>> {
+    let chunks: Vec<Vec<String>> = messages.iter().map(|m| chunk_text(&m.content)).collect();
+    let inputs: Vec<String> = chunks.iter().flatten().cloned().collect();
+    let expected = inputs.len();
+    let embeddings = provider.embed(model, inputs)?;
+    if embeddings.len() != expected {
+        return Err(anyhow!(
+            "{} returned {} embeddings for {} inputs",
+            provider.name(),
+            embeddings.len(),
+            expected
+        ));
+    }
+
+    let mut embeddings = embeddings.into_iter();
+    Ok(chunks
+        .into_iter()
+        .map(|texts| texts.into_iter().zip(&mut embeddings).collect())
+        .collect())
+}
+
+/// Whether a failed embedding request is worth retrying on a later pass.
+/// Anything else is blamed on the messages in it.
+fn is_transient(provider: &dyn LlmProvider, error: &anyhow::Error) -> bool {
+    ProviderError::from_error(provider.name(), error).kind != ErrorKind::Other
+}
+
+/// Embed every message `model` hasn't embedded yet
+fn index_pending(conn: &mut Connection, provider: &dyn LlmProvider, model: &str) -> Result<()> {
+    loop {
+        let messages = db::unembedded_messages(conn, model, BATCH_MESSAGES)?;
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        match embed_messages(provider, model, &messages) {
+            Ok(embedded) => {
+                for (message, chunks) in messages.iter().zip(embedded) {
+                    db::save_embeddings(conn, message, model, &chunks)?;
+                }
+            }
+            Err(e) if is_transient(provider, &e) => return Err(e),
+            // Find the messages the provider rejected, so they don't hold up
+            // every pass after this one
+            Err(e) => {
+                let mut rejected = Vec::new();
+                for message in &messages {
+                    match embed_messages(provider, model, std::slice::from_ref(message)) {
+                        Ok(embedded) => db::save_embeddings(conn, message, model, &embedded[0])?,
+                        Err(e) if is_transient(provider, &e) => return Err(e),
+                        Err(e) => rejected.push((message, e)),
+                    }
+                }
+                // Nothing embedded on its own either, so blame the model or
+                // its configuration rather than the messages
+                if rejected.len() == messages.len() {
+                    return Err(e);
+                }
+                for (message, e) in rejected {
+                    crate::debug_log!(
+                        "DEBUG: Skipping message {} for {}: {:#}",
+                        message.id,
+                        model,
+                        e
+                    );
+                    db::mark_unembeddable(conn, message, model)?;
+                }
+            }
+        }
+    }
+}
+
+/// Embeds saved messages on a background thread with its own connection
+pub struct Indexer {
+    wake: Sender<()>,
+    /// Why the last indexing pass failed, if it did
+    error: Arc<Mutex<Option<String>>>,
+}
+
+impl Indexer {
+    /// Start indexing with `provider`'s `model`, beginning with any
+    /// messages saved before. The thread stops when the indexer is dropped.
+    pub fn start(provider: Arc<dyn LlmProvider>, model: String) -> Self {
+        let (wake, rx) = channel::<()>();
+        let error = Arc::new(Mutex::new(None));
+        let status = error.clone();
+
+        std::thread::spawn(move || {
+            let mut conn = match db::init_db() {
+                Ok(conn) => conn,
+                Err(e) => {
+                    *status.lock().unwrap() = Some(e.to_string());
+                    return;
+                }
+            };
+            while rx.recv().is_ok() {
+                // One pass covers every save made while the last one ran
+                while rx.try_recv().is_ok() {}
+                let result = index_pending(&mut conn, provider.as_ref(), &model);
+                if let Err(ref e) = result {
+                    crate::debug_log!("DEBUG: Indexing with {} failed: {:#}", model, e);
+                }
+                *status.lock().unwrap() = result.err().map(|e| format!("{:#}", e));
+            }
+        });
+
+        let indexer = Self { wake, error };
+        indexer.notify();
+        indexer
+    }
+
+    /// Index newly saved messages
+    pub fn notify(&self) {
+        let _ = self.wake.send(());
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+/// Sessions ranked by how close their best chunk is to `query`
+pub fn search(
+    conn: &Connection,
+    provider: &dyn LlmProvider,
+    model: &str,
+    query: &str,
+) -> Result<Vec<SearchResult>> {
+    let query_embedding = provider
+        .embed(model, vec![query.to_string()])?
+        .pop()
+        .ok_or_else(|| anyhow!("{} returned no embedding", provider.name()))?;
+
+    let mut best: HashMap<String, SearchResult> = HashMap::new();
+    for chunk in db::load_embeddings(conn, model)? {
+        let score = cosine_similarity(&query_embedding, &chunk.embedding);
+        let entry = best
+            .entry(chunk.session_id.clone())
+            .or_insert_with(|| SearchResult {
+                session_id: chunk.session_id,
+                session_name: chunk.session_name,
+                project: chunk.project,
+                updated_at: chunk.updated_at,
+                snippet: String::new(),
+                match_count: 1,
+                score: None,
+            });
+        if entry.score.is_none_or(|best| score > best) {
+            entry.score = Some(score);
+            entry.snippet = chunk.content;
+        }
+    }
+
+    let mut results: Vec<SearchResult> = best.into_values().collect();
+    results.sort_by(|a, b| b.score.unwrap_or(0.0).total_cmp(&a.score.unwrap_or(0.0)));
+    results.truncate(MAX_RESULTS);
+    Ok(results)
+}
+
+/// Blend semantic and keyword results with reciprocal rank fusion, so
+/// sessions ranked well by either search come first. Keyword results are
+/// ranked by their number of matching messages.
+pub fn hybrid(semantic: Vec<SearchResult>, mut keyword: Vec<SearchResult>) -> Vec<SearchResult> {
+    keyword.sort_by_key(|r| std::cmp::Reverse(r.match_count));
+
+    let mut fused: Vec<(f32, SearchResult)> = Vec::new();
+    let mut positions: HashMap<String, usize> = HashMap::new();
+    for (rank, result) in semantic.into_iter().enumerate() {
+        positions.insert(result.session_id.clone(), fused.len());
+        fused.push((1.0 / (FUSION_K + rank as f32 + 1.0), result));
+    }
+    for (rank, result) in keyword.into_iter().enumerate() {
+        let weight = 1.0 / (FUSION_K + rank as f32 + 1.0);
+        match positions.get(&result.session_id) {
+            Some(&i) => {
+                // The keyword snippet shows the words that matched
+                let (fused_score, existing) = &mut fused[i];
+                *fused_score += weight;
+                existing.snippet = result.snippet;
+                existing.match_count = result.match_count;
+            }
+            None => fused.push((weight, result)),
+        }
+    }
+
+    fused.sort_by(|a, b| b.0.total_cmp(&a.0));
+    fused.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::{ChatOptions, ChatStream, ModelInfo, ProviderMessage, ToolDef};
+    use crate::session::Session;
+
+    /// Embeds every input as `[1.0]`, but fails any request holding a
+    /// rejected input
+    struct FakeEmbedder {
+        rejects: &'static str,
+        error: ErrorKind,
+    }
+
+    impl LlmProvider for FakeEmbedder {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn is_available(&self) -> bool {
+            true
+        }
+
+        fn chat(
+            &self,
+            _model: &str,
+            _messages: Vec<ProviderMessage>,
+            _tools: Option<Vec<ToolDef>>,
+            _options: &ChatOptions,
+        ) -> Result<ChatStream> {
+            unimplemented!()
+        }
+
+        fn list_models(&self) -> Result<Vec<ModelInfo>> {
+            Ok(Vec::new())
+        }
+
+        fn embed(&self, _model: &str, inputs: Vec<String>) -> Result<Vec<Vec<f32>>> {
+            if inputs.iter().any(|i| i.contains(self.rejects)) {
+                return Err(ProviderError::new(self.error, "input rejected").into());
+            }
+            Ok(inputs.iter().map(|_| vec![1.0]).collect())
+        }
+    }
+
+    fn saved_messages(contents: &[&str]) -> Connection {
+        let conn = db::create_schema(Connection::open_in_memory().unwrap()).unwrap();
+        let mut session = Session::new(None, None, "fake".to_string(), None);
+        db::save_session(&conn, &session).unwrap();
+        for content in contents {
+            session.add_message("user".to_string(), content.to_string(), None);
+            db::save_message(&conn, &session.id, session.messages.last().unwrap()).unwrap();
+        }
+        conn
+    }
+
+    fn result(id: &str, match_count: usize) -> SearchResult {
+        SearchResult {
+            session_id: id.to_string(),
+            session_name: None,
+            project: None,
+            updated_at: 0,
+            snippet: format!("{} snippet", id),
+            match_count,
+            score: None,
+        }
+    }
+
+    fn ids(results: &[SearchResult]) -> Vec<&str> {
+        results.iter().map(|r| r.session_id.as_str()).collect()
+    }
+
+    #[test]
+    fn short_paragraphs_share_a_chunk() {
+        assert_eq!(
+            chunk_text("one\n\n  two  \n\n\n\nthree"),
+            vec!["one\n\ntwo\n\nthree"]
+        );
+    }
+
+    #[test]
+    fn chunks_stay_under_the_limit() {
+        let paragraph = "word ".repeat(200);
+        let text = [paragraph.as_str(); 4].join("\n\n");
+        let chunks = chunk_text(&text);
+        assert!(chunks.len() > 1);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= CHUNK_CHARS);
+            assert!(!chunk.starts_with(char::is_whitespace));
+        }
+    }
+
+    #[test]
+    fn long_paragraphs_break_between_words() {
+        let text = "abcdefghi ".repeat(400);
+        let chunks = chunk_text(&text);
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks
+            .iter()
+            .all(|c| c.split(' ').all(|w| w == "abcdefghi")));
+        // A word longer than a chunk is cut where it has to be
+        let chunks = chunk_text(&"é".repeat(CHUNK_CHARS + 10));
+        assert_eq!(chunks[0].chars().count(), CHUNK_CHARS);
+        assert_eq!(chunks[1].chars().count(), 10);
+    }
+
+    #[test]
+    fn blank_text_still_gets_a_chunk() {
+        assert_eq!(chunk_text(" \n\n\t"), vec![" \n\n\t"]);
+    }
+
+    #[test]
+    fn cosine_similarity_handles_edge_cases() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 1.0]), 0.0);
+    }
+
+    #[test]
+    fn hybrid_favours_sessions_both_searches_found() {
+        let semantic = vec![result("a", 1), result("b", 1), result("c", 1)];
+        let mut matched = result("c", 5);
+        matched.snippet = "keyword match".to_string();
+        let fused = hybrid(semantic, vec![result("d", 1), matched]);
+
+        // "b" and "d" tie on rank, and the semantic one stays ahead
+        assert_eq!(ids(&fused), vec!["c", "a", "b", "d"]);
+        // The keyword snippet and count replace the semantic ones
+        assert_eq!(fused[0].snippet, "keyword match");
+        assert_eq!(fused[0].match_count, 5);
+    }
+
+    #[test]
+    fn hybrid_ranks_keyword_results_by_match_count() {
+        let fused = hybrid(Vec::new(), vec![result("few", 1), result("many", 9)]);
+        assert_eq!(ids(&fused), vec!["many", "few"]);
+    }
+
+    #[test]
+    fn rejected_messages_are_skipped() {
+        let mut conn = saved_messages(&["first", "bad one", "last"]);
+        let provider = FakeEmbedder {
+            rejects: "bad",
+            error: ErrorKind::Other,
+        };
+
+        index_pending(&mut conn, &provider, "embed").unwrap();
+        assert_eq!(db::count_unembedded(&conn, "embed").unwrap(), 0);
+        let contents: Vec<String> = db::load_embeddings(&conn, "embed")
+            .unwrap()
+            .into_iter()
+            .map(|c| c.content)
+            .collect();
+        assert_eq!(contents, vec!["first", "last"]);
+    }
+
+    #[test]
+    fn transient_failures_are_retried_later() {
+        let mut conn = saved_messages(&["first", "bad one"]);
+        let provider = FakeEmbedder {
+            rejects: "bad",
+            error: ErrorKind::Connection,
+        };
+
+        assert!(index_pending(&mut conn, &provider, "embed").is_err());
+        assert_eq!(db::count_unembedded(&conn, "embed").unwrap(), 2);
+    }
+
+    #[test]
+    fn a_model_rejecting_everything_marks_nothing() {
+        let mut conn = saved_messages(&["bad one", "bad two"]);
+        let provider = FakeEmbedder {
+            rejects: "bad",
+            error: ErrorKind::Other,
+        };
+
+        assert!(index_pending(&mut conn, &provider, "embed").is_err());
+        assert_eq!(db::count_unembedded(&conn, "embed").unwrap(), 2);
+    }
+}
@@ -1,5 +1,11 @@
+use crate::provider::{SamplingParams, Usage};
+use anyhow::{anyhow, bail, Result};
+use base64::Engine;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Message {
@@ -13,6 +19,141 @@ pub struct Message {
     pub is_summary: bool,
     #[serde(default)]
     pub token_count: Option<i64>,
+    /// Set on "tool" role messages; `content` holds the tool output
+    #[serde(default)]
+    pub tool_call: Option<ToolCallRecord>,
+    /// Partial reply kept after the user cancelled generation
+    #[serde(default)]
+    pub interrupted: bool,
+    /// Reasoning streamed before the answer, kept apart from `content`
+    #[serde(default)]
+    pub thinking: Option<String>,
+    /// Anthropic signature for `thinking`, needed to replay it
+    #[serde(default)]
+    pub thinking_signature: Option<String>,
+    /// Provider that produced this reply; differs from the session's
+    /// provider when a fallback answered
+    #[serde(default)]
+    pub provider: Option<String>,
+    /// Images sent with a user message
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+    /// Usage the provider reported for the request that produced this reply
+    #[serde(default)]
+    pub usage: Option<Usage>,
+    /// Cost of this reply in USD, priced when it was received
+    #[serde(default)]
+    pub cost: Option<f64>,
+}
+
+/// An image attached to a message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    /// File name, for display
+    pub name: String,
+    pub media_type: String,
+    /// Base64-encoded file contents
+    pub data: String,
+}
+
+/// Largest image accepted; Anthropic rejects anything bigger
+const MAX_ATTACHMENT_BYTES: usize = 5 * 1024 * 1024;
+
+/// Rough token cost of one image, for the context gauge
+const ATTACHMENT_TOKENS: i64 = 1600;
+
+/// Media type for an image path, if it's a format every provider accepts
+pub fn image_media_type(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    match ext.as_str() {
+        "png" => Some("image/png"),
+        "jpg" | "jpeg" => Some("image/jpeg"),
+        "gif" => Some("image/gif"),
+        "webp" => Some("image/webp"),
+        _ => None,
+    }
+}
+
+impl Attachment {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let media_type = image_media_type(path).ok_or_else(|| {
+            anyhow!(
+                "'{}' is not a supported image (png, jpeg, gif, webp)",
+                path.display()
+            )
+        })?;
+        let bytes =
+            std::fs::read(path).map_err(|e| anyhow!("Can't read '{}': {}", path.display(), e))?;
+        if bytes.len() > MAX_ATTACHMENT_BYTES {
+            bail!(
+                "'{}' is {} KB; images are limited to {} KB",
+                path.display(),
+                bytes.len() / 1024,
+                MAX_ATTACHMENT_BYTES / 1024
+            );
+        }
+
+        Ok(Self {
+            name: path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| path.display().to_string()),
+            media_type: media_type.to_string(),
+            data: base64::engine::general_purpose::STANDARD.encode(bytes),
+        })
+    }
+
+    /// Approximate decoded size in bytes
+    pub fn size(&self) -> usize {
+        self.data.len() / 4 * 3
+    }
+}
+
+/// How a tool call was resolved
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolStatus {
+    Approved,
+    AutoApproved,
+    Rejected,
+    Cancelled,
+}
+
+impl ToolStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ToolStatus::Approved => "approved",
+            ToolStatus::AutoApproved => "auto_approved",
+            ToolStatus::Rejected => "rejected",
+            ToolStatus::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "approved" => Some(ToolStatus::Approved),
+            "auto_approved" => Some(ToolStatus::AutoApproved),
+            "rejected" => Some(ToolStatus::Rejected),
+            "cancelled" => Some(ToolStatus::Cancelled),
+            _ => None,
+        }
+    }
+
+    /// Whether the tool actually ran
+    pub fn executed(&self) -> bool {
+        matches!(self, ToolStatus::Approved | ToolStatus::AutoApproved)
+    }
+}
+
+/// A single tool invocation requested by the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCallRecord {
+    pub id: String,
+    pub name: String,
+    pub input: serde_json::Value,
+    pub status: ToolStatus,
+    #[serde(default)]
+    pub is_error: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -25,10 +166,117 @@ pub struct Session {
     pub llm_provider: String,
     pub model: Option<String>,
     pub messages: Vec<Message>,
+    /// Sampling overrides set with `:set`, applied on top of the provider's
+    #[serde(default)]
+    pub sampling: SamplingParams,
+    /// JSON Schema replies must match, set with `:schema`
+    #[serde(default)]
+    pub response_schema: Option<serde_json::Value>,
+    /// Usage reported for the latest response since the session was opened
+    #[serde(skip)]
+    pub last_usage: Option<Usage>,
+    /// Tokenizer for the current provider and model, used to count new messages
+    #[serde(skip)]
+    pub tokenizer: Tokenizer,
+    /// Total cost of the session's replies in USD, None if none were priced
+    #[serde(skip)]
+    pub cost: Option<f64>,
+}
+
+/// How message token counts are worked out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Tokenizer {
+    /// Rough approximation: 1 token ≈ 4 characters
+    #[default]
+    Estimate,
+    /// OpenAI's BPE for GPT-4 and GPT-3.5
+    Cl100k,
+    /// OpenAI's BPE for GPT-4o, GPT-4.1 and the o-series
+    O200k,
+    /// A Hugging Face tokenizer.json, for local models
+    File(PathBuf),
+}
+
+/// Loaded tokenizer.json files, None if the file failed to load
+type TokenizerCache = Mutex<HashMap<PathBuf, Option<Arc<tokenizers::Tokenizer>>>>;
+
+fn tokenizer_cache() -> &'static TokenizerCache {
+    static CACHE: OnceLock<TokenizerCache> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+impl Tokenizer {
+    /// Pick a tokenizer from the provider type and model name. A tokenizer.json
+    /// configured for the model wins (the longest key the model id starts
+    /// with); OpenAI-style providers use tiktoken's BPE when it knows the
+    /// model; everything else falls back to the estimate.
+    pub fn for_model(
+        provider_type: &str,
+        model: &str,
+        tokenizer_files: &HashMap<String, PathBuf>,
+    ) -> Self {
+        use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer as Bpe};
+
+        let configured = tokenizer_files
+            .iter()
+            .filter(|(key, _)| model.starts_with(key.as_str()))
+            .max_by_key(|(key, _)| key.len());
+        if let Some((_, path)) = configured {
+            return Self::File(path.clone());
+        }
+        if !matches!(
+            provider_type,
+            "openai" | "openai_compatible" | "azure_openai"
+        ) {
+            return Self::Estimate;
+        }
+        // Strip routing prefixes like "openai/gpt-4o" (OpenRouter)
+        let model = model.rsplit('/').next().unwrap_or(model);
+        match get_tokenizer(model) {
+            Some(Bpe::O200kBase) => Self::O200k,
+            Some(Bpe::Cl100kBase) => Self::Cl100k,
+            _ => Self::Estimate,
+        }
+    }
+
+    /// Count the tokens in `text`
+    pub fn count(&self, text: &str) -> i64 {
+        match self {
+            Self::Estimate => estimate_tokens(text),
+            Self::Cl100k => tiktoken_rs::cl100k_base_singleton()
+                .encode_with_special_tokens(text)
+                .len() as i64,
+            Self::O200k => tiktoken_rs::o200k_base_singleton()
+                .encode_with_special_tokens(text)
+                .len() as i64,
+            Self::File(path) => match Self::load(path) {
+                Some(tokenizer) => match tokenizer.encode(text, false) {
+                    Ok(encoding) => encoding.len() as i64,
+                    Err(_) => estimate_tokens(text),
+                },
+                None => estimate_tokens(text),
+            },
+        }
+    }
+
+    /// Load a tokenizer.json once per path
+    fn load(path: &Path) -> Option<Arc<tokenizers::Tokenizer>> {
+        let mut cache = tokenizer_cache().lock().ok()?;
+        cache
+            .entry(path.to_path_buf())
+            .or_insert_with(|| match tokenizers::Tokenizer::from_file(path) {
+                Ok(tokenizer) => Some(Arc::new(tokenizer)),
+                Err(e) => {
+                    crate::debug_log!("DEBUG: Failed to load tokenizer {}: {}", path.display(), e);
+                    None
+                }
+            })
+            .clone()
+    }
 }
 
 /// Estimate token count for text (rough approximation: 1 token ≈ 4 characters)
-pub fn estimate_tokens(text: &str) -> i64 {
+fn estimate_tokens(text: &str) -> i64 {
     (text.len() as f64 / 4.0).ceil() as i64
 }
 
@@ -51,6 +299,11 @@ impl Session {
             llm_provider: provider,
             model,
             messages: Vec::new(),
+            sampling: SamplingParams::default(),
+            response_schema: None,
+            last_usage: None,
+            tokenizer: Tokenizer::default(),
+            cost: None,
         }
     }
 
@@ -82,7 +335,7 @@ impl Session {
         token_count: Option<i64>,
     ) {
         // Auto-calculate token count if not provided
-        let final_token_count = token_count.or_else(|| Some(estimate_tokens(&content)));
+        let final_token_count = token_count.or_else(|| Some(self.tokenizer.count(&content)));
 
         self.messages.push(Message {
             role,
@@ -92,12 +345,134 @@ impl Session {
             tools_executed,
             is_summary,
             token_count: final_token_count,
+            tool_call: None,
+            interrupted: false,
+            thinking: None,
+            thinking_signature: None,
+            provider: None,
+            attachments: Vec::new(),
+            usage: None,
+            cost: None,
+        });
+        self.updated_at = Utc::now();
+    }
+
+    /// Add a user message with images attached
+    pub fn add_user_message(&mut self, content: String, attachments: Vec<Attachment>) {
+        let token_count =
+            self.tokenizer.count(&content) + ATTACHMENT_TOKENS * attachments.len() as i64;
+        self.add_message_full(
+            "user".to_string(),
+            content,
+            None,
+            false,
+            false,
+            Some(token_count),
+        );
+        if let Some(msg) = self.messages.last_mut() {
+            msg.attachments = attachments;
+        }
+    }
+
+    /// Record a tool invocation and its output
+    pub fn add_tool_message(&mut self, call: ToolCallRecord, output: String) {
+        let token_count =
+            self.tokenizer.count(&output) + self.tokenizer.count(&call.input.to_string());
+
+        self.messages.push(Message {
+            role: "tool".to_string(),
+            content: output,
+            timestamp: Utc::now(),
+            model: None,
+            tools_executed: false,
+            is_summary: false,
+            token_count: Some(token_count),
+            tool_call: Some(call),
+            interrupted: false,
+            thinking: None,
+            thinking_signature: None,
+            provider: None,
+            attachments: Vec::new(),
+            usage: None,
+            cost: None,
         });
         self.updated_at = Utc::now();
     }
 
-    /// Calculate total tokens in non-summary messages
+    /// Attach reasoning to the message just added
+    pub fn set_last_thinking(&mut self, thinking: String, signature: Option<String>) {
+        if let Some(msg) = self.messages.last_mut() {
+            msg.thinking = Some(thinking);
+            msg.thinking_signature = signature;
+        }
+    }
+
+    /// Record which provider produced the message just added
+    pub fn set_last_provider(&mut self, provider: Option<String>) {
+        if let Some(msg) = self.messages.last_mut() {
+            msg.provider = provider;
+        }
+    }
+
+    /// Record the cost of the reply just added and add it to the session total
+    pub fn set_last_cost(&mut self, cost: Option<f64>) {
+        let (Some(cost), Some(msg)) = (cost, self.messages.last_mut()) else {
+            return;
+        };
+        msg.cost = Some(cost);
+        self.cost = Some(self.cost.unwrap_or(0.0) + cost);
+    }
+
+    /// Record the usage reported for the reply just added. Its token count
+    /// becomes the real completion size, and when the previous reply also
+    /// reported usage, the user message in between gets the real growth in
+    /// prompt size. Returns the index of that user message if corrected.
+    pub fn set_last_usage(&mut self, usage: Usage) -> Option<usize> {
+        let last = self.messages.last_mut()?;
+        if let Some(output) = usage.output_tokens {
+            last.token_count = Some(output as i64);
+        }
+        last.usage = Some(usage);
+
+        // Only the simple case: [previous reply, user message, this reply]
+        let user_idx = self.messages.len().checked_sub(2)?;
+        let previous = self.messages.get(user_idx.checked_sub(1)?)?;
+        if self.messages[user_idx].role != "user" || previous.role != "assistant" {
+            return None;
+        }
+        let previous_prompt = previous.usage?.prompt_tokens()? as i64;
+        let growth =
+            usage.prompt_tokens()? as i64 - previous_prompt - previous.token_count.unwrap_or(0);
+        if growth <= 0 {
+            return None;
+        }
+        self.messages[user_idx].token_count = Some(growth);
+        Some(user_idx)
+    }
+
+    /// Record the part of an assistant reply streamed before cancellation
+    pub fn add_interrupted_message(&mut self, content: String, model: Option<String>) {
+        self.add_message("assistant".to_string(), content, model);
+        if let Some(msg) = self.messages.last_mut() {
+            msg.interrupted = true;
+        }
+    }
+
+    /// Tokens in the conversation: the last prompt size a provider reported,
+    /// plus that reply and everything added since. Without a report, or if
+    /// the conversation was compacted after it, sums the per-message counts.
     pub fn total_tokens(&self) -> i64 {
+        let mut since_report = 0;
+        for m in self.messages.iter().rev() {
+            if m.is_summary {
+                break;
+            }
+            if let Some(prompt) = m.usage.and_then(|u| u.prompt_tokens()) {
+                return prompt as i64 + m.token_count.unwrap_or(0) + since_report;
+            }
+            since_report += m.token_count.unwrap_or(0);
+        }
+
         self.messages
             .iter()
             .filter(|m| !m.is_summary)
@@ -129,6 +504,78 @@ impl Session {
 
         // Compact all but the last keep_recent
         let end_idx = compactable_indices.len() - keep_recent;
-        Some((compactable_indices[0], compactable_indices[end_idx - 1]))
+        let mut end = compactable_indices[end_idx - 1];
+        // A tool turn goes as a whole: its results can't be sent without
+        // the call that asked for them
+        while self
+            .messages
+            .get(end + 1)
+            .is_some_and(|m| m.tool_call.is_some())
+        {
+            end += 1;
+        }
+        Some((compactable_indices[0], end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tool_call(id: &str) -> ToolCallRecord {
+        ToolCallRecord {
+            id: id.to_string(),
+            name: "read".to_string(),
+            input: serde_json::json!({"file_path": "a.txt"}),
+            status: ToolStatus::Approved,
+            is_error: false,
+        }
+    }
+
+    #[test]
+    fn tokenizer_files_are_picked_per_model() {
+        let files = HashMap::from([
+            ("llama3".to_string(), PathBuf::from("llama.json")),
+            ("llama3.2-vision".to_string(), PathBuf::from("vision.json")),
+        ]);
+        let pick = |model: &str| Tokenizer::for_model("ollama", model, &files);
+
+        assert_eq!(pick("llama3.2:3b"), Tokenizer::File("llama.json".into()));
+        assert_eq!(
+            pick("llama3.2-vision:11b"),
+            Tokenizer::File("vision.json".into())
+        );
+        assert_eq!(pick("qwen2.5:7b"), Tokenizer::Estimate);
+        assert_eq!(
+            Tokenizer::for_model("openai", "gpt-4o-mini", &files),
+            Tokenizer::O200k
+        );
+        assert_eq!(
+            Tokenizer::for_model("openai_compatible", "openai/gpt-4", &HashMap::new()),
+            Tokenizer::Cl100k
+        );
+    }
+
+    #[test]
+    fn compaction_takes_tool_turns_whole() {
+        let mut session = Session::new(None, None, "claude".to_string(), None);
+        session.add_message("user".to_string(), "Read it".to_string(), None);
+        session.add_message("assistant".to_string(), String::new(), None);
+        session.add_tool_message(tool_call("call-1"), "a".repeat(10_000));
+        session.add_tool_message(tool_call("call-2"), "b".repeat(10_000));
+        session.add_message("assistant".to_string(), "Done".to_string(), None);
+        session.add_message("user".to_string(), "Thanks".to_string(), None);
+
+        // Keeping three would split the turn after its first result
+        assert_eq!(session.get_compactable_range(3), Some((0, 3)));
+        assert_eq!(session.get_compactable_range(2), Some((0, 3)));
+        assert_eq!(session.get_compactable_range(1), Some((0, 4)));
+        assert_eq!(session.get_compactable_range(6), None);
+
+        // Compacted messages aren't offered again
+        for m in &mut session.messages[..4] {
+            m.tools_executed = true;
+        }
+        assert_eq!(session.get_compactable_range(1), Some((4, 4)));
     }
 }
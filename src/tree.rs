@@ -6,9 +6,11 @@ pub enum TreeItem {
     Project {
         name: String,
         expanded: bool,
+        /// Total cost of the project's sessions, None if none were priced
+        cost: Option<f64>,
     },
     Session {
-        session: Session,
+        session: Box<Session>,
         project: Option<String>,
     },
 }
@@ -54,6 +56,14 @@ impl TreeItem {
     }
 }
 
+/// Sum of the sessions' costs, None if none of them were priced
+fn total_cost(sessions: &[Session]) -> Option<f64> {
+    sessions
+        .iter()
+        .filter_map(|s| s.cost)
+        .reduce(|total, cost| total + cost)
+}
+
 pub struct SessionTree {
     pub items: Vec<TreeItem>,
     collapsed_projects: HashMap<String, bool>,
@@ -100,13 +110,14 @@ impl SessionTree {
                 self.items.push(TreeItem::Project {
                     name: name.clone(),
                     expanded,
+                    cost: total_cost(sessions),
                 });
 
                 // Add sessions if expanded
                 if expanded {
                     for session in sessions {
                         self.items.push(TreeItem::Session {
-                            session: session.clone(),
+                            session: Box::new(session.clone()),
                             project: Some(name.clone()),
                         });
                     }
@@ -120,6 +131,7 @@ impl SessionTree {
                         .get("(no project)")
                         .copied()
                         .unwrap_or(false),
+                    cost: total_cost(sessions),
                 });
 
                 if !self
@@ -130,7 +142,7 @@ impl SessionTree {
                 {
                     for session in sessions {
                         self.items.push(TreeItem::Session {
-                            session: session.clone(),
+                            session: Box::new(session.clone()),
                             project: None,
                         });
                     }
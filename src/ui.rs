@@ -1,4 +1,6 @@
 use crate::app::{App, AppScreen, ModelScreenMode, ProviderScreenMode};
+use crate::pricing::format_cost;
+use crate::session::{Attachment, ToolCallRecord};
 use ratatui::{
     layout::{Alignment, Constraint, Direction, Layout},
     style::{Color, Modifier, Style},
@@ -11,6 +13,42 @@ use vim_navigator::InputMode;
 // Gruvbox fg2 - softer than white, easier on the eyes
 const FG2: Color = Color::Rgb(213, 196, 161);
 
+/// One-line description of a tool call; the raw output is not shown
+fn tool_call_summary(call: &ToolCallRecord) -> String {
+    let mut args = call.input.to_string();
+    if args.chars().count() > 120 {
+        args = args.chars().take(117).collect::<String>() + "...";
+    }
+    let outcome = if !call.status.executed() {
+        format!(" ({})", call.status.as_str())
+    } else if call.is_error {
+        " (error)".to_string()
+    } else {
+        String::new()
+    };
+    format!("[Tool {}] {}{}", call.name, args, outcome)
+}
+
+/// Placeholder shown in place of an attached image
+fn attachment_line<'a>(attachment: &Attachment) -> Line<'a> {
+    Line::from(Span::styled(
+        format!(
+            "  [image: {} ({} KB)]",
+            attachment.name,
+            attachment.size() / 1024
+        ),
+        Style::default().fg(Color::Cyan),
+    ))
+}
+
+fn tool_call_color(call: &ToolCallRecord) -> Color {
+    if call.is_error {
+        Color::Red
+    } else {
+        Color::Green
+    }
+}
+
 pub fn draw(f: &mut Frame, app: &mut App) {
     match app.screen {
         AppScreen::SessionList => draw_session_list(f, app),
@@ -18,6 +56,7 @@ pub fn draw(f: &mut Frame, app: &mut App) {
         AppScreen::Providers => draw_providers(f, app),
         AppScreen::Models => draw_models(f, app),
         AppScreen::Search => draw_search(f, app),
+        AppScreen::Cost => draw_cost(f, app),
         AppScreen::Help => draw_help(f, app),
         AppScreen::Setup => draw_setup(f, app),
     }
@@ -84,9 +123,16 @@ fn draw_session_list(f: &mut Frame, app: &App) {
                 use crate::tree::TreeItem;
 
                 let (display, style) = match item {
-                    TreeItem::Project { name, expanded } => {
+                    TreeItem::Project {
+                        name,
+                        expanded,
+                        cost,
+                    } => {
                         let icon = if *expanded { "▼" } else { "▶" };
-                        let display = format!("{} {}", icon, name);
+                        let cost_str = cost
+                            .map(|c| format!("  {}", format_cost(c)))
+                            .unwrap_or_default();
+                        let display = format!("{} {}{}", icon, name, cost_str);
                         let style = if i == app.session_nav.selected_index {
                             Style::default()
                                 .fg(Color::Cyan)
@@ -102,11 +148,16 @@ fn draw_session_list(f: &mut Frame, app: &App) {
                             .as_ref()
                             .map(|m| format!(" ({})", m))
                             .unwrap_or_default();
+                        let cost_str = session
+                            .cost
+                            .map(|c| format!("  {}", format_cost(c)))
+                            .unwrap_or_default();
                         let display = format!(
-                            "  {} - {}{}",
+                            "  {} - {}{}{}",
                             session.display_name(),
                             session.updated_at.format("%Y-%m-%d %H:%M"),
-                            model_str
+                            model_str,
+                            cost_str
                         );
                         let style = if i == app.session_nav.selected_index {
                             Style::default()
@@ -173,7 +224,7 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
         let total_tokens = session.total_tokens();
         let context_window = app.config.context_window_for_provider(provider);
         let percent = (total_tokens as f64 / context_window as f64 * 100.0) as i32;
-        format!(
+        let mut header = format!(
             "Chat: {} [{} - {}] | Tokens: {}/{} ({}%)",
             session.display_name(),
             provider,
@@ -181,7 +232,19 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
             total_tokens,
             context_window,
             percent
-        )
+        );
+        // Prompt cache activity on the latest response
+        if let Some(usage) = session.last_usage {
+            let read = usage.cache_read_tokens.unwrap_or(0);
+            let written = usage.cache_creation_tokens.unwrap_or(0);
+            if read > 0 || written > 0 {
+                header.push_str(&format!(" | Cache: {} read, {} written", read, written));
+            }
+        }
+        if let Some(cost) = session.cost {
+            header.push_str(&format!(" | Cost: {}", format_cost(cost)));
+        }
+        header
     } else {
         "Chat: No Session".to_string()
     };
@@ -225,14 +288,68 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
         wrapped
     };
 
+    let tool_call_lines = |call: &ToolCallRecord| -> Vec<Line> {
+        wrap_line(&tool_call_summary(call))
+            .into_iter()
+            .enumerate()
+            .map(|(j, wrapped_line)| {
+                let bullet = if j == 0 { "● " } else { "  " };
+                Line::from(vec![
+                    Span::styled(bullet, Style::default().fg(tool_call_color(call))),
+                    Span::styled(wrapped_line, Style::default().fg(Color::Gray)),
+                ])
+            })
+            .collect()
+    };
+
+    // Reasoning: a one-line header when collapsed, dimmed text when expanded
+    let thinking_lines = |thinking: &str| -> Vec<Line> {
+        let dim = Style::default().fg(Color::DarkGray);
+        if !app.show_thinking {
+            let count = thinking.lines().count().max(1);
+            return vec![Line::from(Span::styled(
+                format!("▸ Thinking ({} lines, t to expand)", count),
+                dim,
+            ))];
+        }
+        let mut lines = vec![Line::from(Span::styled("▾ Thinking (t to collapse)", dim))];
+        for line in thinking.lines() {
+            for wrapped_line in wrap_line(line) {
+                lines.push(Line::from(vec![
+                    Span::styled("│ ", dim),
+                    Span::styled(wrapped_line, dim.add_modifier(Modifier::ITALIC)),
+                ]));
+            }
+        }
+        lines
+    };
+
     // Messages
     if let Some(ref session) = app.current_session {
         if session.messages.is_empty() {
             all_lines.push(Line::from("No messages yet. Press 'i' to start typing."));
         } else {
-            for msg in &session.messages {
-                // Skip tool result messages - they contain raw output (file contents, etc.)
-                if msg.tools_executed {
+            for (idx, msg) in session.messages.iter().enumerate() {
+                // Tool calls get a one-line summary instead of their raw output
+                if let Some(ref call) = msg.tool_call {
+                    all_lines.extend(tool_call_lines(call));
+                    all_lines.push(Line::from(""));
+                    continue;
+                }
+
+                // Hidden assistant turns that requested tools still show their reasoning
+                let requested_tools = session
+                    .messages
+                    .get(idx + 1)
+                    .is_some_and(|next| next.tool_call.is_some());
+                if let Some(thinking) = msg.thinking.as_deref().filter(|t| !t.is_empty()) {
+                    if !msg.tools_executed || requested_tools {
+                        all_lines.extend(thinking_lines(thinking));
+                    }
+                }
+
+                // Skip compacted messages and the text of turns that requested tools
+                if msg.tools_executed || requested_tools {
                     continue;
                 }
 
@@ -301,6 +418,22 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
                         }
                     }
                 }
+                all_lines.extend(msg.attachments.iter().map(attachment_line));
+                if msg.interrupted {
+                    all_lines.push(Line::from(Span::styled(
+                        "  [interrupted]",
+                        Style::default().fg(Color::DarkGray),
+                    )));
+                }
+                // Replies from a fallback provider say where they came from
+                if let Some(ref provider) = msg.provider {
+                    if *provider != session.llm_provider {
+                        all_lines.push(Line::from(Span::styled(
+                            format!("  [answered by {}]", provider),
+                            Style::default().fg(Color::DarkGray),
+                        )));
+                    }
+                }
                 all_lines.push(Line::from("")); // Blank line between messages
             }
         }
@@ -308,6 +441,11 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
         all_lines.push(Line::from("No session loaded."));
     }
 
+    // Show reasoning streamed so far
+    if app.waiting_for_response && !app.thinking_buffer.is_empty() {
+        all_lines.extend(thinking_lines(&app.thinking_buffer));
+    }
+
     // Show assistant's streaming response if waiting
     if app.waiting_for_response && !app.assistant_buffer.is_empty() {
         for (i, line) in app.assistant_buffer.lines().enumerate() {
@@ -329,19 +467,28 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
         }
         all_lines.push(Line::from(""));
     } else if app.waiting_for_response {
+        let placeholder = app.retry_status.as_deref().unwrap_or("Thinking...");
         all_lines.push(Line::from(vec![
             Span::styled("● ", Style::default().fg(Color::Gray)),
-            Span::styled("Thinking...", Style::default().fg(Color::DarkGray)),
+            Span::styled(placeholder, Style::default().fg(Color::DarkGray)),
         ]));
         all_lines.push(Line::from(""));
     }
 
+    // Tool calls resolved so far in the current turn
+    if !app.pending_tool_results.is_empty() {
+        for (call, _) in &app.pending_tool_results {
+            all_lines.extend(tool_call_lines(call));
+        }
+        all_lines.push(Line::from(""));
+    }
+
     // Separator before input
     all_lines.push(Line::from("─".repeat(viewport_width)));
 
     // Input area OR tool confirmation
     if app.awaiting_tool_confirmation {
-        if let Some((ref tool_name, ref args)) = app.pending_tool_calls.front() {
+        if let Some(call) = app.pending_tool_calls.front() {
             all_lines.push(Line::from(Span::styled(
                 "Tool Execution Confirmation",
                 Style::default()
@@ -351,8 +498,8 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
             all_lines.push(Line::from(""));
             all_lines.push(Line::from(format!(
                 "Tool: {} - Args: {}",
-                tool_name,
-                serde_json::to_string_pretty(args).unwrap_or_else(|_| "{}".to_string())
+                call.name,
+                serde_json::to_string_pretty(&call.input).unwrap_or_else(|_| "{}".to_string())
             )));
             all_lines.push(Line::from(""));
             let queued = app.pending_tool_calls.len();
@@ -381,6 +528,7 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
             Style::default().fg(FG2).add_modifier(Modifier::BOLD),
         )));
         all_lines.push(Line::from(""));
+        all_lines.extend(app.pending_attachments.iter().map(attachment_line));
 
         if app.message_buffer.is_empty() {
             all_lines.push(Line::from(Span::styled("> ", Style::default().fg(FG2))));
@@ -408,10 +556,14 @@ fn draw_chat(f: &mut Frame, app: &mut App) {
     } else {
         "i: insert | j/k: scroll | G: bottom | Enter: send | :w :q".to_string()
     };
-    all_lines.push(Line::from(Span::styled(
-        footer_text,
-        Style::default().fg(FG2),
-    )));
+    let mut footer = vec![Span::styled(footer_text, Style::default().fg(FG2))];
+    if let Some(ref warning) = app.budget_warning {
+        footer.push(Span::styled(
+            format!(" | {}", warning),
+            Style::default().fg(Color::Yellow),
+        ));
+    }
+    all_lines.push(Line::from(footer));
 
     // Calculate scroll - we now know EXACTLY how many lines we have
     let total_lines = all_lines.len() as u16;
@@ -837,7 +989,8 @@ fn draw_search(f: &mut Frame, app: &App) {
     // Header
     let result_count = app.search_results.len();
     let title = format!(
-        "Search: \"{}\" ({} result{})",
+        "Search ({}): \"{}\" ({} result{})",
+        app.search_mode.label(),
         app.search_query,
         result_count,
         if result_count == 1 { "" } else { "s" }
@@ -850,7 +1003,11 @@ fn draw_search(f: &mut Frame, app: &App) {
 
     // Results
     if app.search_results.is_empty() {
-        let empty = Paragraph::new(format!("No results found for '{}'", app.search_query))
+        let message = match &app.search_error {
+            Some(e) => format!("Semantic search failed: {}", e),
+            None => format!("No results found for '{}'", app.search_query),
+        };
+        let empty = Paragraph::new(message)
             .style(Style::default().fg(Color::Gray))
             .alignment(Alignment::Center)
             .block(
@@ -877,7 +1034,9 @@ fn draw_search(f: &mut Frame, app: &App) {
                 let date = chrono::DateTime::from_timestamp(result.updated_at, 0)
                     .map(|dt| dt.format("%Y-%m-%d").to_string())
                     .unwrap_or_default();
-                let matches = if result.match_count > 1 {
+                let matches = if let Some(score) = result.score {
+                    format!(" ({:.0}% similar)", score * 100.0)
+                } else if result.match_count > 1 {
                     format!(" ({} matches)", result.match_count)
                 } else {
                     String::new()
@@ -927,7 +1086,100 @@ fn draw_search(f: &mut Frame, app: &App) {
     }
 
     // Footer
-    let footer = Paragraph::new(" Enter: open | Esc: back | /: refine search")
+    let mut footer_text =
+        " Enter: open | Tab: search mode | Esc: back | /: refine search".to_string();
+    if app.search_unindexed > 0 {
+        footer_text.push_str(&format!(
+            " | {} message(s) not indexed yet",
+            app.search_unindexed
+        ));
+        if let Some(e) = app.indexer.as_ref().and_then(|i| i.error()) {
+            footer_text.push_str(&format!(" ({})", e));
+        }
+    }
+    let footer = Paragraph::new(footer_text)
+        .style(Style::default().fg(Color::DarkGray))
+        .block(Block::default().borders(Borders::ALL));
+    f.render_widget(footer, chunks[2]);
+
+    // Command line
+    let cmd_line = if app.vim_nav.mode == InputMode::Command {
+        Paragraph::new(format!(":{}", app.vim_nav.command_buffer))
+    } else {
+        Paragraph::new("")
+    };
+    f.render_widget(cmd_line, chunks[3]);
+}
+
+/// One section of the cost report, as a bordered list
+fn cost_section<'a>(title: &'a str, rows: &[crate::db::CostRow]) -> List<'a> {
+    let items: Vec<ListItem> = if rows.is_empty() {
+        vec![ListItem::new("  No priced replies yet").style(Style::default().fg(Color::Gray))]
+    } else {
+        rows.iter()
+            .map(|row| {
+                ListItem::new(Line::from(vec![
+                    Span::styled(
+                        format!("  {:>10}", format_cost(row.cost)),
+                        Style::default().fg(Color::Yellow),
+                    ),
+                    Span::raw(format!("  {}", row.label)),
+                    Span::styled(
+                        format!(
+                            " ({} repl{})",
+                            row.replies,
+                            if row.replies == 1 { "y" } else { "ies" }
+                        ),
+                        Style::default().fg(Color::DarkGray),
+                    ),
+                ]))
+            })
+            .collect()
+    };
+
+    List::new(items).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(title)
+            .border_style(Style::default().fg(FG2)),
+    )
+}
+
+fn draw_cost(f: &mut Frame, app: &App) {
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3), // Header
+            Constraint::Min(1),    // Report
+            Constraint::Length(3), // Footer
+            Constraint::Length(1), // Command line
+        ])
+        .split(f.area());
+
+    // Header
+    let report = &app.cost_report;
+    let total: f64 = report.by_project.iter().map(|row| row.cost).sum();
+    let header = Paragraph::new(format!("Spend: {} in total", format_cost(total)))
+        .style(Style::default().fg(Color::Cyan))
+        .alignment(Alignment::Center)
+        .block(Block::default().borders(Borders::ALL));
+    f.render_widget(header, chunks[0]);
+
+    // Report: days on the left, projects and models stacked on the right
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(chunks[1]);
+    let right = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+        .split(columns[1]);
+    f.render_widget(cost_section("By day", &report.by_day), columns[0]);
+    f.render_widget(cost_section("By project", &report.by_project), right[0]);
+    f.render_widget(cost_section("By model", &report.by_model), right[1]);
+
+    // Footer
+    let footer = Paragraph::new(" Esc: back | Replies are priced when received")
         .style(Style::default().fg(Color::DarkGray))
         .block(Block::default().borders(Borders::ALL));
     f.render_widget(footer, chunks[2]);
@@ -983,6 +1235,7 @@ fn draw_help(f: &mut Frame, _app: &App) {
         Line::from("  Ctrl+Space - Send message (insert mode)"),
         Line::from("  j/k        - Scroll up/down (normal mode)"),
         Line::from("  G          - Jump to bottom and resume auto-scroll"),
+        Line::from("  t          - Expand/collapse model reasoning"),
         Line::from(""),
         Line::from(Span::styled(
             "Providers Screen (3)",
@@ -1019,8 +1272,15 @@ fn draw_help(f: &mut Frame, _app: &App) {
         Line::from("  :providers               - Open providers screen"),
         Line::from("  :models                  - Open models screen"),
         Line::from("  :load <file|session>     - Load context from file or session"),
-        Line::from("  :search <query>          - Search across session messages"),
+        Line::from("  :search <query>          - Search sessions (Tab: keyword/semantic/hybrid)"),
         Line::from("  :compact                 - Manually compact conversation"),
+        Line::from("  :set [key [value]]       - Show/set session sampling (temperature, ...)"),
+        Line::from("  :schema <file|off|retry> - Require JSON replies matching a schema"),
+        Line::from("  :tokens                  - Count the next request's tokens"),
+        Line::from("  :cost                    - Spend by day, project and model"),
+        Line::from("  :budget [override]       - Show spend limits / send past hard limits"),
+        Line::from("  :attach <image>          - Send an image with the next message"),
+        Line::from("  :detach                  - Drop images attached for the next message"),
         Line::from("  :pull <model>            - Download Ollama model"),
         Line::from("  :setup                   - Run setup wizard"),
         Line::from("  :w / :save               - Save current session"),
@@ -1077,7 +1337,8 @@ fn draw_setup(f: &mut Frame, app: &App) {
         1 => draw_setup_ollama(app),
         2 => draw_setup_claude(app),
         3 => draw_setup_bedrock(app),
-        4 => draw_setup_complete(app),
+        4 => draw_setup_azure(app),
+        5 => draw_setup_complete(app),
         _ => vec![],
     };
 
@@ -1113,6 +1374,7 @@ fn draw_setup_welcome() -> Vec<Line<'static>> {
         Line::from("  • Ollama (local models)"),
         Line::from("  • Claude API (Anthropic)"),
         Line::from("  • AWS Bedrock (Claude on AWS)"),
+        Line::from("  • Azure OpenAI"),
         Line::from(""),
         Line::from("Would you like to run the setup wizard?"),
         Line::from(""),
@@ -1276,6 +1538,57 @@ fn draw_setup_bedrock(app: &App) -> Vec<Line<'static>> {
     lines
 }
 
+fn draw_setup_azure(app: &App) -> Vec<Line<'static>> {
+    let mut lines = vec![
+        Line::from(""),
+        Line::from(Span::styled(
+            "Azure OpenAI Setup",
+            Style::default().fg(FG2).add_modifier(Modifier::BOLD),
+        )),
+        Line::from(""),
+    ];
+
+    if let Some(status) = app.azure_status {
+        if status {
+            lines.push(Line::from(Span::styled(
+                "✓ Azure OpenAI deployment configured",
+                Style::default().fg(Color::Green),
+            )));
+            lines.push(Line::from(""));
+            lines.push(Line::from(
+                "You can now use OpenAI models via your Azure deployment.",
+            ));
+        } else {
+            lines.push(Line::from(Span::styled(
+                "✗ No Azure OpenAI deployment found",
+                Style::default().fg(Color::Red),
+            )));
+            lines.push(Line::from(""));
+            lines.push(Line::from("To use Azure OpenAI, add a provider to"));
+            lines.push(Line::from("~/.config/llm-tui/config.toml:"));
+            lines.push(Line::from(""));
+            lines.push(Line::from("  [providers.azure]"));
+            lines.push(Line::from("  type = \"azure_openai\""));
+            lines.push(Line::from(
+                "  endpoint = \"https://my-resource.openai.azure.com\"",
+            ));
+            lines.push(Line::from("  deployment = \"my-gpt-4o\""));
+            lines.push(Line::from("  model = \"gpt-4o\""));
+            lines.push(Line::from("  api_key_env = \"AZURE_OPENAI_API_KEY\""));
+        }
+    } else {
+        lines.push(Line::from("Checking Azure OpenAI configuration..."));
+    }
+
+    lines.push(Line::from(""));
+    lines.push(Line::from(Span::styled(
+        "Press Enter to continue  [s] Skip",
+        Style::default().fg(Color::Green),
+    )));
+
+    lines
+}
+
 fn draw_setup_complete(app: &App) -> Vec<Line<'static>> {
     let mut lines = vec![
         Line::from(""),
@@ -1320,6 +1633,16 @@ fn draw_setup_complete(app: &App) -> Vec<Line<'static>> {
         )));
     }
 
+    // Azure OpenAI status
+    if let Some(status) = app.azure_status {
+        let icon = if status { "✓" } else { "✗" };
+        let color = if status { Color::Green } else { Color::Red };
+        lines.push(Line::from(Span::styled(
+            format!("  {} Azure OpenAI", icon),
+            Style::default().fg(color),
+        )));
+    }
+
     lines.push(Line::from(""));
     lines.push(Line::from(
         "You can reconfigure providers anytime with the :setup command.",